@@ -1,20 +1,29 @@
 use std::io::prelude::*;
 use std::path::Path;
 use std::io;
+use std::sync::mpsc;
+use std::time::Duration;
 
-use git2::{self, BranchType, Commit, Reference, Repository, Signature};
+use git2::{self, BranchType, Commit, Reference, Repository, Signature, StatusOptions, Tree};
+use notify::{self, DebouncedEvent, RecursiveMode, Watcher};
 
 use super::Args;
 use super::util;
-use super::util::{cargo_build, CompilationStats, IncrementalOptions};
+use super::util::{cargo_build, CargoOptions, CompilationStats, IncrementalOptions};
+
+// How long `--watch` waits for a file to stop changing before rebuilding, so
+// that a burst of saves leads to a single build.
+const WATCH_DEBOUNCE_MILLIS: u64 = 500;
 
 pub fn build(args: &Args) {
     assert!(args.cmd_build);
 
+    util::check_cargo_on_path();
+
     let cargo_toml_pathbuf = Path::new(&args.flag_cargo).canonicalize().unwrap();
     let cargo_toml_path = cargo_toml_pathbuf.as_path();
 
-    let repo = &match util::open_repo(cargo_toml_path) {
+    let repo = &match util::open_repo(cargo_toml_path, args.flag_quiet) {
         Ok(repo) => repo,
         Err(e) => {
             error!("failed to find repository containing `{}`: {}",
@@ -25,9 +34,6 @@ pub fn build(args: &Args) {
 
     let repo_dir = cargo_toml_path.parent().unwrap();
 
-    // Check that there are no are untracked .rs files that might affect the build.
-    check_untracked_rs_files(repo);
-
     // Save the current head.
     let current_head = repo.head().unwrap();
 
@@ -40,19 +46,9 @@ pub fn build(args: &Args) {
                 Please check out a regular local branch.");
     }
 
-    println!("head is: {:?}", current_head.shorthand().unwrap());
-
-    // Checkout the branch "cargo-incremental-build", create it if it does not already
-    // exist.
-    create_branch_if_new(repo, "cargo-incremental-build", &current_head);
-    set_head(repo, "refs/heads/cargo-incremental-build");
-
-    // Commit a checkpoint.
-    maybe_commit_checkpoint(repo);
-
-    // Reset back to the initial head.
-    println!("bringing head back to initial state");
-    set_head(repo, current_head.name().unwrap());
+    if !args.flag_quiet {
+        println!("head is: {:?}", current_head.shorthand().unwrap());
+    }
 
     let incr_dir = Path::new("build-cache");
 
@@ -62,7 +58,73 @@ pub fn build(args: &Args) {
         IncrementalOptions::AllDeps(incr_dir)
     };
 
-    println!("Building..");
+    let cargo_options = args.cargo_options();
+    util::check_incremental_support(repo_dir, &cargo_options);
+    if args.flag_verbose {
+        cargo_options.print_summary();
+    } else if !args.flag_quiet {
+        cargo_options.warn_about_wrapper();
+    }
+
+    if let Err(err) = checkpoint_and_build(repo,
+                                           repo_dir,
+                                           &current_head,
+                                           incr_options,
+                                           &cargo_options,
+                                           args) {
+        error!("{}", err)
+    }
+
+    if args.flag_watch {
+        let watched_dir = repo.workdir().unwrap_or(repo_dir);
+        watch(watched_dir, repo_dir, args.flag_quiet, || {
+            // The user is likely still editing, e.g. they just created a new
+            // module that isn't tracked yet, so we wait for the next change
+            // rather than stop watching.
+            if let Err(err) = checkpoint_and_build(repo,
+                                                   repo_dir,
+                                                   &current_head,
+                                                   incr_options,
+                                                   &cargo_options,
+                                                   args) {
+                println!("warning: {}; skipping this build", err);
+            }
+        });
+    }
+}
+
+fn checkpoint_and_build(repo: &Repository,
+                        repo_dir: &Path,
+                        current_head: &Reference,
+                        incr_options: IncrementalOptions,
+                        cargo_options: &CargoOptions,
+                        args: &Args)
+                        -> Result<(), String> {
+    // Check that there are no untracked .rs or Cargo.toml files that might
+    // affect the build.
+    try!(check_untracked_rs_files(repo));
+
+    // Checkout the branch "cargo-incremental-build", create it if it does not already
+    // exist.
+    try!(create_branch_if_new(repo, "cargo-incremental-build", current_head, args.flag_quiet));
+    try!(set_head(repo, "refs/heads/cargo-incremental-build"));
+
+    // Commit a checkpoint. Even if that fails, HEAD has to go back to the
+    // user's branch.
+    let checkpoint_result =
+        maybe_commit_checkpoint(repo, &args.flag_checkpoint_pathspec, args.flag_quiet);
+
+    // Reset back to the initial head.
+    if !args.flag_quiet {
+        println!("bringing head back to initial state");
+    }
+    try!(set_head(repo, current_head.name().unwrap()));
+    try!(checkpoint_result);
+
+    if !args.flag_quiet {
+        println!("Building..");
+    }
+
     let mut stats = CompilationStats::default();
     let build_result = cargo_build(repo_dir,
                                    repo_dir,
@@ -70,7 +132,9 @@ pub fn build(args: &Args) {
                                    incr_options,
                                    &mut stats,
                                    false,
-                                   true);
+                                   !args.flag_quiet,
+                                   args.flag_verbose,
+                                   cargo_options);
 
     for m in build_result.messages {
         println!("{}", m.message);
@@ -85,108 +149,182 @@ pub fn build(args: &Args) {
              stats.modules_reused,
              stats.modules_total,
              build_reuse);
+    if stats.queries_total > 0 {
+        println!("Queries loaded from the cache: {} Total: {}",
+                 stats.queries_reused,
+                 stats.queries_total);
+    }
+    Ok(())
 }
 
-fn set_head(repo: &Repository, branch: &str) {
-    match repo.set_head(branch) {
-        Ok(()) => {}
-        Err(err) => error!("encountered error adjusting head: {}", err),
+// Calls `rebuild` whenever a `.rs` file below `watched_dir` changes, until
+// the user interrupts us. Changes in the build output below `repo_dir`
+// (e.g. code generated by build scripts) are ignored.
+fn watch<F: FnMut()>(watched_dir: &Path, repo_dir: &Path, quiet: bool, mut rebuild: F) {
+    let (sender, receiver) = mpsc::channel();
+    let mut watcher = match notify::watcher(sender, Duration::from_millis(WATCH_DEBOUNCE_MILLIS)) {
+        Ok(watcher) => watcher,
+        Err(err) => error!("failed to start watching for changes: {}", err),
+    };
+
+    if let Err(err) = watcher.watch(watched_dir, RecursiveMode::Recursive) {
+        error!("failed to watch `{}` for changes: {}", watched_dir.display(), err);
     }
-}
 
-fn check_untracked_rs_files(repo: &Repository) {
-    let statuses = match repo.statuses(None) {
-        Ok(s) => s,
-        Err(err) => error!("could not load git repository status: {}", err),
+    let is_relevant = |path: &Path| {
+        path.extension().map_or(false, |ext| ext == "rs") &&
+        !path.starts_with(repo_dir.join("target")) &&
+        !path.starts_with(repo_dir.join("build-cache"))
     };
 
-    let mut errors = 0;
-    for status in statuses.iter() {
-        if status.status().intersects(git2::STATUS_WT_NEW) {
-            if let Some(p) = status.path() {
-                if p.ends_with("rs") {
-                    let stderr = io::stderr();
-                    let mut stderr = stderr.lock();
-                    writeln!(stderr, "file `{}` is untracked", p).unwrap();
-                    errors += 1;
-                }
-            }
+    loop {
+        if !quiet {
+            println!("watching `{}` for changes to .rs files (press Ctrl-C to stop)",
+                     watched_dir.display());
         }
+
+        let mut changed = false;
+        while !changed {
+            changed = match receiver.recv() {
+                Ok(DebouncedEvent::Create(ref path)) |
+                Ok(DebouncedEvent::Write(ref path)) |
+                Ok(DebouncedEvent::Remove(ref path)) |
+                Ok(DebouncedEvent::Rename(_, ref path)) => is_relevant(path),
+                Ok(DebouncedEvent::Error(err, _)) => error!("error while watching: {}", err),
+                Ok(_) => false,
+                Err(_) => error!("stopped receiving file system events"),
+            };
+        }
+
+        // Other files saved at the same time are covered by this build too.
+        while receiver.try_recv().is_ok() {}
+
+        rebuild();
     }
-    if errors > 0 {
-        error!("there are untracked .rs files in the repository");
+}
+
+fn set_head(repo: &Repository, branch: &str) -> Result<(), String> {
+    repo.set_head(branch).map_err(|err| format!("encountered error adjusting head: {}", err))
+}
+
+fn check_untracked_rs_files(repo: &Repository) -> Result<(), String> {
+    let untracked = try!(untracked_build_files(repo));
+    if !untracked.is_empty() {
+        let stderr = io::stderr();
+        let mut stderr = stderr.lock();
+        for path in &untracked {
+            writeln!(stderr, "file `{}` is untracked", path).unwrap();
+        }
+        return Err("there are untracked .rs or Cargo.toml files in the repository".to_string());
     }
+    Ok(())
 }
 
-fn create_branch_if_new(repo: &Repository, name: &str, head: &Reference) {
+// The untracked files that would change the build if we left them out of
+// the checkpoint: sources, and manifests that might add crates or
+// dependencies.
+fn untracked_build_files(repo: &Repository) -> Result<Vec<String>, String> {
+    // By default git reports an untracked directory as a whole, rather than
+    // the files in it.
+    let mut options = StatusOptions::new();
+    options.include_untracked(true).recurse_untracked_dirs(true);
+    let statuses = match repo.statuses(Some(&mut options)) {
+        Ok(s) => s,
+        Err(err) => return Err(format!("could not load git repository status: {}", err)),
+    };
+
+    Ok(statuses.iter()
+        .filter(|status| status.status().intersects(git2::STATUS_WT_NEW))
+        .filter_map(|status| status.path().map(|p| p.to_string()))
+        .filter(|p| {
+            let path = Path::new(p);
+            path.extension().map_or(false, |ext| ext == "rs") ||
+            path.file_name().map_or(false, |name| name == "Cargo.toml")
+        })
+        .collect())
+}
+
+fn create_branch_if_new(repo: &Repository,
+                        name: &str,
+                        head: &Reference,
+                        quiet: bool)
+                        -> Result<(), String> {
     if let Ok(_) = repo.find_branch(name, BranchType::Local) {
-        return;
+        return Ok(());
     }
 
-    println!("creating branch 'cargo-incremental-build'");
+    if !quiet {
+        println!("creating branch 'cargo-incremental-build'");
+    }
     let commit = repo.find_commit(head.target().unwrap()).unwrap();
-    if let Err(e) = repo.branch(name, &commit, false) {
-        error!("failed to create branch '{}': {}", name, e);
+    match repo.branch(name, &commit, false) {
+        Ok(_) => Ok(()),
+        Err(e) => Err(format!("failed to create branch '{}': {}", name, e)),
     }
 }
 
-fn maybe_commit_checkpoint(repo: &Repository) {
+// Commits the changes to files matching `pathspecs` (or to all files, if
+// there are none) to the `cargo-incremental-build` branch.
+fn maybe_commit_checkpoint(repo: &Repository,
+                           pathspecs: &[String],
+                           quiet: bool)
+                           -> Result<(), String> {
     let author = match Signature::now("cargo-incremental", "none") {
         Ok(author) => author,
-        Err(e) => error!("failed to create git signature: {}", e),
+        Err(e) => return Err(format!("failed to create git signature: {}", e)),
     };
 
     let mut index = match repo.index() {
         Ok(index) => index,
-        Err(e) => error!("{}", e),
+        Err(e) => return Err(e.to_string()),
     };
 
-    let mut pathspecs = Vec::new();
-    pathspecs.push("*");
+    let mut pathspecs: Vec<&str> = pathspecs.iter().map(|spec| &spec[..]).collect();
+    if pathspecs.is_empty() {
+        pathspecs.push("*");
+    }
     let pathspecs = pathspecs;
 
     if let Err(e) = index.update_all(pathspecs, None) {
-        error!("{}", e);
+        return Err(e.to_string());
     }
 
     let updated_tree_oid = match index.write_tree() {
         Ok(oid) => oid,
-        Err(e) => error!("failed to get oid for updated tree: {}", e),
+        Err(e) => return Err(format!("failed to get oid for updated tree: {}", e)),
     };
 
     let updated_tree = match repo.find_tree(updated_tree_oid) {
         Ok(tree) => tree,
-        Err(e) => error!("{}", e),
+        Err(e) => return Err(e.to_string()),
     };
 
     let oid = match repo.refname_to_id("refs/heads/cargo-incremental-build") {
         Ok(oid) => oid,
-        Err(e) => error!("failed to get oid for cargo-incremental branch: {}", e),
+        Err(e) => return Err(format!("failed to get oid for cargo-incremental branch: {}", e)),
     };
 
     let last_commit_incr = match repo.find_commit(oid) {
         Ok(commit) => commit,
-        Err(e) => error!("failed to get commit: {}", e),
+        Err(e) => return Err(format!("failed to get commit: {}", e)),
     };
 
     // Check if there are actually any changes
     let last_commit_tree = last_commit_incr.tree().unwrap();
-    if updated_tree.len() == last_commit_tree.len() {
-        let has_changed = updated_tree.iter().any(|entry| {
-            last_commit_tree.get_id(entry.id()).is_none()
-        });
-
-        if !has_changed {
+    if !try!(trees_differ(repo, &last_commit_tree, &updated_tree)) {
+        if !quiet {
             println!("not creating new checkpoint since there are no changes");
-            return
         }
+        return Ok(());
     }
 
     let mut parents: Vec<&Commit> = Vec::new();
     parents.push(&last_commit_incr);
     let parents = parents;
 
-    println!("committing checkpoint");
+    if !quiet {
+        println!("committing checkpoint");
+    }
     let result = repo.commit(Some("HEAD"),
                              &author,
                              &author,
@@ -195,7 +333,60 @@ fn maybe_commit_checkpoint(repo: &Repository) {
                              parents.as_slice());
 
     match result {
-        Ok(oid) => println!("Commit: {:?}", oid),
-        Err(e) => error!("Failed to create commit: {}", e),
+        Ok(oid) => {
+            if !quiet {
+                println!("Commit: {:?}", oid);
+            }
+        }
+        Err(e) => return Err(format!("Failed to create commit: {}", e)),
+    };
+    Ok(())
+}
+
+fn trees_differ(repo: &Repository, old_tree: &Tree, new_tree: &Tree) -> Result<bool, String> {
+    match repo.diff_tree_to_tree(Some(old_tree), Some(new_tree), None) {
+        Ok(diff) => Ok(diff.deltas().len() > 0),
+        Err(e) => Err(format!("failed to compare checkpoint trees: {}", e)),
+    }
+}
+
+#[test]
+fn test_trees_differ() {
+    let temp = util::TempRepo::new("trees");
+    let repo = &temp.repo;
+
+    let tree_with = |contents: &[u8]| {
+        let blob = repo.blob(contents).unwrap();
+        let mut builder = repo.treebuilder(None).unwrap();
+        builder.insert("lib.rs", blob, 0o100644).unwrap();
+        let oid = builder.write().unwrap();
+        repo.find_tree(oid).unwrap()
     };
+
+    // Same number of entries, and same file size, but different content.
+    let old_tree = tree_with(b"fn foo() {}");
+    let new_tree = tree_with(b"fn bar() {}");
+    assert!(trees_differ(repo, &old_tree, &new_tree).unwrap());
+    assert!(!trees_differ(repo, &old_tree, &tree_with(b"fn foo() {}")).unwrap());
+}
+
+#[test]
+fn test_untracked_build_files() {
+    use std::fs::{self, File};
+
+    let temp = util::TempRepo::new("untracked");
+
+    for path in &["bars", "x.rs.bak", "lib.rs", "src/nested/mod.rs", "sub/Cargo.toml",
+                  "rustfmt.toml"] {
+        let path = temp.dir.join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        File::create(path).unwrap();
+    }
+
+    let mut untracked = untracked_build_files(&temp.repo).unwrap();
+    untracked.sort();
+    assert_eq!(untracked, vec!["lib.rs", "src/nested/mod.rs", "sub/Cargo.toml"]);
+
+    // `--watch` relies on getting an error back here rather than exiting.
+    assert!(check_untracked_rs_files(&temp.repo).is_err());
 }
@@ -1,7 +1,9 @@
-use git2::{Commit, Oid};
+use git2::{Commit, ErrorCode, Oid};
 use std::collections::HashSet;
 use std::hash::Hash;
+use std::io;
 use std::io::prelude::*;
+use std::sync::atomic::{AtomicBool, Ordering};
 
 use super::util::short_id;
 
@@ -75,12 +77,14 @@ fn walk<NODE, PRE, POST>(
         if next_parent == frame.num_parents {
             complete(frame.node);
         } else {
-            let node = frame.node.parent(next_parent);
+            let parent = frame.node.parent(next_parent);
             frame.next_parent += 1;
             stack.push(frame);
-            if visited.insert(node.id()) {
-                if check(&node) {
-                    stack.push(DfsFrame::new(node));
+            if let Some(node) = parent {
+                if visited.insert(node.id()) {
+                    if check(&node) {
+                        stack.push(DfsFrame::new(node));
+                    }
                 }
             }
         }
@@ -105,16 +109,21 @@ impl<NODE: DfsNode> DfsFrame<NODE> {
     }
 }
 
-pub trait DfsNode
+pub trait DfsNode: Sized
 {
     type Id: Eq + Hash;
 
     fn id(&self) -> Self::Id;
     fn human_readable_id(&self) -> String;
-    fn parent(&self, index: usize) -> Self;
+    /// Returns `None` if the parent is not available, e.g. because history
+    /// has been truncated. The walk then treats it as the end of history.
+    fn parent(&self, index: usize) -> Option<Self>;
     fn num_parents(&self) -> usize;
 }
 
+// So that we only warn about shallow clones once.
+static WARNED_SHALLOW: AtomicBool = AtomicBool::new(false);
+
 impl<'repo> DfsNode for Commit<'repo> {
     type Id = Oid;
 
@@ -126,9 +135,22 @@ impl<'repo> DfsNode for Commit<'repo> {
         short_id(self)
     }
 
-    fn parent(&self, index: usize) -> Commit<'repo> {
+    fn parent(&self, index: usize) -> Option<Commit<'repo>> {
         match self.parent(index) {
-            Ok(p) => p,
+            Ok(p) => Some(p),
+            // This is what happens at the boundary of a shallow clone.
+            Err(ref err) if err.code() == ErrorCode::NotFound => {
+                if !WARNED_SHALLOW.swap(true, Ordering::SeqCst) {
+                    let stderr = io::stderr();
+                    let mut stderr = stderr.lock();
+                    writeln!(stderr,
+                             "warning: parent {} of commit {} is missing; the repository \
+                              seems to be a shallow clone, so history will be truncated there",
+                             index,
+                             short_id(self)).unwrap();
+                }
+                None
+            }
             Err(err) => {
                 error!("unable to load parent {} of commit {}: {}",
                        index,
@@ -179,8 +201,8 @@ mod test {
             format!("{}", self.id)
         }
 
-        fn parent(&self, index: usize) -> Self {
-            self.parents[index]
+        fn parent(&self, index: usize) -> Option<Self> {
+            Some(self.parents[index])
         }
         fn num_parents(&self) -> usize {
             self.parents.len()
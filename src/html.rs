@@ -0,0 +1,110 @@
+use std::fs::File;
+use std::io::prelude::*;
+use std::path::Path;
+
+use super::report::{CommitStatus, ReplayReport};
+use super::util::escape_markup;
+
+const STYLE: &'static str = "\
+body { font-family: sans-serif; margin: 2em; }
+table { border-collapse: collapse; }
+th, td { padding: 0.3em 0.8em; border-bottom: 1px solid #ddd; text-align: left; }
+td.number { text-align: right; }
+tr.failed { background: #fff4d6; }
+tr.skipped { color: #888; }
+tr.diverged, tr.divergence { background: #ffd6d6; }
+tr.diverged td:first-child { border-left: 4px solid #c00; }
+pre { white-space: pre-wrap; margin: 0.5em 0; }
+pre.diff { background: #f6f6f6; padding: 0.5em; }
+";
+
+/// Writes `report` to `path` as a self-contained HTML page, with one row
+/// per commit in `statuses`. The divergences of a commit are shown below
+/// its row, each in a section that can be expanded to see the message and
+/// `diffs[i]` for the i-th divergence: what differed between the outputs.
+pub fn write_report(path: &Path,
+                    report: &ReplayReport,
+                    statuses: &[CommitStatus],
+                    diffs: &[String]) {
+    let mut html = String::from("<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n");
+    html.push_str(&format!("<title>cargo-incremental replay of {}</title>\n",
+                           escape_markup(&report.revisions)));
+    html.push_str(&format!("<style>\n{}</style>\n</head>\n<body>\n", STYLE));
+
+    html.push_str(&format!("<h1>Replay of <code>{}</code></h1>\n",
+                           escape_markup(&report.revisions)));
+    html.push_str(&format!("<pre>{}\n{}</pre>\n",
+                           escape_markup(&report.rustc_version),
+                           escape_markup(&report.cargo_version)));
+    html.push_str(&format!("<p>{} commits, {} of {} tests passed, {} divergences</p>\n",
+                           statuses.len(),
+                           report.tests_passed,
+                           report.tests_total,
+                           report.divergences.len()));
+
+    html.push_str("<table>\n<tr><th>commit</th><th>summary</th><th>build</th><th>tests</th>\
+                   <th>re-use</th><th>incremental build</th></tr>\n");
+    for status in statuses {
+        let divergences: Vec<_> = report.divergences
+            .iter()
+            .zip(diffs)
+            .filter(|&(divergence, _)| divergence.commit_id == status.id)
+            .collect();
+        let class = if !divergences.is_empty() {
+            "diverged"
+        } else if status.build == "FAIL" {
+            "failed"
+        } else if status.build == "SKIPPED" {
+            "skipped"
+        } else {
+            "ok"
+        };
+        let reuse = if status.modules_total == 0 {
+            "-".to_string()
+        } else {
+            format!("{:.0}%",
+                    status.modules_reused as f64 / status.modules_total as f64 * 100.0)
+        };
+        let build_time = report.commits
+            .iter()
+            .find(|commit| commit.id == status.id)
+            .and_then(|commit| commit.incremental_build_times.first())
+            .map_or("-".to_string(), |time| format!("{:.2}s", time));
+
+        html.push_str(&format!("<tr class=\"{}\"><td><code>{}</code></td><td>{}</td><td>{}</td>\
+                                <td class=\"number\">{}/{}</td><td class=\"number\">{}</td>\
+                                <td class=\"number\">{}</td></tr>\n",
+                               class,
+                               escape_markup(&status.id),
+                               escape_markup(&status.summary),
+                               status.build,
+                               status.tests_passed,
+                               status.tests_total,
+                               reuse,
+                               build_time));
+
+        for (divergence, diff) in divergences {
+            // Some divergences (e.g. in the binaries) have no outputs to diff.
+            let diff = if diff.is_empty() {
+                String::new()
+            } else {
+                format!("<pre class=\"diff\">{}</pre>", escape_markup(diff))
+            };
+            html.push_str(&format!("<tr class=\"divergence\"><td colspan=\"6\"><details>\
+                                    <summary>{}: {}</summary><pre>{}</pre>{}</details></td></tr>\n",
+                                   escape_markup(&divergence.stage),
+                                   escape_markup(divergence.message
+                                       .lines()
+                                       .next()
+                                       .unwrap_or("")),
+                                   escape_markup(&divergence.message),
+                                   diff));
+        }
+    }
+    html.push_str("</table>\n</body>\n</html>\n");
+
+    let result = File::create(path).and_then(|mut file| file.write_all(html.as_bytes()));
+    if let Err(err) = result {
+        error!("failed to write HTML report to `{}`: {}", path.display(), err)
+    }
+}
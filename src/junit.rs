@@ -0,0 +1,57 @@
+use std::fs::File;
+use std::io::prelude::*;
+use std::path::Path;
+
+use super::util::{escape_markup, TestCaseResult};
+
+/// The test results of one replayed commit.
+pub struct TestSuite {
+    pub name: String,
+    pub results: Vec<TestCaseResult>,
+}
+
+/// Writes `suites` to `path` as a JUnit XML report, one `<testsuite>` per
+/// commit.
+pub fn write_report(path: &Path, suites: &[TestSuite]) {
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<testsuites>\n");
+
+    for suite in suites {
+        let failures = suite.results.iter().filter(|r| is_failure(r)).count();
+        let skipped = suite.results.iter().filter(|r| r.status == "ignored").count();
+        xml.push_str(&format!("  <testsuite name=\"{}\" tests=\"{}\" failures=\"{}\" \
+                               skipped=\"{}\">\n",
+                              escape_markup(&suite.name),
+                              suite.results.len(),
+                              failures,
+                              skipped));
+
+        for result in &suite.results {
+            let name = escape_markup(&result.test_name);
+            if result.status == "ok" {
+                xml.push_str(&format!("    <testcase name=\"{}\"/>\n", name));
+            } else if result.status == "ignored" {
+                xml.push_str(&format!("    <testcase name=\"{}\"><skipped/></testcase>\n", name));
+            } else {
+                let panic_message = result.panic_message.as_ref().map_or("", |m| &m[..]);
+                xml.push_str(&format!("    <testcase name=\"{}\"><failure message=\"{}\">{}\
+                                       </failure></testcase>\n",
+                                      name,
+                                      escape_markup(&result.status),
+                                      escape_markup(panic_message)));
+            }
+        }
+
+        xml.push_str("  </testsuite>\n");
+    }
+
+    xml.push_str("</testsuites>\n");
+
+    let result = File::create(path).and_then(|mut file| file.write_all(xml.as_bytes()));
+    if let Err(err) = result {
+        error!("failed to write JUnit report to `{}`: {}", path.display(), err)
+    }
+}
+
+fn is_failure(result: &TestCaseResult) -> bool {
+    result.status != "ok" && result.status != "ignored"
+}
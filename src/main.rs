@@ -4,6 +4,11 @@ extern crate regex;
 extern crate rustc_serialize;
 extern crate progress;
 extern crate toml;
+extern crate ctrlc;
+extern crate atty;
+extern crate notify;
+#[cfg(target_os = "linux")]
+extern crate libc;
 
 #[macro_use]
 extern crate log;
@@ -11,10 +16,14 @@ extern crate env_logger;
 
 use docopt::Docopt;
 use std::env;
+use std::io::prelude::*;
+
+use util::{BuildDriver, CargoOptions};
 
 const USAGE: &'static str = "
-Usage: cargo-incremental build [options]
-       cargo-incremental replay [options] <revisions>
+Usage: cargo-incremental build [options] [--checkpoint-pathspec SPEC]... [--env VAR]...
+       cargo-incremental replay [options] [--env VAR]... <revisions>
+       cargo-incremental compare-reports [options] <baseline> <current>
        cargo-incremental --help
 
 This is a tool for testing incremental compilation. It offers two main
@@ -29,6 +38,10 @@ a commit is added to this branch with the current state of your
 working directory. This way, if you encounter a problem, we can easily
 replay the steps that led to the bug.
 
+With `--watch`, it keeps running and does this again whenever a `.rs`
+file in the repository changes, reporting how many modules were re-used
+each time.
+
 ## Replay mode
 
 This mode will walk back through a linearization of your git history.
@@ -43,17 +56,183 @@ purpose tester.
 To do this, a temporary `work` directory is needed (specified by
 `--work-dir`).  Note that this directory is **completely deleted**
 before execution begins so don't supply a directory with valuable
-contents. =)
+contents. =) The exception is `--resume`: the work directory records in
+`progress.json` which commits were completed, and with `--resume` an
+interrupted replay picks up where it left off (after building the last
+completed commit again, so the incremental cache is in the right state).
+
+The revisions are anything git understands, e.g. `HEAD~10..HEAD` or
+`@{upstream}..HEAD`. A single revision replays its whole history. Since
+we replay a single path through the history, `A...B` does not take the
+commits on both sides as `git log` would: it means `$(git merge-base A B)..B`,
+i.e. the commits of `B` since it forked off `A`.
+
+## Compare-reports mode
+
+`cargo incremental compare-reports` compares two reports written by
+`replay --report-json` and shows how build times, module re-use and test
+results changed. It fails if the incremental build time regressed by more
+than `--threshold` percent, so it can be used to catch performance
+regressions in CI.
 
 Options:
     --cargo CARGO           path to Cargo.toml [default: Cargo.toml]
+    --watch                 for `build`, rebuild whenever a `.rs` file changes
+    --checkpoint-pathspec SPEC
+                            for `build`, only put files matching SPEC (e.g. `src/**`) into
+                            the checkpoint commit; can be given more than once (default: `*`)
     --work-dir DIR          directory where we can do our work [default: work]
+    --incr-dir DIR          for `replay`, keep the incremental compilation caches in DIR
+                            instead of the work directory, e.g. on a bigger or faster
+                            volume; only their sub-directories of DIR are deleted
+    --tmpfs-size SIZE       mount a tmpfs of SIZE (e.g. `8G`) on the work directory to
+                            keep it in RAM, and unmount it at the end; it is left mounted
+                            if the replay fails or finds divergences, so you can inspect
+                            it (Linux only, needs permission to run `mount`)
+    --filter-path GLOB      only replay commits that change a file matching GLOB
+    --exclude-path GLOB     skip commits that only change files matching GLOB (e.g. a
+                            version bump in `Cargo.toml`)
+    --filter-author TEXT    only replay commits whose author name or email contains TEXT
+    --shard SHARD           only replay every TOTAL-th commit, starting with the INDEX-th,
+                            where SHARD is INDEX/TOTAL and INDEX counts from 0, e.g. to
+                            split a replay across machines
+    --max-parallel-commits N
+                            replay N shards at once, each in a git worktree and a
+                            sub-directory of the work directory of its own, and combine
+                            their results; build times are not reported, since the shards
+                            compete for the CPUs [default: 1]
+    --resume                continue an interrupted replay in the same work directory,
+                            skipping the commits it completed
+    --dump-order PATH       write the commits that would be replayed to PATH (`-` for
+                            stdout) and exit without building
+    --print-commit-list-json
+                            print the commits that would be replayed to stdout as a JSON
+                            array of `{index, short_id, oid, subject}` objects, on one line
+    --exit-after-list       with `--print-commit-list-json`, exit without building
+    --dry-run               print the commits, stages and directories a replay would use,
+                            then exit without building
     --just-current          track just the current projection incrementally, not all deps
     --cli-log               print all sub-process output instead of writing to files
+    --summary-only          with `--cli-log`, print one line per commit instead of one line
+                            per stage; sub-process output is still written to files
+    --strip-ansi            remove color codes from the sub-process output written to files,
+                            e.g. when color is forced on with `CARGO_TERM_COLOR=always`
     --skip-tests            do not run tests, just compare compilation artifacts
+    --skip-uncheckoutable   skip commits that git fails to check out (e.g. because of a
+                            corrupt object) instead of aborting the replay
+    --skip-normal           do not do normal builds; only compare the incremental builds
+                            with from-scratch incremental builds
+    --reference KIND        what to compare the incremental build against: `build` does a
+                            normal build; `check` only runs `cargo check`, which is much
+                            faster but leaves the final binaries unchecked [default: build]
+    --runner RUNNER         how to run tests: `libtest` (`cargo test`) or `nextest`
+                            (`cargo nextest run`) [default: libtest]
+    --ignore-ignored-tests  when comparing test results, leave out the tests that were
+                            ignored, e.g. because that depends on the environment
+    --compare-only-errors-in-tests
+                            compare only which tests passed and failed, not what they
+                            printed, for test suites whose output differs between runs
+    --report-json PATH      write a summary of the replay to PATH as JSON
+    --format FORMAT         how to print the report at the end of a replay: `human`, `json`
+                            (the summary `--report-json` writes) or `csv` (one row per
+                            commit); combine with `--quiet` to print only the report
+                            [default: human]
+    --per-commit-table      end the report with a table of whether each commit built, how
+                            many of its tests passed and how many modules were re-used
+    --threshold PERCENT     for `compare-reports`, how much slower the incremental build
+                            may get before we fail [default: 10]
+    --junit PATH            write the test results of all commits to PATH as JUnit XML
+    --html-report PATH      write the results of all commits to PATH as an HTML page, with
+                            the details of each divergence
     --skip-reuse-check      do not run the full-reuse check
+    --single-core           pin all builds to one CPU and build with `--jobs 1`, for more
+                            stable timings (pinning is only supported on Linux)
+    --samples N             build each commit N times, normally and incrementally, and
+                            report the mean and standard deviation of the build times;
+                            the extra incremental builds are no-change rebuilds
+                            [default: 1]
+    --min-iteration-time SECS
+                            make each commit take at least SECS seconds from its checkout
+                            on, so that the next checkout gets newer file timestamps even
+                            on file systems with coarse ones [default: 2]
+    --retries N             retry a normal build that failed because of the network up to
+                            N times [default: 0]
+    --retry-incremental     also apply `--retries` to the incremental build; this can hide
+                            bugs, since a failed attempt may leave state in the cache
+    --no-from-scratch       do not rebuild from an empty incremental cache to compare against
+    --fast-from-scratch     only rebuild the current project from scratch, not its
+                            dependencies
+    --compare-metadata      also compare the metadata and query-cache files of the incremental
+                            caches, ignoring their headers
+    --only-crate NAME       only compare the incremental caches of the crate NAME, and only
+                            count its module re-use; with parallel jobs, rustc's re-use
+                            output may be attributed to the wrong crate (see `--single-core`)
+    --no-tree-dedup         do a normal build even if the tree is identical to the previous commit's
+    --compare-binaries      byte-compare the final binaries of the normal and incremental builds
+    --compare-three-way     byte-compare the final binaries of the normal, incremental and
+                            from-scratch incremental builds pairwise, reporting which pairs
+                            differ
+    --strip-before-compare  run `strip` on copies of the binaries before `--compare-binaries`
+                            or `--compare-three-way` compares them; this checks functional equivalence, not
+                            bit-for-bit reproducibility
+    --update-before-build   run `cargo update` before building the first commit and use the
+                            resulting Cargo.lock for all commits, so that commits whose
+                            lockfile no longer resolves can be built
+    --update-each-commit    like `--update-before-build`, but run `cargo update` for every
+                            commit
+    --clean-registry        build with an empty CARGO_HOME in the work directory, so that
+                            stale registry and git checkouts can't affect the results;
+                            all dependencies are downloaded and built again
     --no-debuginfo          compile without debuginfo whe comparing artifacts
+    --reproducible          pin timestamps and scrub volatile environment variables for builds
+    --no-wrapper            unset RUSTC_WRAPPER (e.g. sccache) for the builds
+    --snapshot-incr         keep the incremental cache of each commit in the commit's
+                            directory (hardlinked where possible)
+    --incr-determinism-check
+                            build each commit incrementally a second time, starting from the
+                            same cache, and check that both builds cache the same object files
+    --track-cache-size      measure the incremental cache after each commit and report
+                            how its size developed
+    --dump-depgraph         save the dep-graph of incremental builds as a dot file
+    --verify-ich            report the unstable fingerprints that rustc's check of the cached
+                            fingerprints (-Z incremental-verify-ich, which is always on) finds
+                            as divergences of their own
+    --self-profile          have rustc profile the incremental builds (-Z self-profile) and
+                            keep the profiles in each commit's directory, e.g. for
+                            measureme's `summarize`
+    --env VAR               set the environment variable VAR, given as `KEY=VALUE`, for all
+                            cargo processes; can be given more than once
+    --rustflags FLAGS       pass FLAGS (e.g. `-Ccodegen-units=1`) to every rustc invocation,
+                            in addition to `RUSTFLAGS`
+    --target TRIPLE         build for the target TRIPLE instead of the host
+    --bin NAME              only build and test the binary NAME
+    --test NAME             only build and test the integration test NAME
+    --example NAME          only build and test the example NAME
+    --all-targets           build all targets (including tests, benches and examples), not
+                            just the default ones; tests are run as usual
+    --doc                   run `cargo doc` instead of `cargo build`
+    --clippy                run `cargo clippy` instead of `cargo build`
+    --on-divergence MODE    what to do when incremental and normal results differ: `stop`
+                            aborts, `continue` records the divergence and keeps going
+                            [default: stop]
+    --exit-code-on-divergence N
+                            the exit code to use when `--on-divergence stop` stops at a
+                            divergence, to tell it apart from other errors (which exit
+                            with 1) [default: 1]
+    --until-divergence      stop at the first divergence like `--on-divergence stop`, but
+                            leave the checkout, the caches and the work directory exactly
+                            as they are, and print where to find them
+    --fail-on-incr-build-failure
+                            stop when an incremental build fails, unless the normal build
+                            of the commit failed as well
+    --divergence-report PATH
+                            on divergence, write the commit, stage and the output of both
+                            sides to PATH as JSON, e.g. to attach to a bug report
+    --diff                  on divergence, show a diff of the outputs instead of both in full
+    --color WHEN            whether to color diffs: `auto` (if stdout is a terminal),
+                            `always` or `never` [default: auto]
     --verbose               print more output
+    --quiet                 only print the final summary and errors
 ";
 
 // dead code allowed for now
@@ -62,16 +241,120 @@ Options:
 pub struct Args {
     cmd_build: bool,
     cmd_replay: bool,
+    cmd_compare_reports: bool,
     arg_arguments: Vec<String>,
+    arg_baseline: String,
+    arg_current: String,
     flag_cargo: String,
+    flag_watch: bool,
+    flag_checkpoint_pathspec: Vec<String>,
     arg_revisions: String,
     flag_work_dir: String,
+    flag_incr_dir: String,
+    flag_tmpfs_size: String,
+    flag_filter_path: String,
+    flag_exclude_path: String,
+    flag_filter_author: String,
+    flag_shard: String,
+    flag_max_parallel_commits: usize,
+    flag_resume: bool,
+    flag_dump_order: String,
+    flag_print_commit_list_json: bool,
+    flag_exit_after_list: bool,
+    flag_dry_run: bool,
     flag_just_current: bool,
     flag_cli_log: bool,
+    flag_summary_only: bool,
+    flag_strip_ansi: bool,
     flag_skip_reuse_check: bool,
     flag_skip_tests: bool,
+    flag_skip_uncheckoutable: bool,
+    flag_skip_normal: bool,
+    flag_reference: ReferenceBuild,
+    flag_runner: TestRunner,
+    flag_ignore_ignored_tests: bool,
+    flag_compare_only_errors_in_tests: bool,
+    flag_report_json: String,
+    flag_format: ReportFormat,
+    flag_per_commit_table: bool,
+    flag_threshold: f64,
+    flag_junit: String,
+    flag_html_report: String,
+    flag_single_core: bool,
+    flag_samples: u32,
+    flag_min_iteration_time: u64,
+    flag_retries: u32,
+    flag_retry_incremental: bool,
+    flag_no_from_scratch: bool,
+    flag_fast_from_scratch: bool,
+    flag_no_tree_dedup: bool,
+    flag_compare_metadata: bool,
+    flag_only_crate: String,
+    flag_compare_binaries: bool,
+    flag_compare_three_way: bool,
+    flag_strip_before_compare: bool,
+    flag_update_before_build: bool,
+    flag_update_each_commit: bool,
+    flag_clean_registry: bool,
     flag_no_debuginfo: bool,
+    flag_reproducible: bool,
+    flag_no_wrapper: bool,
+    flag_snapshot_incr: bool,
+    flag_incr_determinism_check: bool,
+    flag_track_cache_size: bool,
+    flag_dump_depgraph: bool,
+    flag_verify_ich: bool,
+    flag_self_profile: bool,
+    flag_env: Vec<String>,
+    flag_rustflags: String,
+    flag_target: String,
+    flag_bin: String,
+    flag_test: String,
+    flag_example: String,
+    flag_all_targets: bool,
+    flag_doc: bool,
+    flag_clippy: bool,
+    flag_on_divergence: OnDivergence,
+    flag_exit_code_on_divergence: i32,
+    flag_until_divergence: bool,
+    flag_fail_on_incr_build_failure: bool,
+    flag_divergence_report: String,
+    flag_diff: bool,
+    flag_color: ColorChoice,
     flag_verbose: bool,
+    flag_quiet: bool,
+}
+
+#[derive(RustcDecodable, Copy, Clone, Debug, PartialEq, Eq)]
+pub enum OnDivergence {
+    Stop,
+    Continue,
+}
+
+#[derive(RustcDecodable, Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ReferenceBuild {
+    Build,
+    Check,
+}
+
+#[derive(RustcDecodable, Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ReportFormat {
+    Human,
+    Json,
+    Csv,
+}
+
+#[derive(RustcDecodable, Copy, Clone, Debug, PartialEq, Eq)]
+pub enum TestRunner {
+    Libtest,
+    Nextest,
+}
+
+#[derive(RustcDecodable, Copy, Clone, Debug, PartialEq, Eq)]
+pub enum ColorChoice {
+    Auto,
+    Always,
+    Never,
 }
 
 impl Args {
@@ -91,6 +374,55 @@ impl Args {
                 write!(cmd, " --work-dir {}", self.flag_work_dir).unwrap();
             }
 
+            if !self.flag_incr_dir.is_empty() {
+                write!(cmd, " --incr-dir {}", self.flag_incr_dir).unwrap();
+            }
+
+            if !self.flag_tmpfs_size.is_empty() {
+                write!(cmd, " --tmpfs-size {}", self.flag_tmpfs_size).unwrap();
+            }
+
+            if !self.flag_filter_path.is_empty() {
+                write!(cmd, " --filter-path {}", util::shell_quote(&self.flag_filter_path)).unwrap();
+            }
+
+            if !self.flag_exclude_path.is_empty() {
+                write!(cmd, " --exclude-path {}", util::shell_quote(&self.flag_exclude_path)).unwrap();
+            }
+
+            if !self.flag_filter_author.is_empty() {
+                write!(cmd, " --filter-author {}", util::shell_quote(&self.flag_filter_author)).unwrap();
+            }
+
+            if !self.flag_shard.is_empty() {
+                write!(cmd, " --shard {}", self.flag_shard).unwrap();
+            }
+
+            if self.flag_max_parallel_commits != 1 {
+                write!(cmd, " --max-parallel-commits {}", self.flag_max_parallel_commits)
+                    .unwrap();
+            }
+
+            if self.flag_resume {
+                cmd.push_str(" --resume");
+            }
+
+            if !self.flag_dump_order.is_empty() {
+                write!(cmd, " --dump-order {}", self.flag_dump_order).unwrap();
+            }
+
+            if self.flag_print_commit_list_json {
+                cmd.push_str(" --print-commit-list-json");
+            }
+
+            if self.flag_exit_after_list {
+                cmd.push_str(" --exit-after-list");
+            }
+
+            if self.flag_dry_run {
+                cmd.push_str(" --dry-run");
+            }
+
             if self.flag_just_current {
                 cmd.push_str(" --just-current");
             }
@@ -99,22 +431,243 @@ impl Args {
                 cmd.push_str(" --cli-log");
             }
 
+            if self.flag_summary_only {
+                cmd.push_str(" --summary-only");
+            }
+
+            if self.flag_strip_ansi {
+                cmd.push_str(" --strip-ansi");
+            }
+
             if self.flag_skip_tests {
                 cmd.push_str(" --skip-tests");
             }
 
+            if self.flag_skip_uncheckoutable {
+                cmd.push_str(" --skip-uncheckoutable");
+            }
+
+            if self.flag_skip_normal {
+                cmd.push_str(" --skip-normal");
+            }
+
+            if self.flag_reference == ReferenceBuild::Check {
+                cmd.push_str(" --reference check");
+            }
+
+            if self.flag_runner == TestRunner::Nextest {
+                cmd.push_str(" --runner nextest");
+            }
+
+            if self.flag_ignore_ignored_tests {
+                cmd.push_str(" --ignore-ignored-tests");
+            }
+
+            if self.flag_compare_only_errors_in_tests {
+                cmd.push_str(" --compare-only-errors-in-tests");
+            }
+
+            if !self.flag_report_json.is_empty() {
+                write!(cmd, " --report-json {}", self.flag_report_json).unwrap();
+            }
+
+            match self.flag_format {
+                ReportFormat::Human => {}
+                ReportFormat::Json => cmd.push_str(" --format json"),
+                ReportFormat::Csv => cmd.push_str(" --format csv"),
+            }
+
+            if self.flag_per_commit_table {
+                cmd.push_str(" --per-commit-table");
+            }
+
+            if !self.flag_junit.is_empty() {
+                write!(cmd, " --junit {}", self.flag_junit).unwrap();
+            }
+
+            if !self.flag_html_report.is_empty() {
+                write!(cmd, " --html-report {}", self.flag_html_report).unwrap();
+            }
+
             if self.flag_skip_reuse_check {
                 cmd.push_str(" --skip-reuse-check");
             }
 
+            if self.flag_single_core {
+                cmd.push_str(" --single-core");
+            }
+
+            if self.flag_samples != 1 {
+                write!(cmd, " --samples {}", self.flag_samples).unwrap();
+            }
+
+            if self.flag_min_iteration_time != 2 {
+                write!(cmd, " --min-iteration-time {}", self.flag_min_iteration_time).unwrap();
+            }
+
+            if self.flag_retries != 0 {
+                write!(cmd, " --retries {}", self.flag_retries).unwrap();
+            }
+
+            if self.flag_retry_incremental {
+                cmd.push_str(" --retry-incremental");
+            }
+
+            if self.flag_no_from_scratch {
+                cmd.push_str(" --no-from-scratch");
+            }
+
+            if self.flag_fast_from_scratch {
+                cmd.push_str(" --fast-from-scratch");
+            }
+
+            if self.flag_no_tree_dedup {
+                cmd.push_str(" --no-tree-dedup");
+            }
+
+            if self.flag_compare_metadata {
+                cmd.push_str(" --compare-metadata");
+            }
+
+            if !self.flag_only_crate.is_empty() {
+                write!(cmd, " --only-crate {}", self.flag_only_crate).unwrap();
+            }
+
+            if self.flag_compare_binaries {
+                cmd.push_str(" --compare-binaries");
+            }
+
+            if self.flag_compare_three_way {
+                cmd.push_str(" --compare-three-way");
+            }
+
+            if self.flag_strip_before_compare {
+                cmd.push_str(" --strip-before-compare");
+            }
+
+            if self.flag_update_before_build {
+                cmd.push_str(" --update-before-build");
+            }
+
+            if self.flag_update_each_commit {
+                cmd.push_str(" --update-each-commit");
+            }
+
+            if self.flag_clean_registry {
+                cmd.push_str(" --clean-registry");
+            }
+
             if self.flag_no_debuginfo {
                 cmd.push_str(" --no-debuginfo");
             }
 
+            if self.flag_reproducible {
+                cmd.push_str(" --reproducible");
+            }
+
+            if self.flag_no_wrapper {
+                cmd.push_str(" --no-wrapper");
+            }
+
+            if self.flag_snapshot_incr {
+                cmd.push_str(" --snapshot-incr");
+            }
+
+            if self.flag_incr_determinism_check {
+                cmd.push_str(" --incr-determinism-check");
+            }
+
+            if self.flag_track_cache_size {
+                cmd.push_str(" --track-cache-size");
+            }
+
+            if self.flag_dump_depgraph {
+                cmd.push_str(" --dump-depgraph");
+            }
+
+            if self.flag_verify_ich {
+                cmd.push_str(" --verify-ich");
+            }
+
+            if self.flag_self_profile {
+                cmd.push_str(" --self-profile");
+            }
+
+            for var in &self.flag_env {
+                write!(cmd, " --env {}", util::shell_quote(var)).unwrap();
+            }
+
+            if !self.flag_rustflags.is_empty() {
+                write!(cmd, " --rustflags {}", util::shell_quote(&self.flag_rustflags)).unwrap();
+            }
+
+            if !self.flag_target.is_empty() {
+                write!(cmd, " --target {}", self.flag_target).unwrap();
+            }
+
+            if !self.flag_bin.is_empty() {
+                write!(cmd, " --bin {}", self.flag_bin).unwrap();
+            }
+
+            if !self.flag_test.is_empty() {
+                write!(cmd, " --test {}", self.flag_test).unwrap();
+            }
+
+            if !self.flag_example.is_empty() {
+                write!(cmd, " --example {}", self.flag_example).unwrap();
+            }
+
+            if self.flag_all_targets {
+                cmd.push_str(" --all-targets");
+            }
+
+            if self.flag_doc {
+                cmd.push_str(" --doc");
+            }
+
+            if self.flag_clippy {
+                cmd.push_str(" --clippy");
+            }
+
+            if self.flag_on_divergence == OnDivergence::Continue {
+                cmd.push_str(" --on-divergence continue");
+            }
+
+            if self.flag_exit_code_on_divergence != 1 {
+                write!(cmd, " --exit-code-on-divergence {}", self.flag_exit_code_on_divergence)
+                    .unwrap();
+            }
+
+            if self.flag_until_divergence {
+                cmd.push_str(" --until-divergence");
+            }
+
+            if self.flag_fail_on_incr_build_failure {
+                cmd.push_str(" --fail-on-incr-build-failure");
+            }
+
+            if !self.flag_divergence_report.is_empty() {
+                write!(cmd, " --divergence-report {}", self.flag_divergence_report).unwrap();
+            }
+
+            if self.flag_diff {
+                cmd.push_str(" --diff");
+            }
+
+            match self.flag_color {
+                ColorChoice::Auto => {}
+                ColorChoice::Always => cmd.push_str(" --color always"),
+                ColorChoice::Never => cmd.push_str(" --color never"),
+            }
+
             if self.flag_verbose {
                 cmd.push_str(" --verbose");
             }
 
+            if self.flag_quiet {
+                cmd.push_str(" --quiet");
+            }
+
             write!(cmd, " {}", self.arg_revisions).unwrap();
 
             return cmd;
@@ -122,17 +675,84 @@ impl Args {
 
         unimplemented!()
     }
+
+    pub fn use_color(&self) -> bool {
+        match self.flag_color {
+            ColorChoice::Auto => atty::is(atty::Stream::Stdout),
+            ColorChoice::Always => true,
+            ColorChoice::Never => false,
+        }
+    }
+
+    pub fn cargo_options(&self) -> CargoOptions {
+        CargoOptions {
+            reproducible: self.flag_reproducible,
+            no_wrapper: self.flag_no_wrapper,
+            dump_depgraph: self.flag_dump_depgraph,
+            driver: if self.flag_doc {
+                BuildDriver::Doc
+            } else if self.flag_clippy {
+                BuildDriver::Clippy
+            } else {
+                BuildDriver::Build
+            },
+            target: if self.flag_target.is_empty() {
+                None
+            } else {
+                Some(self.flag_target.clone())
+            },
+            selected_targets: [("--bin", &self.flag_bin),
+                               ("--test", &self.flag_test),
+                               ("--example", &self.flag_example)]
+                .iter()
+                .filter(|&&(_, name)| !name.is_empty())
+                .map(|&(selector, name)| (selector, name.clone()))
+                .collect(),
+            all_targets: self.flag_all_targets,
+            single_job: self.flag_single_core,
+            rustflags: if self.flag_rustflags.is_empty() {
+                None
+            } else {
+                Some(self.flag_rustflags.clone())
+            },
+            env: self.flag_env
+                .iter()
+                .map(|var| {
+                    let mut parts = var.splitn(2, '=');
+                    let key = parts.next().unwrap().to_string();
+                    (key, parts.next().unwrap_or("").to_string())
+                })
+                .collect(),
+            // `replay` sets this for `--clean-registry`, once it knows the work directory.
+            cargo_home: None,
+            // Likewise for `--self-profile`.
+            self_profile_dir: None,
+            strip_ansi: self.flag_strip_ansi,
+            // rustc's crate names, which the cache directories use, have
+            // underscores where the package names may have dashes.
+            only_crate: if self.flag_only_crate.is_empty() {
+                None
+            } else {
+                Some(self.flag_only_crate.replace("-", "_"))
+            },
+        }
+    }
 }
 
+// `error!(exit_code: N; ...)` exits with `N` instead of 1.
 macro_rules! error {
-    ($($args:tt)*) => {
+    (exit_code: $exit_code:expr; $($args:tt)*) => {
         {
             let stderr = ::std::io::stderr();
             let mut stderr = stderr.lock();
             write!(stderr, "error: ").unwrap();
             writeln!(stderr, $($args)*).unwrap();
-            ::std::process::exit(1)
+            ::util::restore_modified_files();
+            ::std::process::exit($exit_code)
         }
+    };
+    ($($args:tt)*) => {
+        error!(exit_code: 1; $($args)*)
     }
 }
 
@@ -144,16 +764,100 @@ fn main() {
         .and_then(|d| d.argv(env::args().into_iter()).decode())
         .unwrap_or_else(|e| e.exit());
 
+    if args.flag_quiet && (args.flag_cli_log || args.flag_verbose) {
+        error!("`--quiet` cannot be combined with `--cli-log` or `--verbose`");
+    }
+
+    if args.flag_summary_only && !args.flag_cli_log {
+        error!("`--summary-only` needs `--cli-log`");
+    }
+
+    if args.flag_compare_three_way &&
+       (args.flag_no_from_scratch || args.flag_skip_normal || args.flag_doc || args.flag_clippy ||
+        args.flag_reference == ReferenceBuild::Check) {
+        error!("`--compare-three-way` needs normal and from-scratch builds with binaries; it \
+                cannot be combined with `--no-from-scratch`, `--skip-normal`, `--doc`, \
+                `--clippy` or `--reference check`");
+    }
+
+    if args.flag_incr_determinism_check && (args.flag_doc || args.flag_clippy) {
+        error!("`--incr-determinism-check` compares object files, which `--doc` and `--clippy` \
+                builds don't produce");
+    }
+
+    if args.flag_all_targets &&
+       (!args.flag_bin.is_empty() || !args.flag_test.is_empty() || !args.flag_example.is_empty()) {
+        error!("`--all-targets` cannot be combined with `--bin`, `--test` or `--example`");
+    }
+
+    // `cargo rustc` and `cargo rustdoc`, which `build` and `--just-current`
+    // use, only pass flags on to one target, and `cargo doc` has no
+    // `--all-targets`.
+    if args.flag_all_targets && (args.cmd_build || args.flag_just_current || args.flag_doc) {
+        error!("`--all-targets` is only for `replay`, and cannot be combined with \
+                `--just-current` or `--doc`");
+    }
+
+    if args.flag_until_divergence &&
+       (args.flag_on_divergence == OnDivergence::Continue || args.flag_max_parallel_commits > 1) {
+        error!("`--until-divergence` stops at the first divergence, so it cannot be combined \
+                with `--on-divergence continue` or `--max-parallel-commits`");
+    }
+
+    if args.flag_exit_after_list && !args.flag_print_commit_list_json {
+        error!("`--exit-after-list` needs `--print-commit-list-json`");
+    }
+
+    for var in &args.flag_env {
+        let key = var.split('=').next().unwrap();
+        if !var.contains('=') || key.is_empty() {
+            error!("`--env {}` should look like `--env KEY=VALUE`", var);
+        }
+        if key == "RUSTFLAGS" {
+            error!("`--env` cannot set `RUSTFLAGS`; use `--rustflags` instead");
+        }
+        if key == "CARGO_INCREMENTAL" || key == "CARGO_TARGET_DIR" {
+            error!("`--env` cannot set `{}`, which we set for each build ourselves", key);
+        }
+    }
+
+    if args.flag_doc && args.flag_clippy {
+        error!("`--doc` cannot be combined with `--clippy`");
+    }
+
+    if args.flag_reference == ReferenceBuild::Check && (args.flag_doc || args.flag_clippy) {
+        error!("`--reference check` cannot be combined with `--doc` or `--clippy`");
+    }
+
+    if args.flag_skip_normal && args.flag_reference == ReferenceBuild::Check {
+        error!("`--skip-normal` cannot be combined with `--reference check`");
+    }
+
+    if args.flag_skip_normal && args.flag_no_from_scratch {
+        error!("`--skip-normal` cannot be combined with `--no-from-scratch`, since that \
+                leaves nothing to compare");
+    }
+
+    if args.flag_single_core && !util::pin_to_single_cpu() && !args.flag_quiet {
+        println!("warning: could not pin to a single CPU; only limiting cargo to one job");
+    }
+
     if args.cmd_build {
         build::build(&args);
     } else if args.cmd_replay {
         replay::replay(&args);
+    } else if args.cmd_compare_reports {
+        report::compare_reports(&args);
     }
 }
 
 mod build;
 mod dfs;
+mod html;
+mod junit;
+mod parallel;
 mod replay;
+mod report;
 mod util;
 
 #[test]
@@ -161,14 +865,88 @@ fn test_args_to_cli_command() {
     let args = Args {
         cmd_build: false,
         cmd_replay: true,
+        cmd_compare_reports: false,
         arg_arguments: vec![],
+        arg_baseline: "".to_string(),
+        arg_current: "".to_string(),
         flag_cargo: "".to_string(),
+        flag_watch: false,
+        flag_checkpoint_pathspec: vec![],
         arg_revisions: "master~1..master".to_string(),
         flag_work_dir: "".to_string(),
+        flag_incr_dir: "".to_string(),
+        flag_tmpfs_size: "".to_string(),
+        flag_filter_path: "".to_string(),
+        flag_exclude_path: "".to_string(),
+        flag_filter_author: "".to_string(),
+        flag_shard: "".to_string(),
+        flag_max_parallel_commits: 1,
+        flag_resume: false,
+        flag_dump_order: "".to_string(),
+        flag_print_commit_list_json: false,
+        flag_exit_after_list: false,
+        flag_dry_run: false,
         flag_just_current: false,
         flag_cli_log: false,
+        flag_summary_only: false,
+        flag_strip_ansi: false,
         flag_skip_tests: false,
+        flag_skip_uncheckoutable: false,
+        flag_skip_normal: false,
+        flag_reference: ReferenceBuild::Build,
+        flag_runner: TestRunner::Libtest,
+        flag_ignore_ignored_tests: false,
+        flag_compare_only_errors_in_tests: false,
+        flag_report_json: "".to_string(),
+        flag_format: ReportFormat::Human,
+        flag_per_commit_table: false,
+        flag_threshold: 10.0,
+        flag_junit: "".to_string(),
+        flag_html_report: "".to_string(),
+        flag_skip_reuse_check: false,
+        flag_single_core: false,
+        flag_samples: 1,
+        flag_min_iteration_time: 2,
+        flag_retries: 0,
+        flag_retry_incremental: false,
+        flag_no_from_scratch: false,
+        flag_fast_from_scratch: false,
+        flag_no_tree_dedup: false,
+        flag_compare_metadata: false,
+        flag_only_crate: "".to_string(),
+        flag_compare_binaries: false,
+        flag_compare_three_way: false,
+        flag_strip_before_compare: false,
+        flag_update_before_build: false,
+        flag_update_each_commit: false,
+        flag_clean_registry: false,
         flag_no_debuginfo: false,
+        flag_reproducible: false,
+        flag_no_wrapper: false,
+        flag_snapshot_incr: false,
+        flag_incr_determinism_check: false,
+        flag_track_cache_size: false,
+        flag_dump_depgraph: false,
+        flag_verify_ich: false,
+        flag_self_profile: false,
+        flag_env: vec![],
+        flag_rustflags: "".to_string(),
+        flag_target: "".to_string(),
+        flag_bin: "".to_string(),
+        flag_test: "".to_string(),
+        flag_example: "".to_string(),
+        flag_all_targets: false,
+        flag_doc: false,
+        flag_clippy: false,
+        flag_on_divergence: OnDivergence::Stop,
+        flag_exit_code_on_divergence: 1,
+        flag_until_divergence: false,
+        flag_fail_on_incr_build_failure: false,
+        flag_divergence_report: "".to_string(),
+        flag_diff: false,
+        flag_color: ColorChoice::Auto,
+        flag_verbose: false,
+        flag_quiet: false,
     };
 
     assert_eq!(args.to_cli_command(), "cargo-incremental replay master~1..master");
@@ -185,6 +963,73 @@ fn test_args_to_cli_command() {
     };
     assert_eq!(work_dir.to_cli_command(), "cargo-incremental replay --work-dir /tmp/ciw master~1..master");
 
+    let incr_dir = Args {
+        flag_incr_dir: "/mnt/fast".to_string(),
+        .. args.clone()
+    };
+    assert_eq!(incr_dir.to_cli_command(), "cargo-incremental replay --incr-dir /mnt/fast master~1..master");
+
+    let tmpfs_size = Args {
+        flag_tmpfs_size: "8G".to_string(),
+        .. args.clone()
+    };
+    assert_eq!(tmpfs_size.to_cli_command(), "cargo-incremental replay --tmpfs-size 8G master~1..master");
+
+    let filter_path = Args {
+        flag_filter_path: "src/*.rs".to_string(),
+        .. args.clone()
+    };
+    assert_eq!(filter_path.to_cli_command(), "cargo-incremental replay --filter-path 'src/*.rs' master~1..master");
+
+    let exclude_path = Args {
+        flag_exclude_path: "Cargo.toml".to_string(),
+        .. args.clone()
+    };
+    assert_eq!(exclude_path.to_cli_command(), "cargo-incremental replay --exclude-path Cargo.toml master~1..master");
+
+    let filter_author = Args {
+        flag_filter_author: "Jane Doe".to_string(),
+        .. args.clone()
+    };
+    assert_eq!(filter_author.to_cli_command(), "cargo-incremental replay --filter-author 'Jane Doe' master~1..master");
+
+    let shard = Args {
+        flag_shard: "1/4".to_string(),
+        .. args.clone()
+    };
+    assert_eq!(shard.to_cli_command(), "cargo-incremental replay --shard 1/4 master~1..master");
+
+    let max_parallel_commits = Args {
+        flag_max_parallel_commits: 4,
+        .. args.clone()
+    };
+    assert_eq!(max_parallel_commits.to_cli_command(), "cargo-incremental replay --max-parallel-commits 4 master~1..master");
+
+    let resume = Args {
+        flag_resume: true,
+        .. args.clone()
+    };
+    assert_eq!(resume.to_cli_command(), "cargo-incremental replay --resume master~1..master");
+
+    let dump_order = Args {
+        flag_dump_order: "-".to_string(),
+        .. args.clone()
+    };
+    assert_eq!(dump_order.to_cli_command(), "cargo-incremental replay --dump-order - master~1..master");
+
+    let commit_list = Args {
+        flag_print_commit_list_json: true,
+        flag_exit_after_list: true,
+        .. args.clone()
+    };
+    assert_eq!(commit_list.to_cli_command(), "cargo-incremental replay --print-commit-list-json --exit-after-list master~1..master");
+
+    let dry_run = Args {
+        flag_dry_run: true,
+        .. args.clone()
+    };
+    assert_eq!(dry_run.to_cli_command(), "cargo-incremental replay --dry-run master~1..master");
+
     let just_current = Args {
         flag_just_current: true,
         .. args.clone()
@@ -197,27 +1042,342 @@ fn test_args_to_cli_command() {
     };
     assert_eq!(cli_log.to_cli_command(), "cargo-incremental replay --cli-log master~1..master");
 
+    let summary_only = Args {
+        flag_cli_log: true,
+        flag_summary_only: true,
+        .. args.clone()
+    };
+    assert_eq!(summary_only.to_cli_command(), "cargo-incremental replay --cli-log --summary-only master~1..master");
+
+    let strip_ansi = Args {
+        flag_strip_ansi: true,
+        .. args.clone()
+    };
+    assert_eq!(strip_ansi.to_cli_command(), "cargo-incremental replay --strip-ansi master~1..master");
+
     let skip_tests = Args {
         flag_skip_tests: true,
         .. args.clone()
     };
     assert_eq!(skip_tests.to_cli_command(), "cargo-incremental replay --skip-tests master~1..master");
 
+    let skip_uncheckoutable = Args {
+        flag_skip_uncheckoutable: true,
+        .. args.clone()
+    };
+    assert_eq!(skip_uncheckoutable.to_cli_command(), "cargo-incremental replay --skip-uncheckoutable master~1..master");
+
+    let skip_normal = Args {
+        flag_skip_normal: true,
+        .. args.clone()
+    };
+    assert_eq!(skip_normal.to_cli_command(), "cargo-incremental replay --skip-normal master~1..master");
+
+    let reference = Args {
+        flag_reference: ReferenceBuild::Check,
+        .. args.clone()
+    };
+    assert_eq!(reference.to_cli_command(), "cargo-incremental replay --reference check master~1..master");
+
+    let runner = Args {
+        flag_runner: TestRunner::Nextest,
+        .. args.clone()
+    };
+    assert_eq!(runner.to_cli_command(), "cargo-incremental replay --runner nextest master~1..master");
+
+    let ignore_ignored_tests = Args {
+        flag_ignore_ignored_tests: true,
+        .. args.clone()
+    };
+    assert_eq!(ignore_ignored_tests.to_cli_command(), "cargo-incremental replay --ignore-ignored-tests master~1..master");
+
+    let compare_only_errors_in_tests = Args {
+        flag_compare_only_errors_in_tests: true,
+        .. args.clone()
+    };
+    assert_eq!(compare_only_errors_in_tests.to_cli_command(), "cargo-incremental replay --compare-only-errors-in-tests master~1..master");
+
+    let report_json = Args {
+        flag_report_json: "report.json".to_string(),
+        .. args.clone()
+    };
+    assert_eq!(report_json.to_cli_command(), "cargo-incremental replay --report-json report.json master~1..master");
+
+    let format = Args {
+        flag_format: ReportFormat::Csv,
+        .. args.clone()
+    };
+    assert_eq!(format.to_cli_command(), "cargo-incremental replay --format csv master~1..master");
+
+    let per_commit_table = Args {
+        flag_per_commit_table: true,
+        .. args.clone()
+    };
+    assert_eq!(per_commit_table.to_cli_command(), "cargo-incremental replay --per-commit-table master~1..master");
+
+    let junit = Args {
+        flag_junit: "report.xml".to_string(),
+        .. args.clone()
+    };
+    assert_eq!(junit.to_cli_command(), "cargo-incremental replay --junit report.xml master~1..master");
+
+    let html_report = Args {
+        flag_html_report: "report.html".to_string(),
+        .. args.clone()
+    };
+    assert_eq!(html_report.to_cli_command(), "cargo-incremental replay --html-report report.html master~1..master");
+
     let skip_reuse_check = Args {
         flag_skip_reuse_check: true,
         .. args.clone()
     };
     assert_eq!(skip_reuse_check.to_cli_command(), "cargo-incremental replay --skip-reuse-check master~1..master");
 
+    let single_core = Args {
+        flag_single_core: true,
+        .. args.clone()
+    };
+    assert_eq!(single_core.to_cli_command(), "cargo-incremental replay --single-core master~1..master");
+
+    let samples = Args {
+        flag_samples: 5,
+        .. args.clone()
+    };
+    assert_eq!(samples.to_cli_command(), "cargo-incremental replay --samples 5 master~1..master");
+
+    let min_iteration_time = Args {
+        flag_min_iteration_time: 0,
+        .. args.clone()
+    };
+    assert_eq!(min_iteration_time.to_cli_command(), "cargo-incremental replay --min-iteration-time 0 master~1..master");
+
+    let retries = Args {
+        flag_retries: 3,
+        .. args.clone()
+    };
+    assert_eq!(retries.to_cli_command(), "cargo-incremental replay --retries 3 master~1..master");
+
+    let retry_incremental = Args {
+        flag_retry_incremental: true,
+        .. args.clone()
+    };
+    assert_eq!(retry_incremental.to_cli_command(), "cargo-incremental replay --retry-incremental master~1..master");
+
+    let no_from_scratch = Args {
+        flag_no_from_scratch: true,
+        .. args.clone()
+    };
+    assert_eq!(no_from_scratch.to_cli_command(), "cargo-incremental replay --no-from-scratch master~1..master");
+
+    let fast_from_scratch = Args {
+        flag_fast_from_scratch: true,
+        .. args.clone()
+    };
+    assert_eq!(fast_from_scratch.to_cli_command(), "cargo-incremental replay --fast-from-scratch master~1..master");
+
+    let no_tree_dedup = Args {
+        flag_no_tree_dedup: true,
+        .. args.clone()
+    };
+    assert_eq!(no_tree_dedup.to_cli_command(), "cargo-incremental replay --no-tree-dedup master~1..master");
+
+    let compare_metadata = Args {
+        flag_compare_metadata: true,
+        .. args.clone()
+    };
+    assert_eq!(compare_metadata.to_cli_command(), "cargo-incremental replay --compare-metadata master~1..master");
+
+    let only_crate = Args {
+        flag_only_crate: "my-crate".to_string(),
+        .. args.clone()
+    };
+    assert_eq!(only_crate.to_cli_command(), "cargo-incremental replay --only-crate my-crate master~1..master");
+
+    let compare_binaries = Args {
+        flag_compare_binaries: true,
+        .. args.clone()
+    };
+    assert_eq!(compare_binaries.to_cli_command(), "cargo-incremental replay --compare-binaries master~1..master");
+
+    let compare_three_way = Args {
+        flag_compare_three_way: true,
+        .. args.clone()
+    };
+    assert_eq!(compare_three_way.to_cli_command(), "cargo-incremental replay --compare-three-way master~1..master");
+
+    let strip_before_compare = Args {
+        flag_strip_before_compare: true,
+        .. args.clone()
+    };
+    assert_eq!(strip_before_compare.to_cli_command(), "cargo-incremental replay --strip-before-compare master~1..master");
+
+    let update_before_build = Args {
+        flag_update_before_build: true,
+        .. args.clone()
+    };
+    assert_eq!(update_before_build.to_cli_command(), "cargo-incremental replay --update-before-build master~1..master");
+
+    let update_each_commit = Args {
+        flag_update_each_commit: true,
+        .. args.clone()
+    };
+    assert_eq!(update_each_commit.to_cli_command(), "cargo-incremental replay --update-each-commit master~1..master");
+
+    let clean_registry = Args {
+        flag_clean_registry: true,
+        .. args.clone()
+    };
+    assert_eq!(clean_registry.to_cli_command(), "cargo-incremental replay --clean-registry master~1..master");
+
     let no_debuginfo = Args {
         flag_no_debuginfo: true,
         .. args.clone()
     };
     assert_eq!(no_debuginfo.to_cli_command(), "cargo-incremental replay --no-debuginfo master~1..master");
 
+    let reproducible = Args {
+        flag_reproducible: true,
+        .. args.clone()
+    };
+    assert_eq!(reproducible.to_cli_command(), "cargo-incremental replay --reproducible master~1..master");
+
+    let no_wrapper = Args {
+        flag_no_wrapper: true,
+        .. args.clone()
+    };
+    assert_eq!(no_wrapper.to_cli_command(), "cargo-incremental replay --no-wrapper master~1..master");
+
+    let snapshot_incr = Args {
+        flag_snapshot_incr: true,
+        .. args.clone()
+    };
+    assert_eq!(snapshot_incr.to_cli_command(), "cargo-incremental replay --snapshot-incr master~1..master");
+
+    let incr_determinism_check = Args {
+        flag_incr_determinism_check: true,
+        .. args.clone()
+    };
+    assert_eq!(incr_determinism_check.to_cli_command(), "cargo-incremental replay --incr-determinism-check master~1..master");
+
+    let track_cache_size = Args {
+        flag_track_cache_size: true,
+        .. args.clone()
+    };
+    assert_eq!(track_cache_size.to_cli_command(), "cargo-incremental replay --track-cache-size master~1..master");
+
+    let dump_depgraph = Args {
+        flag_dump_depgraph: true,
+        .. args.clone()
+    };
+    assert_eq!(dump_depgraph.to_cli_command(), "cargo-incremental replay --dump-depgraph master~1..master");
+
+    let verify_ich = Args {
+        flag_verify_ich: true,
+        .. args.clone()
+    };
+    assert_eq!(verify_ich.to_cli_command(), "cargo-incremental replay --verify-ich master~1..master");
+
+    let self_profile = Args {
+        flag_self_profile: true,
+        .. args.clone()
+    };
+    assert_eq!(self_profile.to_cli_command(), "cargo-incremental replay --self-profile master~1..master");
+
+    let env = Args {
+        flag_env: vec!["RUST_BACKTRACE=1".to_string(), "CC=clang -m32".to_string()],
+        .. args.clone()
+    };
+    assert_eq!(env.to_cli_command(), "cargo-incremental replay --env RUST_BACKTRACE=1 --env 'CC=clang -m32' master~1..master");
+
+    let rustflags = Args {
+        flag_rustflags: "-C codegen-units=1".to_string(),
+        .. args.clone()
+    };
+    assert_eq!(rustflags.to_cli_command(), "cargo-incremental replay --rustflags '-C codegen-units=1' master~1..master");
+
+    let target = Args {
+        flag_target: "thumbv7em-none-eabi".to_string(),
+        .. args.clone()
+    };
+    assert_eq!(target.to_cli_command(), "cargo-incremental replay --target thumbv7em-none-eabi master~1..master");
+
+    let selected_targets = Args {
+        flag_bin: "server".to_string(),
+        flag_test: "integration".to_string(),
+        flag_example: "demo".to_string(),
+        .. args.clone()
+    };
+    assert_eq!(selected_targets.to_cli_command(), "cargo-incremental replay --bin server --test integration --example demo master~1..master");
+
+    let all_targets = Args {
+        flag_all_targets: true,
+        .. args.clone()
+    };
+    assert_eq!(all_targets.to_cli_command(), "cargo-incremental replay --all-targets master~1..master");
+
+    let doc = Args {
+        flag_doc: true,
+        .. args.clone()
+    };
+    assert_eq!(doc.to_cli_command(), "cargo-incremental replay --doc master~1..master");
+
+    let clippy = Args {
+        flag_clippy: true,
+        .. args.clone()
+    };
+    assert_eq!(clippy.to_cli_command(), "cargo-incremental replay --clippy master~1..master");
+
+    let on_divergence = Args {
+        flag_on_divergence: OnDivergence::Continue,
+        .. args.clone()
+    };
+    assert_eq!(on_divergence.to_cli_command(), "cargo-incremental replay --on-divergence continue master~1..master");
+
+    let exit_code_on_divergence = Args {
+        flag_exit_code_on_divergence: 3,
+        .. args.clone()
+    };
+    assert_eq!(exit_code_on_divergence.to_cli_command(), "cargo-incremental replay --exit-code-on-divergence 3 master~1..master");
+
+    let until_divergence = Args {
+        flag_until_divergence: true,
+        .. args.clone()
+    };
+    assert_eq!(until_divergence.to_cli_command(), "cargo-incremental replay --until-divergence master~1..master");
+
+    let fail_on_incr_build_failure = Args {
+        flag_fail_on_incr_build_failure: true,
+        .. args.clone()
+    };
+    assert_eq!(fail_on_incr_build_failure.to_cli_command(), "cargo-incremental replay --fail-on-incr-build-failure master~1..master");
+
+    let divergence_report = Args {
+        flag_divergence_report: "divergence.json".to_string(),
+        .. args.clone()
+    };
+    assert_eq!(divergence_report.to_cli_command(), "cargo-incremental replay --divergence-report divergence.json master~1..master");
+
+    let diff = Args {
+        flag_diff: true,
+        .. args.clone()
+    };
+    assert_eq!(diff.to_cli_command(), "cargo-incremental replay --diff master~1..master");
+
+    let color = Args {
+        flag_color: ColorChoice::Never,
+        .. args.clone()
+    };
+    assert_eq!(color.to_cli_command(), "cargo-incremental replay --color never master~1..master");
+
     let verbose = Args {
         flag_verbose: true,
         .. args.clone()
     };
     assert_eq!(verbose.to_cli_command(), "cargo-incremental replay --verbose master~1..master");
+
+    let quiet = Args {
+        flag_quiet: true,
+        .. args.clone()
+    };
+    assert_eq!(quiet.to_cli_command(), "cargo-incremental replay --quiet master~1..master");
 }
@@ -0,0 +1,362 @@
+use git2::Repository;
+use rustc_serialize::json::ToJson;
+use std::cmp;
+use std::collections::HashMap;
+use std::env;
+use std::fs::File;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use super::{Args, ReportFormat};
+use super::report::{CommitReport, ReplayReport};
+use super::util;
+
+// The options we set ourselves for each worker, so the user's values (if any)
+// are not passed on. They all take a value.
+const WORKER_OPTIONS: &'static [&'static str] = &["--cargo",
+                                                  "--work-dir",
+                                                  "--incr-dir",
+                                                  "--max-parallel-commits",
+                                                  "--report-json"];
+
+struct Worker {
+    index: usize,
+    worktree: PathBuf,
+    log_path: PathBuf,
+    report_path: PathBuf,
+}
+
+/// Replays `commit_ids` (in order) with `--max-parallel-commits` workers at
+/// once. Each worker is a `cargo-incremental replay --shard INDEX/TOTAL`
+/// process with a git worktree and a work directory of its own, so the
+/// commits are partitioned exactly as `--shard` would partition them. The
+/// workers compete for the CPUs, so this is only good for finding
+/// divergences: build times are not reported.
+pub fn replay(repo: &Repository, cargo_toml_path: &Path, commit_ids: &[String], args: &Args) {
+    for &(used, flag) in &[(!args.flag_shard.is_empty(), "--shard"),
+                           (args.flag_resume, "--resume"),
+                           (!args.flag_junit.is_empty(), "--junit"),
+                           (!args.flag_html_report.is_empty(), "--html-report"),
+                           (!args.flag_divergence_report.is_empty(), "--divergence-report")] {
+        if used {
+            error!("`--max-parallel-commits` cannot be combined with `{}`", flag);
+        }
+    }
+
+    let repo_dir = match repo.workdir().map(|dir| dir.canonicalize()) {
+        Some(Ok(dir)) => dir,
+        Some(Err(err)) => error!("failed to canonicalize the repository directory: {}", err),
+        None => error!("cannot replay in a bare repository"),
+    };
+    let cargo_toml_in_repo = match cargo_toml_path.strip_prefix(&repo_dir) {
+        Ok(path) => path.to_path_buf(),
+        Err(_) => {
+            error!("`{}` is not inside the repository `{}`",
+                   cargo_toml_path.display(),
+                   repo_dir.display())
+        }
+    };
+    let head = match repo.head().ok().and_then(|head| head.target()) {
+        Some(oid) => oid.to_string(),
+        None => error!("HEAD does not point at a commit"),
+    };
+
+    let work_dir = Path::new(&args.flag_work_dir);
+    util::remove_dir(work_dir);
+    let work_dir = util::absolute_dir_path(work_dir);
+
+    // Forget the worktrees of an earlier run, whose directories we just
+    // deleted along with the work directory.
+    run_git(&repo_dir, &["worktree", "prune"]);
+
+    let exe = match env::current_exe() {
+        Ok(exe) => exe,
+        Err(err) => error!("failed to find the `cargo-incremental` executable: {}", err),
+    };
+    let user_args = worker_args(env::args().skip(1));
+
+    let commit_count = commit_ids.len();
+    let worker_count = cmp::min(args.flag_max_parallel_commits, cmp::max(commit_count, 1));
+    if !args.flag_quiet {
+        println!("replaying {} commits with {} workers; build times are not reported, since \
+                  the workers compete for the CPUs",
+                 commit_count,
+                 worker_count);
+    }
+
+    let mut workers = vec![];
+    let mut children = vec![];
+    for index in 0..worker_count {
+        let worker_dir = work_dir.join(format!("worker-{}", index));
+        util::make_dir(&worker_dir);
+        let worker = Worker {
+            index: index,
+            worktree: worker_dir.join("repo"),
+            log_path: worker_dir.join("output.log"),
+            report_path: worker_dir.join("report.json"),
+        };
+
+        // The worktree starts at HEAD, so revisions like `HEAD~10..HEAD`
+        // mean the same thing in it.
+        run_git(&repo_dir,
+                &["worktree", "add", "--detach", &worker.worktree.display().to_string(), &head]);
+
+        let log = match File::create(&worker.log_path) {
+            Ok(log) => log,
+            Err(err) => error!("failed to create `{}`: {}", worker.log_path.display(), err),
+        };
+        let log_for_stderr = match log.try_clone() {
+            Ok(log) => log,
+            Err(err) => error!("failed to create `{}`: {}", worker.log_path.display(), err),
+        };
+
+        let mut cmd = Command::new(&exe);
+        cmd.args(&user_args)
+            .arg("--cargo")
+            .arg(worker.worktree.join(&cargo_toml_in_repo))
+            .arg("--work-dir")
+            .arg(worker_dir.join("work"))
+            .arg("--shard")
+            .arg(format!("{}/{}", index, worker_count))
+            .arg("--report-json")
+            .arg(&worker.report_path)
+            .stdin(Stdio::null())
+            .stdout(Stdio::from(log))
+            .stderr(Stdio::from(log_for_stderr));
+        if !args.flag_incr_dir.is_empty() {
+            cmd.arg("--incr-dir")
+                .arg(Path::new(&args.flag_incr_dir).join(format!("worker-{}", index)));
+        }
+        debug!("{:?}", cmd);
+
+        match cmd.spawn() {
+            Ok(child) => children.push(child),
+            Err(err) => error!("failed to start worker {}: {}", index, err),
+        }
+        if !args.flag_quiet {
+            println!("worker {}: started, writing its output to `{}`",
+                     index,
+                     worker.log_path.display());
+        }
+        workers.push(worker);
+    }
+
+    let mut failures = vec![];
+    for (worker, mut child) in workers.iter().zip(children) {
+        match child.wait() {
+            Ok(status) if status.success() => {
+                if !args.flag_quiet {
+                    println!("worker {}: done", worker.index);
+                }
+            }
+            Ok(status) => {
+                println!("worker {}: failed ({}); see `{}`",
+                         worker.index,
+                         status,
+                         worker.log_path.display());
+                // Killed by a signal if there is no exit code.
+                failures.push(status.code().unwrap_or(1));
+            }
+            Err(err) => error!("failed to wait for worker {}: {}", worker.index, err),
+        }
+    }
+
+    // A worker that stopped at a divergence writes its report before it
+    // exits. One that failed for another reason has not, but the others
+    // still tell us about their commits.
+    let reports: Vec<ReplayReport> = workers.iter()
+        .filter(|worker| worker.report_path.exists())
+        .map(|worker| {
+            match ReplayReport::load(&worker.report_path) {
+                Ok(report) => report,
+                Err(err) => error!("{}", err),
+            }
+        })
+        .collect();
+    let report = merge_reports(&args.arg_revisions, commit_ids, &reports);
+
+    match args.flag_format {
+        ReportFormat::Human => print_report(&report, worker_count),
+        ReportFormat::Json => println!("{}", report.to_json().pretty()),
+        ReportFormat::Csv => print!("{}", report.to_csv()),
+    }
+
+    if !args.flag_report_json.is_empty() {
+        report.write(Path::new(&args.flag_report_json));
+    }
+
+    // The work directories stay around for inspection, but the worktrees
+    // would only clutter `git worktree list`.
+    for worker in &workers {
+        util::remove_dir(&worker.worktree);
+    }
+    run_git(&repo_dir, &["worktree", "prune"]);
+
+    if let Some(&exit_code) = failures.first() {
+        error!(exit_code: exit_code;
+               "{} of {} workers failed",
+               failures.len(),
+               worker_count)
+    }
+}
+
+fn print_report(report: &ReplayReport, worker_count: usize) {
+    println!("");
+    println!("Fuzzing report ({} workers):", worker_count);
+    println!("- compiler: {}", report.rustc_version.replace("\n", "\n  "));
+    println!("- cargo: {}", report.cargo_version);
+    println!("- {} commits built", report.commits.len());
+    println!("- {} total tests executed ({} of those passed)",
+             report.tests_total,
+             report.tests_passed);
+    println!("- {} of {} (or {:.0}%) modules were re-used",
+             report.modules_reused,
+             report.modules_total,
+             report.reuse_percent());
+    if report.queries_total > 0 {
+        println!("- {} of {} (or {:.0}%) query results were loaded from the cache",
+                 report.queries_reused,
+                 report.queries_total,
+                 report.queries_reused as f64 / report.queries_total as f64 * 100.0);
+    }
+    if !report.divergences.is_empty() {
+        println!("- {} divergences found:", report.divergences.len());
+        for divergence in &report.divergences {
+            println!("  - {} ({}): {}",
+                     divergence.commit_id,
+                     divergence.stage,
+                     divergence.message.lines().next().unwrap_or(""));
+        }
+    }
+}
+
+// Runs `git` in `repo_dir`. The version of git2 we use knows nothing about
+// worktrees, so we leave them to the command-line tool.
+fn run_git(repo_dir: &Path, git_args: &[&str]) {
+    let mut cmd = Command::new("git");
+    cmd.current_dir(repo_dir).args(git_args);
+    debug!("{:?}", cmd);
+    match cmd.output() {
+        Ok(output) => {
+            if !output.status.success() {
+                util::print_output(&output);
+                error!("`git {}` failed", git_args.join(" "))
+            }
+        }
+        Err(err) => error!("could not execute `git {}`: {}", git_args.join(" "), err),
+    }
+}
+
+// Drops the `WORKER_OPTIONS` from the command line we were started with,
+// in both the `--option VALUE` and the `--option=VALUE` form.
+fn worker_args<I>(user_args: I) -> Vec<String>
+    where I: Iterator<Item = String>
+{
+    let mut result = vec![];
+    let mut skip_value = false;
+    for arg in user_args {
+        if skip_value {
+            skip_value = false;
+        } else if WORKER_OPTIONS.contains(&&arg[..]) {
+            skip_value = true;
+        } else if !WORKER_OPTIONS.iter().any(|option| arg.starts_with(&format!("{}=", option))) {
+            result.push(arg);
+        }
+    }
+    result
+}
+
+// Combines the reports of the workers. The build times are left out, since
+// they were measured while the workers competed for the CPUs.
+fn merge_reports(revisions: &str,
+                 commit_ids: &[String],
+                 reports: &[ReplayReport])
+                 -> ReplayReport {
+    let mut merged = ReplayReport {
+        revisions: revisions.to_string(),
+        .. ReplayReport::default()
+    };
+    // The workers all use the same toolchain.
+    if let Some(report) = reports.first() {
+        merged.rustc_version = report.rustc_version.clone();
+        merged.cargo_version = report.cargo_version.clone();
+    }
+    for report in reports {
+        merged.commits.extend(report.commits.iter().map(|commit| {
+            CommitReport {
+                normal_build_times: vec![],
+                incremental_build_times: vec![],
+                .. commit.clone()
+            }
+        }));
+        merged.modules_reused += report.modules_reused;
+        merged.modules_total += report.modules_total;
+        merged.queries_reused += report.queries_reused;
+        merged.queries_total += report.queries_total;
+        merged.tests_total += report.tests_total;
+        merged.tests_passed += report.tests_passed;
+        merged.divergences.extend(report.divergences.iter().cloned());
+    }
+
+    // `--shard` deals the commits out to the workers in turn, so put them
+    // back in the order of `commit_ids`. The divergences only know the short
+    // id of their commit.
+    let positions: HashMap<&str, usize> = commit_ids.iter()
+        .enumerate()
+        .map(|(position, id)| (&id[..], position))
+        .collect();
+    merged.commits.sort_by_key(|commit| positions.get(&commit.oid[..]).cloned());
+    merged.divergences.sort_by_key(|divergence| {
+        commit_ids.iter().position(|id| id.starts_with(&divergence.commit_id))
+    });
+    merged
+}
+
+#[test]
+fn test_worker_args() {
+    let args = ["--cargo", "foo/Cargo.toml", "--skip-tests", "--work-dir=/tmp/work",
+                "--max-parallel-commits", "4", "master~10..master"];
+    assert_eq!(worker_args(args.iter().map(|arg| arg.to_string())),
+               vec!["--skip-tests".to_string(), "master~10..master".to_string()]);
+}
+
+#[test]
+fn test_merge_reports_keeps_history_order() {
+    use super::report::DivergenceReport;
+
+    let commit_ids: Vec<String> = ["aaaa1111", "bbbb2222", "cccc3333", "dddd4444", "eeee5555"]
+        .iter()
+        .map(|id| id.to_string())
+        .collect();
+    let commit = |index: usize| {
+        CommitReport {
+            id: commit_ids[index][..4].to_string(),
+            oid: commit_ids[index].clone(),
+            .. CommitReport::default()
+        }
+    };
+    let divergence = |index: usize| {
+        DivergenceReport {
+            commit_id: commit_ids[index][..4].to_string(),
+            .. DivergenceReport::default()
+        }
+    };
+
+    // With two workers, worker 0 gets commits 0, 2 and 4, worker 1 gets 1 and 3.
+    let reports = vec![ReplayReport {
+                           commits: vec![commit(0), commit(2), commit(4)],
+                           divergences: vec![divergence(4)],
+                           .. ReplayReport::default()
+                       },
+                       ReplayReport {
+                           commits: vec![commit(1), commit(3)],
+                           divergences: vec![divergence(1)],
+                           .. ReplayReport::default()
+                       }];
+    let merged = merge_reports("master~5..master", &commit_ids, &reports);
+    let oids: Vec<_> = merged.commits.iter().map(|commit| &commit.oid[..]).collect();
+    assert_eq!(oids, vec!["aaaa1111", "bbbb2222", "cccc3333", "dddd4444", "eeee5555"]);
+    let divergent: Vec<_> = merged.divergences.iter().map(|d| &d.commit_id[..]).collect();
+    assert_eq!(divergent, vec!["bbbb", "eeee"]);
+}
@@ -1,44 +1,80 @@
+use git2::{self, Commit, Delta, Diff, DiffFindOptions, DiffOptions, Oid, Patch, Pathspec,
+           Repository};
 use progress::Bar;
 use regex::Regex;
+use std::cmp;
 use std::collections::BTreeSet;
 use std::env;
 use std::io::prelude::*;
 use std::io::{self, SeekFrom};
 use std::path::{Path, PathBuf};
-use std::process::Command;
-use std::fs::{File, OpenOptions};
+use std::process::Output;
+use std::fs::{self, File, OpenOptions};
+use std::mem;
+use std::sync::mpsc;
+use std::thread;
 use std::time;
 
-use super::Args;
+use super::{Args, OnDivergence, ReferenceBuild, ReportFormat, TestRunner};
 use super::dfs;
+use super::junit;
+use super::parallel;
+use rustc_serialize::json::{Json, ToJson};
+use std::collections::BTreeMap;
+
+use super::html;
+use super::report::{CommitReport, CommitStatus, DivergenceReport, ReplayReport};
 use super::util;
-use super::util::{cargo_build, CompilationStats, IncrementalOptions, TestResult,
-                  TestCaseResult};
+use super::util::{cargo_build, BuildDriver, BuildResult, CargoCommand, CargoOptions,
+                  CompilationStats, IncrementalOptions, TestResult, TestCaseResult};
 
 const CHECKOUT: &'static str = "checkout";
 const NORMAL_BUILD: &'static str = "normal build";
 const INCREMENTAL_BUILD: &'static str = "incremental build";
 const COMPARE_BUILDS: &'static str = "compare incr/normal builds";
+const COMPARE_BINARIES: &'static str = "compare incr/normal binaries";
 const NORMAL_TEST: &'static str = "normal test";
 const INCREMENTAL_TEST: &'static str = "incremental test";
 const COMPARE_TESTS: &'static str = "compare incr/normal tests";
 const INCREMENTAL_BUILD_NO_CHANGE: &'static str = "incremental build / no change";
+const INCREMENTAL_BUILD_DETERMINISM: &'static str = "incremental build / determinism";
 const INCREMENTAL_BUILD_NO_CACHE: &'static str = "incremental build / no cache";
+const INCREMENTAL_TEST_NO_CACHE: &'static str = "incremental test / no cache";
+const COMPARE_THREE_WAY: &'static str = "compare normal/incr/from-scratch binaries";
+
+// How much of a commit's summary line we show in progress output.
+const MAX_SUMMARY_CHARS: usize = 40;
+
+// The progress bar pads its job title to this many columns and fits the bar
+// into the rest of the line, so a longer title wraps on every redraw.
+const PROGRESS_TITLE_CHARS: usize = 50;
+
+// Unchanged lines shown around each difference with `--diff`.
+const DIFF_CONTEXT: usize = 3;
+
+// Records which commits a replay has completed, for `--resume`.
+const PROGRESS_FILE: &'static str = "progress.json";
 
 const STAGES: &'static [&'static str] = &[CHECKOUT,
                                           NORMAL_BUILD,
                                           INCREMENTAL_BUILD,
                                           COMPARE_BUILDS,
+                                          COMPARE_BINARIES,
                                           NORMAL_TEST,
                                           INCREMENTAL_TEST,
                                           COMPARE_TESTS,
                                           INCREMENTAL_BUILD_NO_CHANGE,
-                                          INCREMENTAL_BUILD_NO_CACHE];
+                                          INCREMENTAL_BUILD_DETERMINISM,
+                                          INCREMENTAL_BUILD_NO_CACHE,
+                                          COMPARE_THREE_WAY,
+                                          INCREMENTAL_TEST_NO_CACHE];
 
 pub fn replay(args: &Args) {
     assert!(args.cmd_replay);
     debug!("replay(): revisions = {}", args.arg_revisions);
 
+    util::check_cargo_on_path();
+
     let cargo_toml_path = Path::new(&args.flag_cargo);
 
     if !cargo_toml_path.exists() || !cargo_toml_path.is_file() {
@@ -49,7 +85,7 @@ pub fn replay(args: &Args) {
     let cargo_toml_pathref = cargo_toml_path.canonicalize().unwrap();
     let cargo_toml_path = cargo_toml_pathref.as_path();
 
-    let ref repo = match util::open_repo(cargo_toml_path) {
+    let ref repo = match util::open_repo(cargo_toml_path, args.flag_quiet) {
         Ok(repo) => repo,
         Err(e) => {
             error!("failed to find repository containing `{}`: {}",
@@ -58,22 +94,57 @@ pub fn replay(args: &Args) {
         }
     };
 
+    util::check_work_dir_outside_repo(repo, Path::new(&args.flag_work_dir), "--work-dir");
+    if !args.flag_incr_dir.is_empty() {
+        util::check_work_dir_outside_repo(repo, Path::new(&args.flag_incr_dir), "--incr-dir");
+    }
     util::check_clean(repo);
 
-    // Filter down to the range of revisions specified by the user
+    // Remember where HEAD was, so that we can put the repository back the way
+    // we found it if the user interrupts us.
+    let original_head = match repo.head() {
+        Ok(head) => head,
+        Err(err) => error!("failed to read HEAD: {}", err),
+    };
+    let original_head_branch = if original_head.is_branch() {
+        original_head.name().map(|name| name.to_string())
+    } else {
+        None
+    };
+    let original_head_commit = match original_head.target().map(|oid| repo.find_commit(oid)) {
+        Some(Ok(commit)) => commit,
+        Some(Err(err)) => error!("failed to load commit at HEAD: {}", err),
+        None => error!("HEAD does not point at a commit"),
+    };
+
+    let restore_repo = || {
+        restore_original_head(repo, &original_head_commit, original_head_branch.as_ref())
+    };
+
+    util::install_interrupt_handler();
+
+    // Filter down to the range of revisions specified by the user. We hand
+    // the whole revspec to git, so that things like `@{upstream}..HEAD` or
+    // `origin/master..HEAD` mean what they mean to git.
+    let revisions = match repo.revparse(&args.arg_revisions) {
+        Ok(revspec) => revspec,
+        Err(err) => {
+            error!("failed to parse revspec `{}`: {}",
+                   args.arg_revisions,
+                   err)
+        }
+    };
+
     let (from_commit, to_commit);
-    if args.arg_revisions.contains("..") {
-        let revisions = match repo.revparse(&args.arg_revisions) {
-            Ok(revspec) => revspec,
-            Err(err) => {
-                error!("failed to parse revspec `{}`: {}",
-                       args.arg_revisions,
-                       err)
-            }
+    if revisions.mode().contains(git2::REVPARSE_SINGLE) {
+        from_commit = None;
+        to_commit = match revisions.from() {
+            Some(object) => util::commit_or_error(object.clone()),
+            None => error!("revspec `{}` did not name a commit", args.arg_revisions),
         };
-
-        from_commit = match revisions.from() {
-            Some(object) => Some(util::commit_or_error(object.clone())),
+    } else {
+        let from = match revisions.from() {
+            Some(object) => util::commit_or_error(object.clone()),
             None => {
                 error!("revspec `{}` had no \"from\" point specified",
                        args.arg_revisions)
@@ -88,23 +159,113 @@ pub fn replay(args: &Args) {
                        args.arg_revisions)
             }
         };
-    } else {
-        from_commit = None;
-        to_commit = match repo.revparse_single(&args.arg_revisions) {
-            Ok(revspec) => util::commit_or_error(revspec),
-            Err(err) => {
-                error!("failed to parse revspec `{}`: {}",
-                       args.arg_revisions,
-                       err)
+
+        // For `A...B`, git would take the commits on both sides of the fork.
+        // They don't form a path, so we only replay the `B` side, starting
+        // from the merge base: `A...B` is `$(git merge-base A B)..B`.
+        from_commit = if revisions.mode().contains(git2::REVPARSE_MERGE_BASE) {
+            let merge_base = match repo.merge_base(from.id(), to_commit.id()) {
+                Ok(oid) => oid,
+                Err(err) => {
+                    error!("failed to find the merge base of revspec `{}`: {}",
+                           args.arg_revisions,
+                           err)
+                }
+            };
+            match repo.find_commit(merge_base) {
+                Ok(commit) => Some(commit),
+                Err(err) => error!("failed to find merge base `{}`: {}", merge_base, err),
             }
+        } else {
+            Some(from)
         };
     }
 
     let commits = dfs::find_path(from_commit, to_commit);
+    if commits.is_empty() {
+        // E.g. `HEAD..HEAD`; without this we would go on to report on no
+        // builds at all, dividing by zero along the way.
+        error!("revision range `{}` resolved to 0 commits; nothing to test",
+               args.arg_revisions);
+    }
+    let commits = filter_commits(repo, commits, args);
+    if commits.is_empty() {
+        error!("`--filter-path`, `--exclude-path` and `--filter-author` left none of the \
+                commits in `{}`; nothing to test",
+               args.arg_revisions);
+    }
+    let commits = shard_commits(commits, args);
+    if commits.is_empty() {
+        // With more shards than commits, some get nothing to do. That is
+        // not an error, so that e.g. a CI job per shard still passes.
+        if !args.flag_quiet {
+            println!("shard `{}` has no commits; nothing to test", args.flag_shard);
+        }
+        return;
+    }
+
+    if !args.flag_dump_order.is_empty() {
+        dump_order(&commits, Path::new(&args.flag_dump_order));
+        return;
+    }
 
-    // Start out by cleaning up any existing work directory.
+    if args.flag_print_commit_list_json {
+        println!("{}", commit_list_json(&commits));
+        if args.flag_exit_after_list {
+            return;
+        }
+    }
+
+    if args.flag_dry_run {
+        print_plan(&commits, cargo_toml_path, args);
+        return;
+    }
+
+    if args.flag_max_parallel_commits > 1 {
+        let commit_ids: Vec<_> = commits.iter().map(|commit| commit.id().to_string()).collect();
+        parallel::replay(repo, cargo_toml_path, &commit_ids, args);
+        return;
+    }
+
+    // Start out by cleaning up any existing work directory, unless we are
+    // resuming an earlier replay in it.
     let work_dir = Path::new(&args.flag_work_dir);
-    util::remove_dir(work_dir);
+    let progress_path = work_dir.join(PROGRESS_FILE);
+    let resumed_count = if args.flag_resume {
+        load_progress(&progress_path, &commits, args.flag_quiet)
+    } else {
+        None
+    };
+    if resumed_count.is_none() {
+        // A replay that stopped at a divergence leaves its tmpfs mounted, and
+        // removing a mount point fails.
+        if util::is_mount_point(work_dir) {
+            if !args.flag_quiet {
+                println!("unmounting `{}`, which an earlier replay left mounted",
+                         work_dir.display());
+            }
+            if let Err(err) = util::unmount(work_dir) {
+                error!("`{}` is still mounted, and we could not unmount it: {}",
+                       work_dir.display(),
+                       err)
+            }
+        }
+        util::remove_dir(work_dir);
+    }
+
+    // When resuming, the earlier replay's tmpfs (if any) is still mounted.
+    let on_tmpfs = resumed_count.is_none() && !args.flag_tmpfs_size.is_empty() && {
+        util::make_dir(work_dir);
+        match util::mount_tmpfs(work_dir, &args.flag_tmpfs_size) {
+            Ok(()) => true,
+            Err(err) => {
+                if !args.flag_quiet {
+                    println!("warning: {}; using `{}` as it is", err, work_dir.display());
+                }
+                false
+            }
+        }
+    };
 
     // We structure our work directory like:
     //
@@ -112,8 +273,34 @@ pub fn replay(args: &Args) {
     // work/incr <-- incremental compilation cache
     // work/from_scratch <-- incremental compilation cache for from-scratch builds
     // work/commits/1231123 <-- output from building 1231123
-    let target_normal_dir = util::absolute_dir_path(&work_dir.join("target-normal"));
-    let target_incr_dir = util::absolute_dir_path(&work_dir.join("target-incr"));
+    //
+    // With `--incr-dir`, the incremental compilation caches go there instead.
+    // When resuming, these still hold what the earlier replay left in them.
+    let sub_dir = |base: &Path, name: &str| {
+        if resumed_count.is_some() {
+            util::reused_dir_path(&base.join(name))
+        } else {
+            util::absolute_dir_path(&base.join(name))
+        }
+    };
+    let work_sub_dir = |name: &str| sub_dir(work_dir, name);
+    let target_normal_dir = work_sub_dir("target-normal");
+    let target_incr_dir = work_sub_dir("target-incr");
+
+    let incr_base_dir = if args.flag_incr_dir.is_empty() {
+        work_dir
+    } else {
+        Path::new(&args.flag_incr_dir)
+    };
+    if resumed_count.is_none() && !args.flag_incr_dir.is_empty() {
+        // We only own these, not the whole `--incr-dir`.
+        for dir in &["incr-workspace",
+                     "incr-evacuated",
+                     "incr-determinism-before",
+                     "incr-determinism-first"] {
+            util::remove_dir(&incr_base_dir.join(dir));
+        }
+    }
 
     // We always use this directory as the incr. comp. cache directory, so we
     // can always pass the same commandline arguments to Cargo. Cargo does not
@@ -123,7 +310,7 @@ pub fn replay(args: &Args) {
     //
     // We copy the contents into another directory before overwriting them, so
     // we can compare later.
-    let incr_comp_workspace = util::absolute_dir_path(&work_dir.join("incr-workspace"));
+    let incr_comp_workspace = sub_dir(incr_base_dir, "incr-workspace");
 
     let incr_options = if args.flag_just_current {
         IncrementalOptions::CurrentProject(&incr_comp_workspace)
@@ -133,15 +320,83 @@ pub fn replay(args: &Args) {
 
     // This is where we copy the contents of incr_comp_workspace before
     // overwriting them in the from-scratch test
-    let incr_evacuated = util::absolute_dir_path(&work_dir.join("incr-evacuated"));
+    let incr_evacuated = sub_dir(incr_base_dir, "incr-evacuated");
+
+    // For `--incr-determinism-check`: the cache as it was before the
+    // incremental build of the current commit, and the cache that build left
+    // while we build again from the former.
+    let incr_determinism_before = sub_dir(incr_base_dir, "incr-determinism-before");
+    let incr_determinism_first = sub_dir(incr_base_dir, "incr-determinism-first");
     let commits_dir = work_dir.join("commits");
     util::make_dir(&commits_dir);
 
+    // Stripped copies of the final binaries go here when using
+    // `--strip-before-compare`.
+    let stripped_dir = work_dir.join("stripped");
+
+    // With `--compare-three-way`, the from-scratch build overwrites the
+    // incremental build's binaries, so we keep copies of them here.
+    let incr_artifacts_dir = work_dir.join("incr-artifacts");
+
     let cargo_dir = match cargo_toml_path.parent() {
         Some(p) => p,
         None => error!("Cargo.toml path has no parent: {}", args.flag_cargo),
     };
 
+    if !args.flag_quiet {
+        for setting in util::target_dir_settings(cargo_dir) {
+            println!("warning: {} sets a target directory; we override it with \
+                      `CARGO_TARGET_DIR` to keep the builds apart, and will fail if that \
+                      does not work",
+                     setting);
+        }
+    }
+
+    let mut cargo_options = args.cargo_options();
+    if args.flag_clean_registry {
+        cargo_options.cargo_home = Some(work_sub_dir("cargo-home"));
+    }
+    if args.flag_self_profile {
+        // rustc writes its profiles here, and we move those of each
+        // incremental build to the commit's directory. The path ends up in
+        // RUSTFLAGS, which cargo hashes into `-C metadata`, so it has to be
+        // the same for all builds.
+        cargo_options.self_profile_dir = Some(work_sub_dir("self-profile"));
+    }
+    if args.flag_verbose {
+        cargo_options.print_summary();
+    } else if !args.flag_quiet {
+        cargo_options.warn_about_wrapper();
+    }
+    let only_crate = cargo_options.only_crate.as_ref().map(|name| &name[..]);
+    util::check_incremental_support(cargo_dir, &cargo_options);
+    let (rustc_version, cargo_version) = util::toolchain_versions(cargo_dir, &cargo_options);
+
+    // The options for the normal build, which is only a `cargo check` with
+    // `--reference check`. Everything else, including the normal test run,
+    // uses `cargo_options`.
+    let reference_options = match args.flag_reference {
+        ReferenceBuild::Build => cargo_options.clone(),
+        ReferenceBuild::Check => {
+            CargoOptions {
+                driver: BuildDriver::Check,
+                .. cargo_options.clone()
+            }
+        }
+    };
+
+    if args.flag_snapshot_incr && !args.flag_quiet {
+        println!("warning: `--snapshot-incr` keeps a copy of the incremental cache for every \
+                  commit in `{}`, which can use a lot of disk space",
+                 commits_dir.display());
+    }
+
+    if !args.flag_target.is_empty() && !args.flag_skip_tests && !args.flag_quiet {
+        println!("warning: tests will be run for `--target {}`; if that target cannot run \
+                  them (e.g. because it is `no_std`), use `--skip-tests`",
+                 args.flag_target);
+    }
+
     let mut bar = Bar::new();
     let mut stats_normal = CompilationStats::default();
     let mut stats_incr = CompilationStats::default();
@@ -149,99 +404,482 @@ pub fn replay(args: &Args) {
 
     let (mut tests_total, mut tests_passed) = (0, 0);
 
+    // The tree and normal build result of the previous commit. If the next
+    // commit has the same tree (e.g. an empty merge), we can skip its normal
+    // build.
+    let mut previous_normal_build: Option<(Oid, BuildResult)> = None;
+
+    let work_dir_note = if on_tmpfs {
+        "the work directory (a tmpfs, which stays mounted)"
+    } else {
+        "the work directory"
+    };
+    let mut divergences = DivergenceLog::new(args,
+                                             &commits_dir,
+                                             vec![("the checkout", cargo_dir.to_path_buf()),
+                                                  ("the incremental compilation cache",
+                                                   incr_comp_workspace.clone()),
+                                                  ("the cache evacuated for the from-scratch \
+                                                    build",
+                                                   incr_evacuated.clone()),
+                                                  (work_dir_note, work_dir.to_path_buf())],
+                                             ReplayReport {
+                                                 revisions: args.arg_revisions.clone(),
+                                                 rustc_version: rustc_version,
+                                                 cargo_version: cargo_version,
+                                                 .. ReplayReport::default()
+                                             });
+
+    // The `Cargo.lock` produced by `--update-before-build`, which we reuse
+    // for all later commits.
+    let mut updated_lockfile = None;
+
+    // The size of the incremental cache after each commit, for
+    // `--track-cache-size`.
+    let mut cache_sizes: Vec<(String, u64)> = vec![];
+
+    // Commits that neither the normal nor the incremental build could
+    // build. Their builds "agree", but that says little. We keep the
+    // `exit_reason` of the first killed build, if any.
+    let mut broken_commits: Vec<(String, String, Option<String>)> = vec![];
+
+    // Commits whose incremental build failed, and whether their normal
+    // build succeeded (`None` with `--skip-normal`). An incremental build
+    // failing on its own is a different bug from one that builds but
+    // diverges.
+    let mut incr_build_failures: Vec<(String, String, Option<bool>)> = vec![];
+
+    // Commits that git failed to check out, with `--skip-uncheckoutable`,
+    // and why.
+    let mut skipped_commits: Vec<(String, String, String)> = vec![];
+
+    // Whether we have made sure that the incremental builds use the cache.
+    let mut checked_incr_cache = false;
+
+    // Whether we have made sure that the builds use their own target
+    // directories.
+    let mut checked_target_dirs = false;
+
     let start_time = time::Instant::now();
 
-    for (index, commit) in commits.iter().enumerate() {
+    // With `--cli-log`, build output is printed rather than saved, unless we
+    // only print a summary line per commit.
+    let save_build_output = !args.flag_cli_log || args.flag_summary_only;
+
+    let resumed_count = resumed_count.unwrap_or(0);
+    if resumed_count > 0 && !args.flag_quiet {
+        println!("resuming after {} commits completed by an earlier replay", resumed_count);
+    }
+
+    // The earlier replay may have got partway through the next commit, so the
+    // incremental cache and target directory can hold its state rather than
+    // that of the last completed commit. We build the latter again, so that
+    // the first resumed commit still starts from its parent's cache.
+    if resumed_count > 0 {
+        let index = resumed_count - 1;
+        let commit = &commits[index];
+        let short_id = util::short_id(commit);
+        let rebuilt = util::checkout_commit(repo, commit).is_ok() && {
+            if args.flag_no_debuginfo {
+                if let Err(err) = inject_no_debug_into_cargo_toml(&cargo_dir) {
+                    error!("error while injecting no_debug into Cargo.toml: {}", err)
+                }
+            }
+            if args.flag_update_before_build || args.flag_update_each_commit {
+                update_lockfile(&cargo_dir,
+                                &target_normal_dir,
+                                &mut updated_lockfile,
+                                &cargo_options,
+                                args);
+            }
+            let commit_dir = commits_dir.join(format!("{:04}-{}-resume-rebuild", index, short_id));
+            util::remove_dir(&commit_dir);
+            util::make_dir(&commit_dir);
+            cargo_build(&cargo_dir,
+                        &commit_dir,
+                        &target_incr_dir,
+                        incr_options,
+                        &mut CompilationStats::default(),
+                        true,
+                        args.flag_verbose,
+                        args.flag_verbose,
+                        &cargo_options)
+                .success
+        };
+        if !rebuilt && !args.flag_quiet {
+            println!("warning: could not rebuild {} before resuming; the incremental build of \
+                      the next commit may not start from its parent's cache",
+                     short_id);
+        }
+    }
+
+    for (index, commit) in commits.iter().enumerate().skip(resumed_count) {
         let short_id = util::short_id(commit);
+        let summary = util::commit_summary(commit, MAX_SUMMARY_CHARS);
+        divergences.so_far.current_commit = (short_id.clone(), summary.clone());
+        let mut normal_samples = vec![];
+        let mut incr_samples = vec![];
         let mut sub_task_runner = SubTaskRunner {
             progress_bar: &mut bar,
             commit_id: short_id.clone(),
+            commit_summary: summary.clone(),
             commit_index: index,
             cli_log: args.flag_cli_log,
+            summary_only: args.flag_summary_only,
+            stage_results: vec![],
+            quiet: args.flag_quiet,
+            on_interrupt: &restore_repo,
             total_commit_count: commits.len(),
             global_start_time: start_time,
         };
 
-        if args.flag_cli_log {
-            println!("\nTESTING COMMIT {} ({} of {})", short_id, index + 1, commits.len());
+        if args.flag_cli_log && !args.flag_summary_only {
+            println!("\nTESTING COMMIT {} \"{}\" ({} of {})",
+                     short_id,
+                     summary,
+                     index + 1,
+                     commits.len());
         }
 
-        sub_task_runner.run(CHECKOUT, || {
-            util::checkout_commit(repo, commit);
+        let check_out_time = time::Instant::now();
+        let checkout_error = sub_task_runner.run(CHECKOUT, || {
+            if let Err(err) = util::checkout_commit(repo, commit) {
+                if args.flag_skip_uncheckoutable {
+                    return (Some(err), "skipped");
+                }
+                error!("{}", err)
+            }
             if args.flag_no_debuginfo {
                 if let Err(err) = inject_no_debug_into_cargo_toml(&cargo_dir) {
                     error!("error while injecting no_debug into Cargo.toml: {}", err)
                 }
             }
-            ((), "OK")
+            if args.flag_update_before_build || args.flag_update_each_commit {
+                update_lockfile(&cargo_dir,
+                                &target_normal_dir,
+                                &mut updated_lockfile,
+                                &cargo_options,
+                                args);
+            }
+            (None, "OK")
         });
 
+        if let Some(err) = checkout_error {
+            if !args.flag_quiet {
+                println!("warning: {}; skipping this commit", err);
+            }
+            // The checkout may have stopped halfway, so we put back the
+            // commit that HEAD still points at, so the next checkout starts
+            // from a clean tree.
+            let head = repo.head().ok().and_then(|head| head.target());
+            if let Some(head) = head.and_then(|oid| repo.find_commit(oid).ok()) {
+                util::reset_repo(repo, &head);
+            }
+            skipped_commits.push((short_id.clone(), summary.clone(), err));
+            divergences.so_far.commit_statuses.push(CommitStatus {
+                id: short_id.clone(),
+                summary: summary.clone(),
+                build: "SKIPPED",
+                .. CommitStatus::default()
+            });
+            sub_task_runner.print_commit_summary();
+            save_progress(&progress_path, &commits, index + 1);
+            continue;
+        }
+
+        if index > 0 && ((args.flag_cli_log && !args.flag_summary_only) || args.flag_verbose) {
+            print_diff_stat(repo, &commits[index - 1], commit);
+        }
+
+        // Renaming a module file should leave most of the cache usable, but
+        // rustc may not see it that way.
+        let rename_only = index > 0 && is_rename_only(repo, &commits[index - 1], commit);
+
         // NORMAL BUILD --------------------------------------------------------
         let normal_build_result = sub_task_runner.run(NORMAL_BUILD, || {
+            if args.flag_skip_normal {
+                return (None, "skipped");
+            }
+
+            if !args.flag_no_tree_dedup {
+                if let Some((ref tree_id, ref result)) = previous_normal_build {
+                    if *tree_id == commit.tree_id() {
+                        return (Some(result.clone()), "reused (identical tree)");
+                    }
+                }
+            }
+
             let commit_dir = commits_dir.join(format!("{:04}-{}-normal-build", index, short_id));
             util::make_dir(&commit_dir);
 
             util::cargo_clean(&cargo_dir,
                               &target_normal_dir,
-                              args.flag_just_current);
-
-            (cargo_build(&cargo_dir,
-                         &commit_dir,
-                         &target_normal_dir,
-                         IncrementalOptions::None,
-                         &mut stats_normal,
-                         !args.flag_cli_log,
-                         args.flag_verbose),
-             "OK")
+                              args.flag_just_current,
+                              args.flag_verbose,
+                              &cargo_options);
+
+            let time_before = stats_normal.build_time;
+            let (result, retried) = retry_transient_failures(args.flag_retries, || {
+                cargo_build(&cargo_dir,
+                            &commit_dir,
+                            &target_normal_dir,
+                            IncrementalOptions::None,
+                            &mut stats_normal,
+                            save_build_output,
+                            args.flag_verbose,
+                            args.flag_verbose,
+                            &reference_options)
+            });
+            util::save_repro_script(&commit_dir, commit, &result.command);
+
+            if result.success {
+                normal_samples.push(stats_normal.build_time - time_before);
+                for _ in 1..args.flag_samples {
+                    util::cargo_clean(&cargo_dir,
+                                      &target_normal_dir,
+                                      args.flag_just_current,
+                                      args.flag_verbose,
+                                      &cargo_options);
+                    normal_samples.push(sample_build_time(&cargo_dir,
+                                                          &commit_dir,
+                                                          &target_normal_dir,
+                                                          IncrementalOptions::None,
+                                                          args,
+                                                          &reference_options));
+                }
+            }
+
+            (Some(result), if retried { "OK (retried)" } else { "OK" })
         });
 
+        if let Some(ref result) = normal_build_result {
+            previous_normal_build = Some((commit.tree_id(), result.clone()));
+        }
+
         // INCREMENTAL BUILD ---------------------------------------------------
+        let modules_before = (stats_incr.modules_reused, stats_incr.modules_total);
         let incr_build_result = sub_task_runner.run(INCREMENTAL_BUILD, || {
             let commit_dir = commits_dir.join(format!("{:04}-{}-incr-build", index, short_id));
             util::make_dir(&commit_dir);
 
+            if args.flag_incr_determinism_check {
+                // Hardlinks are fine, see `--snapshot-incr` below.
+                util::remove_dir(&incr_determinism_before);
+                util::link_or_copy_dir(&incr_comp_workspace, &incr_determinism_before);
+            }
+
             util::cargo_clean(&cargo_dir,
                               &target_incr_dir,
-                              args.flag_just_current);
-
-            (cargo_build(&cargo_dir,
-                         &commit_dir,
-                         &target_incr_dir,
-                         incr_options,
-                         &mut stats_incr,
-                         !args.flag_cli_log,
-                         args.flag_verbose),
-             "OK")
+                              args.flag_just_current,
+                              args.flag_verbose,
+                              &cargo_options);
+
+            if let Some(ref self_profile_dir) = cargo_options.self_profile_dir {
+                // Drop the profiles of the other builds since the last commit.
+                util::remove_dir(self_profile_dir);
+                util::make_dir(self_profile_dir);
+            }
+
+            // A failed attempt may have left things in the incremental cache
+            // that the retry then picks up, which could hide a real bug. So
+            // we only retry here if the user asked for it explicitly.
+            let retries = if args.flag_retry_incremental {
+                args.flag_retries
+            } else {
+                0
+            };
+            let time_before = stats_incr.build_time;
+            let (result, retried) = retry_transient_failures(retries, || {
+                cargo_build(&cargo_dir,
+                            &commit_dir,
+                            &target_incr_dir,
+                            incr_options,
+                            &mut stats_incr,
+                            save_build_output,
+                            args.flag_verbose,
+                            args.flag_verbose,
+                            &cargo_options)
+            });
+            util::save_repro_script(&commit_dir, commit, &result.command);
+
+            if let Some(ref self_profile_dir) = cargo_options.self_profile_dir {
+                util::rename_directory(self_profile_dir, &commit_dir.join("self-profile"));
+                util::make_dir(self_profile_dir);
+            }
+
+            if args.flag_snapshot_incr {
+                // rustc creates a new session directory for each build rather
+                // than changing files in place, so hardlinks stay intact.
+                let snapshot_dir =
+                    commits_dir.join(format!("{:04}-{}-incr-cache", index, short_id));
+                util::link_or_copy_dir(&incr_comp_workspace, &snapshot_dir);
+            }
+
+            if result.success {
+                incr_samples.push(stats_incr.build_time - time_before);
+                // The cache is warm now, so all further samples measure
+                // rebuilds without any changes.
+                for _ in 1..args.flag_samples {
+                    util::cargo_clean(&cargo_dir,
+                                      &target_incr_dir,
+                                      args.flag_just_current,
+                                      args.flag_verbose,
+                                      &cargo_options);
+                    incr_samples.push(sample_build_time(&cargo_dir,
+                                                        &commit_dir,
+                                                        &target_incr_dir,
+                                                        incr_options,
+                                                        args,
+                                                        &cargo_options));
+                }
+            }
+
+            if check_ich_verification(INCREMENTAL_BUILD, &result, &short_id, &mut divergences) {
+                (result, "DIVERGED")
+            } else if retried {
+                (result, "OK (retried)")
+            } else {
+                (result, "OK")
+            }
         });
 
+        // If rustc never got our `-Z incremental` flag (e.g. because something
+        // overrides RUSTFLAGS), the "incremental" builds are normal builds
+        // and every comparison trivially passes. So check once that the
+        // first successful incremental build filled the cache. (rustdoc
+        // doesn't necessarily use it.)
+        if !checked_incr_cache && incr_build_result.success && !args.flag_doc {
+            if !has_session_dirs(&incr_comp_workspace) {
+                error!("the incremental build of {} succeeded but left no session directory \
+                        in `{}`, so incremental compilation did not engage; is something \
+                        overriding RUSTFLAGS?",
+                       short_id,
+                       incr_comp_workspace.display())
+            }
+            checked_incr_cache = true;
+        }
+
+        // Likewise, check once that the builds went into the target
+        // directories we gave them. If not, the normal build would have used
+        // the incremental build's artifacts, tripping up the checks later.
+        if !checked_target_dirs && incr_build_result.success {
+            let mut target_dirs = vec![target_incr_dir.as_path()];
+            if normal_build_result.as_ref().map_or(false, |r| r.success) {
+                target_dirs.push(target_normal_dir.as_path());
+            }
+            util::check_target_dirs_used(&target_dirs, &cargo_dir);
+            checked_target_dirs = true;
+        }
+
         // COMPARE BUILD CLI OUTPUT --------------------------------------------
         sub_task_runner.run(COMPARE_BUILDS, || {
-            if normal_build_result != incr_build_result {
-                println!("OUTPUT OF NORMAL BUILD:\n");
-                util::print_output(&normal_build_result.raw_output);
-
-                println!("\nOUTPUT OF INCREMENTAL BUILD:\n");
-                util::print_output(&incr_build_result.raw_output);
+            let normal_build_result = match normal_build_result {
+                Some(ref result) => result,
+                None => return ((), "skipped"),
+            };
+
+            if *normal_build_result != incr_build_result {
+                if divergences.is_fatal() && args.flag_diff {
+                    println!("DIFF OF NORMAL (-) AND INCREMENTAL (+) BUILD OUTPUT:\n");
+                    print_diff(&util::output_text(&normal_build_result.raw_output),
+                               &util::output_text(&incr_build_result.raw_output),
+                               args);
+                } else if divergences.is_fatal() {
+                    println!("OUTPUT OF NORMAL BUILD:\n");
+                    util::print_output(&normal_build_result.raw_output);
+
+                    println!("\nOUTPUT OF INCREMENTAL BUILD:\n");
+                    util::print_output(&incr_build_result.raw_output);
+                }
 
-                error!("incremental build differed from normal build")
+                let mut message = "incremental build differed from normal build".to_string();
+                for &(name, result) in &[("normal build", normal_build_result),
+                                         ("incremental build", &incr_build_result)] {
+                    if let Some(ref reason) = result.exit_reason {
+                        message.push_str(&format!("\n- {}: {}", name, reason));
+                    }
+                }
+                divergences.report(&short_id,
+                                   COMPARE_BUILDS,
+                                   message,
+                                   &[("normal build", &normal_build_result.raw_output),
+                                     ("incremental build", &incr_build_result.raw_output)]);
+                ((), "DIVERGED")
             } else {
                 ((), "OK")
             }
         });
 
+        // With `--skip-normal`, the incremental build alone decides.
+        let normal_build_failed = normal_build_result.as_ref().map_or(true, |r| !r.success);
+        if normal_build_failed && !incr_build_result.success {
+            let exit_reason = normal_build_result.as_ref()
+                .and_then(|r| r.exit_reason.clone())
+                .or(incr_build_result.exit_reason.clone());
+            broken_commits.push((short_id.clone(), summary.clone(), exit_reason));
+        }
+
+        if !incr_build_result.success {
+            let normal_build_succeeded = normal_build_result.as_ref().map(|r| r.success);
+            incr_build_failures.push((short_id.clone(), summary.clone(), normal_build_succeeded));
+            if args.flag_fail_on_incr_build_failure && normal_build_succeeded != Some(false) {
+                if !save_build_output {
+                    // With `--cli-log`, the output has already been shown.
+                    error!("the incremental build of {} failed", short_id)
+                }
+                error!("the incremental build of {} failed; its output is in `{}`",
+                       short_id,
+                       commits_dir.join(format!("{:04}-{}-incr-build", index, short_id))
+                           .display())
+            }
+        }
+
+        // COMPARE FINAL BINARIES ----------------------------------------------
+        sub_task_runner.run(COMPARE_BINARIES, || {
+            if !args.flag_compare_binaries ||
+               args.flag_doc ||
+               args.flag_reference == ReferenceBuild::Check ||
+               normal_build_failed ||
+               !incr_build_result.success {
+                return ((), "skipped");
+            }
+
+            let strip_dir = if args.flag_strip_before_compare {
+                Some(stripped_dir.as_path())
+            } else {
+                None
+            };
+
+            let target = cargo_options.target.as_ref().map(|target| &target[..]);
+            match compare_build_artifacts(&target_normal_dir,
+                                          &target_incr_dir,
+                                          target,
+                                          strip_dir) {
+                Ok(()) => ((), "OK"),
+                Err(err) => {
+                    divergences.report(&short_id, COMPARE_BINARIES, err, &[]);
+                    ((), "DIVERGED")
+                }
+            }
+        });
+
         // NORMAL TESTING ------------------------------------------------------
         let normal_test = sub_task_runner.run(NORMAL_TEST, || {
-            if args.flag_skip_tests {
+            if args.flag_skip_tests || args.flag_skip_normal {
                 return (None, "skipped");
             }
 
             let commit_dir = commits_dir.join(format!("{:04}-{}-normal-test", index, short_id));
             util::make_dir(&commit_dir);
-            (Some(cargo_test(&cargo_dir,
-                             &commit_dir,
-                             &target_normal_dir,
-                             IncrementalOptions::None)),
-             "OK")
+            let result = cargo_test(&cargo_dir,
+                                    &commit_dir,
+                                    &target_normal_dir,
+                                    IncrementalOptions::None,
+                                    args.flag_verbose,
+                                    &cargo_options,
+                                    args.flag_runner);
+            util::save_repro_script(&commit_dir, commit, &result.command);
+            (Some(result), "OK")
         });
 
 
@@ -253,34 +891,34 @@ pub fn replay(args: &Args) {
 
             let commit_dir = commits_dir.join(format!("{:04}-{}-incr-test", index, short_id));
             util::make_dir(&commit_dir);
-            (Some(cargo_test(&cargo_dir,
-                             &commit_dir,
-                             &target_incr_dir,
-                             incr_options)),
-             "OK")
+            let result = cargo_test(&cargo_dir,
+                                    &commit_dir,
+                                    &target_incr_dir,
+                                    incr_options,
+                                    args.flag_verbose,
+                                    &cargo_options,
+                                    args.flag_runner);
+            util::save_repro_script(&commit_dir, commit, &result.command);
+            (Some(result), "OK")
         });
 
 
         // COMPARE TEST RESULTS ------------------------------------------------
         sub_task_runner.run(COMPARE_TESTS, || {
-            if args.flag_skip_tests {
-                return ((), "skipped");
-            }
-
-            let normal_test = normal_test.clone().unwrap();
-            let incr_test = incr_test.unwrap();
-
-            if normal_test != incr_test {
-                println!("OUTPUT OF NORMAL TESTS:\n");
-                util::print_output(&normal_test.raw_output);
-
-                println!("\nOUTPUT OF INCREMENTAL TESTS:\n");
-                util::print_output(&incr_test.raw_output);
-
-                error!("incremental tests differed from normal tests")
-            } else {
-                ((), "OK")
-            }
+            let (normal_test, incr_test) = match (normal_test.as_ref(), incr_test.as_ref()) {
+                (Some(normal_test), Some(incr_test)) => (normal_test, incr_test),
+                _ => return ((), "skipped"),
+            };
+
+            let status = compare_test_results(normal_test,
+                                              "normal",
+                                              incr_test,
+                                              "incremental",
+                                              COMPARE_TESTS,
+                                              &short_id,
+                                              &mut divergences,
+                                              args);
+            ((), status)
         });
 
 
@@ -293,7 +931,9 @@ pub fn replay(args: &Args) {
                 // We run `cargo clean` so we don't get re-use at the Cargo level.
                 util::cargo_clean(&cargo_dir,
                                   &target_incr_dir,
-                                  args.flag_just_current);
+                                  args.flag_just_current,
+                                  args.flag_verbose,
+                                  &cargo_options);
 
                 let mut full_reuse_stats = CompilationStats::default();
                 assert_eq!(full_reuse_stats.modules_reused, 0);
@@ -304,8 +944,15 @@ pub fn replay(args: &Args) {
                                                    &target_incr_dir,
                                                    incr_options,
                                                    &mut full_reuse_stats,
-                                                   !args.flag_cli_log,
-                                                   args.flag_verbose);
+                                                   save_build_output,
+                                                   args.flag_verbose,
+                                                   args.flag_verbose,
+                                                   &cargo_options);
+                util::save_repro_script(&commit_dir, commit, &result_no_change.command);
+                let diverged = check_ich_verification(INCREMENTAL_BUILD_NO_CHANGE,
+                                                      &result_no_change,
+                                                      &short_id,
+                                                      &mut divergences);
                 if result_no_change.success {
                     if full_reuse_stats.modules_reused != full_reuse_stats.modules_total {
                         error!("only {} modules out of {} re-used in full re-use test",
@@ -317,16 +964,81 @@ pub fn replay(args: &Args) {
                     error!("error during (no change) build!");
                 }
 
-                ((), "OK")
+                if diverged {
+                    ((), "DIVERGED")
+                } else {
+                    ((), "OK")
+                }
             } else {
                 ((), "skipped")
             }
         });
 
 
+        // INCREMENTAL BUILD (DETERMINISM) ------------------------------------
+        // Build again, starting from the same cache as the incremental build,
+        // which should produce the same object files. The second build has to
+        // use the same cache and target directories as the first: their paths
+        // end up in RUSTFLAGS and in the artifacts.
+        sub_task_runner.run(INCREMENTAL_BUILD_DETERMINISM, || {
+            if !args.flag_incr_determinism_check || !incr_build_result.success {
+                return ((), "skipped");
+            }
+
+            let commit_dir =
+                commits_dir.join(format!("{:04}-{}-incr-build-determinism", index, short_id));
+            util::make_dir(&commit_dir);
+            util::remove_dir(&incr_determinism_first);
+            util::rename_directory(&incr_comp_workspace, &incr_determinism_first);
+            util::rename_directory(&incr_determinism_before, &incr_comp_workspace);
+
+            util::cargo_clean(&cargo_dir,
+                              &target_incr_dir,
+                              args.flag_just_current,
+                              args.flag_verbose,
+                              &cargo_options);
+            let second_result = cargo_build(&cargo_dir,
+                                            &commit_dir,
+                                            &target_incr_dir,
+                                            incr_options,
+                                            &mut CompilationStats::default(),
+                                            save_build_output,
+                                            args.flag_verbose,
+                                            args.flag_verbose,
+                                            &cargo_options);
+            util::save_repro_script(&commit_dir, commit, &second_result.command);
+
+            let comparison = if second_result != incr_build_result {
+                Err("the second incremental build's output differed from the first's"
+                    .to_string())
+            } else {
+                compare_incr_comp_dirs(&incr_determinism_first,
+                                       &incr_comp_workspace,
+                                       args.flag_compare_metadata,
+                                       only_crate,
+                                       None)
+            };
+
+            // Carry on with the cache of the first build.
+            util::remove_dir(&incr_comp_workspace);
+            util::rename_directory(&incr_determinism_first, &incr_comp_workspace);
+
+            match comparison {
+                Ok(()) => ((), "OK"),
+                Err(err) => {
+                    divergences.report(&short_id,
+                                       INCREMENTAL_BUILD_DETERMINISM,
+                                       format!("incremental build is not deterministic: {}", err),
+                                       &[("first build", &incr_build_result.raw_output),
+                                         ("second build", &second_result.raw_output)]);
+                    ((), "DIVERGED")
+                }
+            }
+        });
+
         // INCREMENTAL BUILD (FROM SCRATCH) ------------------------------------
-        sub_task_runner.run(INCREMENTAL_BUILD_NO_CACHE, || {
-            if incr_build_result.success {
+        sub_task_runner.run_with_progress(INCREMENTAL_BUILD_NO_CACHE, |progress| {
+            if incr_build_result.success && !args.flag_no_from_scratch {
                 let commit_dir = commits_dir.join(format!("{:04}-{}-incr-build-from-scratch", index, short_id));
                 util::make_dir(&commit_dir);
                 // We want to do a clean rebuild in incremental mode, so clear the
@@ -337,30 +1049,81 @@ pub fn replay(args: &Args) {
                 // Now create an empty workspace directory again
                 util::make_dir(&incr_comp_workspace);
 
+                if args.flag_compare_three_way {
+                    let target = cargo_options.target.as_ref().map(|target| &target[..]);
+                    snapshot_build_artifacts(&target_incr_dir, target, &incr_artifacts_dir);
+                }
+
+                // With `--fast-from-scratch` we keep the dependencies that the
+                // incremental build just produced, so that only the current
+                // project is rebuilt (and compared). We can't borrow them from
+                // the normal build instead: they were built with different
+                // RUSTFLAGS, so cargo would rebuild them anyway.
                 util::cargo_clean(&cargo_dir,
                                   &target_incr_dir,
-                                  args.flag_just_current);
-
-                let from_scratch_result = cargo_build(&cargo_dir,
-                                                      &commit_dir,
-                                                      &target_incr_dir,
-                                                      incr_options,
-                                                      &mut stats_incr_from_scratch,
-                                                      !args.flag_cli_log,
-                                                      args.flag_verbose);
+                                  args.flag_just_current || args.flag_fast_from_scratch,
+                                  args.flag_verbose,
+                                  &cargo_options);
+
+                let from_scratch_result = progress.show_elapsed_time(|| {
+                    cargo_build(&cargo_dir,
+                                &commit_dir,
+                                &target_incr_dir,
+                                incr_options,
+                                &mut stats_incr_from_scratch,
+                                save_build_output,
+                                args.flag_verbose,
+                                args.flag_verbose,
+                                &cargo_options)
+                });
+                util::save_repro_script(&commit_dir, commit, &from_scratch_result.command);
+                let diverged = check_ich_verification(INCREMENTAL_BUILD_NO_CACHE,
+                                                      &from_scratch_result,
+                                                      &short_id,
+                                                      &mut divergences);
                 if !from_scratch_result.success {
                     util::print_output(&from_scratch_result.raw_output);
                     error!("error during (incr-from-scratch) build!");
                 }
 
+                // rustdoc leaves few (if any) artifacts in the cache for us
+                // to compare below, so also compare what it told the user.
+                if args.flag_doc && from_scratch_result != incr_build_result {
+                    divergences.report(&short_id,
+                                       INCREMENTAL_BUILD_NO_CACHE,
+                                       "from-scratch doc build differed from incremental doc build"
+                                           .to_string(),
+                                       &[("incremental build", &incr_build_result.raw_output),
+                                         ("from-scratch build", &from_scratch_result.raw_output)]);
+                    return ((), "DIVERGED");
+                }
+
                 // CHECK THAT REGULAR AND FROM-SCRATCH INCREMENTAL COMPILATION YIELD THE
                 // SAME RESULTS
-                match compare_incr_comp_dirs(&incr_comp_workspace, &incr_evacuated) {
+                let mut show_crate = |crate_index, crate_count| {
+                    progress.set_detail(&format!("comparing crate {} of {}",
+                                                 crate_index + 1,
+                                                 crate_count));
+                };
+                match compare_incr_comp_dirs(&incr_comp_workspace,
+                                             &incr_evacuated,
+                                             args.flag_compare_metadata,
+                                             only_crate,
+                                             Some(&mut show_crate)) {
+                    Ok(()) if diverged => ((), "DIVERGED"),
                     Ok(()) => ((), "OK"),
-                    Err(err) => {
-                        error!("{}\nTo reproduce execute: {}",
-                               err,
-                               args.to_cli_command())
+                    Err(mut err) => {
+                        if args.flag_dump_depgraph {
+                            let incr_commit_dir =
+                                commits_dir.join(format!("{:04}-{}-incr-build", index, short_id));
+                            let dot_file = format!("{}.dot", util::DEP_GRAPH_FILE_STEM);
+                            err.push_str(&format!("\nCompare the dep-graphs in `{}` and `{}`.",
+                                                  incr_commit_dir.join(&dot_file).display(),
+                                                  commit_dir.join(&dot_file).display()));
+                        }
+
+                        divergences.report(&short_id, INCREMENTAL_BUILD_NO_CACHE, err, &[]);
+                        ((), "DIVERGED")
                     }
                 }
             } else {
@@ -368,120 +1131,1462 @@ pub fn replay(args: &Args) {
             }
         });
 
+        // COMPARE ALL FINAL BINARIES -------------------------------------------
+        // Unlike the comparisons above, this tells which of the three builds
+        // is the odd one out.
+        sub_task_runner.run(COMPARE_THREE_WAY, || {
+            if !args.flag_compare_three_way || normal_build_failed || !incr_build_result.success {
+                return ((), "skipped");
+            }
+
+            let strip_dir = if args.flag_strip_before_compare {
+                Some(stripped_dir.as_path())
+            } else {
+                None
+            };
+
+            let target = cargo_options.target.as_ref().map(|target| &target[..]);
+            let pairs = [("normal", target_normal_dir.as_path(),
+                          "incremental", incr_artifacts_dir.as_path()),
+                         ("normal", target_normal_dir.as_path(),
+                          "from-scratch incremental", target_incr_dir.as_path()),
+                         ("incremental", incr_artifacts_dir.as_path(),
+                          "from-scratch incremental", target_incr_dir.as_path())];
+            let mut differences = vec![];
+            for &(reference_name, reference_dir, tested_name, tested_dir) in &pairs {
+                if let Err(err) = compare_build_artifacts(reference_dir,
+                                                          tested_dir,
+                                                          target,
+                                                          strip_dir) {
+                    differences.push(format!("- {} and {} build: {}",
+                                             reference_name,
+                                             tested_name,
+                                             err));
+                }
+            }
+
+            if differences.is_empty() {
+                ((), "OK")
+            } else {
+                divergences.report(&short_id,
+                                   COMPARE_THREE_WAY,
+                                   format!("final binaries differ between the\n{}",
+                                           differences.join("\n")),
+                                   &[]);
+                ((), "DIVERGED")
+            }
+        });
+
+        // INCREMENTAL TESTING (FROM SCRATCH) ----------------------------------
+        // The from-scratch build above has left its artifacts in
+        // `target_incr_dir`, so these tests run against them.
+        sub_task_runner.run(INCREMENTAL_TEST_NO_CACHE, || {
+            if args.flag_skip_tests || args.flag_no_from_scratch || !incr_build_result.success {
+                return ((), "skipped");
+            }
+
+            let commit_dir = commits_dir.join(format!("{:04}-{}-incr-test-from-scratch", index, short_id));
+            util::make_dir(&commit_dir);
+            let result = cargo_test(&cargo_dir,
+                                    &commit_dir,
+                                    &target_incr_dir,
+                                    incr_options,
+                                    args.flag_verbose,
+                                    &cargo_options,
+                                    args.flag_runner);
+            util::save_repro_script(&commit_dir, commit, &result.command);
+
+            let status = compare_test_results(incr_test.as_ref().unwrap(),
+                                              "incremental",
+                                              &result,
+                                              "from-scratch incremental",
+                                              INCREMENTAL_TEST_NO_CACHE,
+                                              &short_id,
+                                              &mut divergences,
+                                              args);
+            ((), status)
+        });
+
         // UPDATE STATISTICS
-        let test_results = normal_test.map(|x| x.results).unwrap_or(vec![]);
+        // With `--skip-normal`, the incremental test results are all we have.
+        let test_results = normal_test.or(incr_test).map(|x| x.results).unwrap_or(vec![]);
+        if !args.flag_junit.is_empty() && !args.flag_skip_tests {
+            divergences.so_far.junit_suites.push(junit::TestSuite {
+                name: format!("{} {}", short_id, summary),
+                results: test_results.clone(),
+            });
+        }
         tests_passed += test_results.iter().filter(|t| t.status == "ok").count();
         tests_total += test_results.len();
 
-        if args.flag_no_debuginfo {
-            // If we injected `debug = false` into the Cargo.toml, we better
-            // reset the repo so it is clean for the next iteration.
+        divergences.so_far.report.commits.push(CommitReport {
+            id: short_id.clone(),
+            oid: commit.id().to_string(),
+            author: util::commit_author(commit),
+            date: util::commit_date(commit),
+            summary: util::commit_summary(commit, usize::max_value()),
+            normal_build_times: normal_samples,
+            incremental_build_times: incr_samples,
+        });
+
+        divergences.so_far.commit_statuses.push(CommitStatus {
+            id: short_id.clone(),
+            summary: summary.clone(),
+            build: if normal_build_result.as_ref().map_or(true, |r| r.success) &&
+                      incr_build_result.success {
+                "OK"
+            } else {
+                "FAIL"
+            },
+            tests_passed: test_results.iter().filter(|t| t.status == "ok").count(),
+            tests_total: test_results.len(),
+            modules_reused: stats_incr.modules_reused - modules_before.0,
+            modules_total: stats_incr.modules_total - modules_before.1,
+            rename_only: rename_only,
+        });
+        divergences.so_far.update_totals(&stats_normal, &stats_incr, tests_total, tests_passed);
+
+        if args.flag_track_cache_size {
+            cache_sizes.push((short_id.clone(), util::dir_size(&incr_comp_workspace)));
+        }
+
+        if args.flag_no_debuginfo || args.flag_update_before_build ||
+           args.flag_update_each_commit {
+            // If we injected `debug = false` into the Cargo.toml, or changed
+            // the Cargo.lock, we better reset the repo so it is clean for the
+            // next iteration.
             util::reset_repo(repo, commit);
+            util::forget_modified_files();
+        }
+
+        // cargo notices changed files by their timestamps, which some file
+        // systems only keep to the second or so. If we checked out the next
+        // commit too soon, its files might look no newer than the last build.
+        while check_out_time.elapsed().as_secs() < args.flag_min_iteration_time {
+            thread::sleep(time::Duration::from_millis(100));
         }
+
+        sub_task_runner.print_commit_summary();
+        save_progress(&progress_path, &commits, index + 1);
     }
 
-    if !args.flag_cli_log {
+    if !args.flag_cli_log && !args.flag_quiet {
         bar.reach_percent(100);
     }
 
-    assert!(stats_normal.modules_reused == 0, "normal build reused modules");
-    println!("");
-    println!("Fuzzing report:");
-    println!("- {} commits built", commits.len());
-    println!("- normal compilation took {:.2}s", stats_normal.build_time);
-    println!("- incremental compilation took {:.2}s", stats_incr.build_time);
-    println!("- {} total tests executed ({} of those passed)",
-             tests_total,
-             tests_passed);
-    println!("- normal/incremental ratio {:.2}",
-             stats_normal.build_time / stats_incr.build_time);
-    println!("- {} of {} (or {:.0}%) modules were re-used",
-             stats_incr.modules_reused,
-             stats_incr.modules_total,
-             (stats_incr.modules_reused as f64 / stats_incr.modules_total as f64) * 100.0);
+    // (This holds trivially with `--skip-normal`.)
+    assert!(stats_normal.modules_reused == 0, "normal build reused modules");
+    let divergence_reports = divergences.to_reports();
+    let divergence_diffs = divergences.output_diffs();
+    let so_far = mem::replace(&mut divergences.so_far, ReportsSoFar::default());
+    let report = FinalReport {
+        report: ReplayReport {
+            divergences: divergence_reports,
+            .. so_far.report
+        },
+        resumed_count: resumed_count,
+        skipped_commits: skipped_commits,
+        broken_commits: broken_commits,
+        incr_build_failures: incr_build_failures,
+        cache_sizes: cache_sizes,
+        commit_statuses: so_far.commit_statuses,
+        commits_dir: commits_dir.clone(),
+    };
+    report.print(args);
+    write_report_files(args,
+                       &report.report,
+                       &report.commit_statuses,
+                       &so_far.junit_suites,
+                       &divergence_diffs);
+
+    if on_tmpfs {
+        if !divergences.divergences.is_empty() {
+            if !args.flag_quiet {
+                println!("leaving the tmpfs mounted on `{}` so that the divergences can be \
+                          inspected",
+                         work_dir.display());
+            }
+        } else if let Err(err) = util::unmount(work_dir) {
+            println!("warning: {}", err);
+        }
+    }
+}
+
+// Writes `--junit`, `--report-json` and `--html-report`, for those that were
+// asked for.
+fn write_report_files(args: &Args,
+                      report: &ReplayReport,
+                      commit_statuses: &[CommitStatus],
+                      junit_suites: &[junit::TestSuite],
+                      divergence_diffs: &[String]) {
+    if !args.flag_junit.is_empty() {
+        junit::write_report(Path::new(&args.flag_junit), junit_suites);
+    }
+
+    if !args.flag_report_json.is_empty() {
+        report.write(Path::new(&args.flag_report_json));
+    }
+
+    if !args.flag_html_report.is_empty() {
+        html::write_report(Path::new(&args.flag_html_report),
+                           report,
+                           commit_statuses,
+                           divergence_diffs);
+    }
+}
+
+// What the report at the end of a replay is made of. `--format json` and
+// `--format csv` only print the `ReplayReport`; the human-readable format
+// also mentions the details of this particular run.
+struct FinalReport {
+    report: ReplayReport,
+    resumed_count: usize,
+    skipped_commits: Vec<(String, String, String)>,
+    broken_commits: Vec<(String, String, Option<String>)>,
+    incr_build_failures: Vec<(String, String, Option<bool>)>,
+    cache_sizes: Vec<(String, u64)>,
+    commit_statuses: Vec<CommitStatus>,
+    commits_dir: PathBuf,
+}
+
+
+impl FinalReport {
+    fn print(&self, args: &Args) {
+        match args.flag_format {
+            ReportFormat::Human => self.print_human(args),
+            ReportFormat::Json => println!("{}", self.report.to_json().pretty()),
+            ReportFormat::Csv => print!("{}", self.report.to_csv()),
+        }
+    }
+
+    fn print_human(&self, args: &Args) {
+        let report = &self.report;
+        println!("");
+        println!("Fuzzing report:");
+        println!("- compiler: {}", report.rustc_version.replace("\n", "\n  "));
+        println!("- cargo: {}", report.cargo_version);
+        println!("- {} commits built", report.commits.len());
+        if self.resumed_count > 0 {
+            println!("- {} commits were skipped, since an earlier replay completed them \
+                      (they are not included in this report)",
+                     self.resumed_count);
+        }
+        if !args.flag_skip_normal {
+            println!("- normal compilation took {:.2}s", report.normal_build_time);
+        }
+        println!("- incremental compilation took {:.2}s", report.incremental_build_time);
+        println!("- {} total tests executed ({} of those passed)",
+                 report.tests_total,
+                 report.tests_passed);
+        if args.flag_skip_normal {
+            println!("- no normal builds were done (`--skip-normal`); incremental builds were \
+                      only compared with from-scratch incremental builds");
+        } else {
+            println!("- normal/incremental ratio {:.2}",
+                     report.normal_build_time / report.incremental_build_time);
+        }
+        if args.flag_reference == ReferenceBuild::Check {
+            println!("- the normal builds only ran `cargo check` (`--reference check`), so the \
+                      ratio says little");
+        }
+        if report.modules_total == 0 {
+            println!("- rustc did not report any module re-use");
+        } else {
+            println!("- {} of {} (or {:.0}%) modules were re-used",
+                     report.modules_reused,
+                     report.modules_total,
+                     report.reuse_percent());
+        }
+        if report.queries_total > 0 {
+            println!("- {} of {} (or {:.0}%) query results were loaded from the cache",
+                     report.queries_reused,
+                     report.queries_total,
+                     report.queries_reused as f64 / report.queries_total as f64 * 100.0);
+        }
+        self.print_rename_reuse();
+        if args.flag_samples > 1 {
+            let normal: Vec<f64> = report.commits
+                .iter()
+                .flat_map(|commit| commit.normal_build_times.iter().cloned())
+                .collect();
+            let incr: Vec<f64> = report.commits
+                .iter()
+                .filter_map(|commit| commit.incremental_build_times.first().cloned())
+                .collect();
+            let no_change: Vec<f64> = report.commits
+                .iter()
+                .flat_map(|commit| commit.incremental_build_times.iter().skip(1).cloned())
+                .collect();
+            println!("- build times over {} samples per commit:", args.flag_samples);
+            for &(label, ref samples) in &[("normal build", normal),
+                                           ("incremental build", incr),
+                                           ("no-change incremental rebuild", no_change)] {
+                if samples.is_empty() {
+                    // No normal builds with `--skip-normal`.
+                    continue;
+                }
+                let (mean, std_dev) = util::mean_and_std_dev(samples);
+                println!("  - {}: mean {:.2}s, standard deviation {:.2}s", label, mean, std_dev);
+            }
+        }
+        if let Some(&(_, final_size)) = self.cache_sizes.last() {
+            // We've checked that there is at least one.
+            let &(ref min_commit, min_size) =
+                self.cache_sizes.iter().min_by_key(|&&(_, size)| size).unwrap();
+            let &(ref max_commit, max_size) =
+                self.cache_sizes.iter().max_by_key(|&&(_, size)| size).unwrap();
+            println!("- incremental cache size: min {} (after {}), max {} (after {}), final {}",
+                     util::bytes_to_string(min_size),
+                     min_commit,
+                     util::bytes_to_string(max_size),
+                     max_commit,
+                     util::bytes_to_string(final_size));
+        }
+        if args.flag_self_profile {
+            println!("- rustc's self-profiles of the incremental builds are in the \
+                      `self-profile` directories below `{}`",
+                     self.commits_dir.display());
+        }
+        if args.flag_update_each_commit {
+            println!("- dependencies were updated with `cargo update` for each commit, \
+                      not built as locked in the committed Cargo.lock");
+        } else if args.flag_update_before_build {
+            println!("- dependencies were updated with `cargo update` before the first commit, \
+                      not built as locked in the committed Cargo.lock");
+        }
+        if !self.skipped_commits.is_empty() {
+            println!("- {} commits could not be checked out and were skipped:",
+                     self.skipped_commits.len());
+            for &(ref commit_id, ref summary, ref err) in &self.skipped_commits {
+                println!("  - {} \"{}\": {}", commit_id, summary, err);
+            }
+        }
+        if !self.broken_commits.is_empty() {
+            println!("- {} commits did not build (normally or incrementally):",
+                     self.broken_commits.len());
+            for &(ref commit_id, ref summary, ref exit_reason) in &self.broken_commits {
+                match *exit_reason {
+                    Some(ref reason) => println!("  - {} \"{}\" ({})", commit_id, summary, reason),
+                    None => println!("  - {} \"{}\"", commit_id, summary),
+                }
+            }
+        }
+        if !self.incr_build_failures.is_empty() {
+            println!("- {} commits failed to build incrementally:",
+                     self.incr_build_failures.len());
+            for &(ref commit_id, ref summary, normal_build_succeeded) in
+                &self.incr_build_failures {
+                match normal_build_succeeded {
+                    Some(true) => {
+                        println!("  - {} \"{}\" (the normal build succeeded)", commit_id, summary)
+                    }
+                    Some(false) => {
+                        println!("  - {} \"{}\" (the normal build failed too)", commit_id, summary)
+                    }
+                    None => println!("  - {} \"{}\"", commit_id, summary),
+                }
+            }
+        }
+        if !report.divergences.is_empty() {
+            println!("- {} divergences found:", report.divergences.len());
+            for divergence in &report.divergences {
+                println!("  - {} ({}): {}",
+                         divergence.commit_id,
+                         divergence.stage,
+                         divergence.message.lines().next().unwrap_or(""));
+            }
+        }
+        if args.flag_per_commit_table {
+            self.print_commit_table();
+        }
+    }
+
+    // A rename that invalidates much of the cache would be lost in the overall
+    // re-use, so we show the commits that only renamed files on their own.
+    fn print_rename_reuse(&self) {
+        let (renames, others): (Vec<&CommitStatus>, Vec<&CommitStatus>) =
+            self.commit_statuses
+                .iter()
+                .filter(|status| status.build != "SKIPPED")
+                .partition(|status| status.rename_only);
+        if renames.is_empty() {
+            return;
+        }
+
+        let reuse = |statuses: &[&CommitStatus]| {
+            let reused: u64 = statuses.iter().map(|status| status.modules_reused).sum();
+            let total: u64 = statuses.iter().map(|status| status.modules_total).sum();
+            if total == 0 {
+                "no module re-use reported".to_string()
+            } else {
+                format!("{} of {} (or {:.0}%) modules re-used",
+                        reused,
+                        total,
+                        reused as f64 / total as f64 * 100.0)
+            }
+        };
+        println!("- {} commits only renamed files: {}; the other {} commits: {}",
+                 renames.len(),
+                 reuse(&renames),
+                 others.len(),
+                 reuse(&others));
+    }
+
+    fn print_commit_table(&self) {
+        let id_width = self.commit_statuses
+            .iter()
+            .map(|status| status.id.len())
+            .fold("commit".len(), cmp::max);
+        println!("- results per commit:");
+        println!("  {:<width$}  {:<7}  {:>9}  {:>5}", "commit", "build", "tests", "reuse",
+                 width = id_width);
+        for status in &self.commit_statuses {
+            let reuse = if status.modules_total == 0 {
+                "-".to_string()
+            } else {
+                format!("{:.0}%",
+                        status.modules_reused as f64 / status.modules_total as f64 * 100.0)
+            };
+            println!("  {:<width$}  {:<7}  {:>9}  {:>5}",
+                     status.id,
+                     status.build,
+                     format!("{}/{}", status.tests_passed, status.tests_total),
+                     reuse,
+                     width = id_width);
+        }
+    }
+}
+
+// With `-Z incremental-verify-ich`, which we always pass, rustc checks the
+// fingerprints it loads from the cache against freshly computed ones. Any
+// mismatch means the incremental build diverged, so with `--verify-ich` we
+// report it right away rather than waiting for later stages.
+// Returns true if a divergence was reported.
+fn check_ich_verification(stage: &'static str,
+                          result: &BuildResult,
+                          commit_id: &str,
+                          divergences: &mut DivergenceLog)
+                          -> bool {
+    if divergences.args.flag_verify_ich && !result.unstable_fingerprints.is_empty() {
+        if divergences.is_fatal() {
+            util::print_output(&result.raw_output);
+        }
+
+        divergences.report(commit_id,
+                           stage,
+                           format!("`-Z incremental-verify-ich` found unstable fingerprints:\n{}",
+                                   result.unstable_fingerprints.join("\n")),
+                           &[("build", &result.raw_output)]);
+        true
+    } else {
+        false
+    }
+}
+
+// Puts the repository back at the commit (and branch, if any) that HEAD
+// pointed to before the replay.
+fn restore_original_head(repo: &Repository, commit: &Commit, branch: Option<&String>) {
+    // A hard reset also reverts any changes we made to Cargo.toml and
+    // Cargo.lock. Putting back their contents from the replayed commit on
+    // top, as `error!` would, would only leave the checkout dirty.
+    util::reset_repo(repo, commit);
+    util::forget_modified_files();
+    if let Some(branch) = branch {
+        if let Err(err) = repo.set_head(branch) {
+            error!("failed to restore HEAD to `{}`: {}", branch, err)
+        }
+    }
+}
+
+struct Divergence {
+    commit_id: String,
+    stage: &'static str,
+    message: String,
+    // The output of each side of the comparison, for `--divergence-report`
+    // and `--html-report`.
+    outputs: Vec<(String, String)>,
+}
+
+// Every place where the incremental results disagree with the reference
+// results goes through here, so that `--on-divergence` can decide whether
+// to abort (the default) or to record the divergence and keep going.
+struct DivergenceLog<'a> {
+    args: &'a Args,
+    divergences: Vec<Divergence>,
+    // Where the outputs of each commit are saved, and the other places that
+    // `--until-divergence` tells the user about when it stops.
+    commits_dir: PathBuf,
+    kept_paths: Vec<(&'static str, PathBuf)>,
+    so_far: ReportsSoFar,
+}
+
+// What `--junit`, `--report-json` and `--html-report` are made of, for the
+// commits replayed so far. The `DivergenceLog` keeps it, so that it can still
+// write those files when it stops the replay at a divergence.
+#[derive(Default)]
+struct ReportsSoFar {
+    report: ReplayReport,
+    // For `--per-commit-table`, and to report the re-use in commits that
+    // only rename files separately.
+    commit_statuses: Vec<CommitStatus>,
+    // The normal test results of each commit, for `--junit`.
+    junit_suites: Vec<junit::TestSuite>,
+    // The short id and summary of the commit being replayed.
+    current_commit: (String, String),
+}
+
+impl ReportsSoFar {
+    fn update_totals(&mut self,
+                     stats_normal: &CompilationStats,
+                     stats_incr: &CompilationStats,
+                     tests_total: usize,
+                     tests_passed: usize) {
+        self.report.normal_build_time = stats_normal.build_time;
+        self.report.incremental_build_time = stats_incr.build_time;
+        self.report.modules_reused = stats_incr.modules_reused;
+        self.report.modules_total = stats_incr.modules_total;
+        self.report.queries_reused = stats_incr.queries_reused;
+        self.report.queries_total = stats_incr.queries_total;
+        self.report.tests_total = tests_total as u64;
+        self.report.tests_passed = tests_passed as u64;
+    }
+}
+
+impl<'a> DivergenceLog<'a> {
+    fn new(args: &'a Args,
+           commits_dir: &Path,
+           kept_paths: Vec<(&'static str, PathBuf)>,
+           report: ReplayReport)
+           -> DivergenceLog<'a> {
+        DivergenceLog {
+            args: args,
+            divergences: vec![],
+            commits_dir: commits_dir.to_path_buf(),
+            kept_paths: kept_paths,
+            so_far: ReportsSoFar {
+                report: report,
+                .. ReportsSoFar::default()
+            },
+        }
+    }
+
+    fn is_fatal(&self) -> bool {
+        self.args.flag_on_divergence == OnDivergence::Stop
+    }
+
+    // For `--until-divergence`: lists everything we leave behind for
+    // debugging the divergence in `commit_id`.
+    fn print_kept_state(&self, commit_id: &str) {
+        println!("stopped at the first divergence, in commit {}; nothing was cleaned up:",
+                 commit_id);
+        for &(what, ref path) in &self.kept_paths {
+            if path.exists() {
+                println!("- {}: `{}`", what, path.display());
+            }
+        }
+        for path in util::modified_files() {
+            println!("- modified for the build (not restored): `{}`", path.display());
+        }
+
+        // The directories of the commit are named `INDEX-SHORT_ID-STAGE`.
+        let infix = format!("-{}-", commit_id);
+        let mut commit_dirs: Vec<PathBuf> = match fs::read_dir(&self.commits_dir) {
+            Ok(entries) => {
+                entries.filter_map(|entry| entry.ok())
+                    .filter(|entry| entry.file_name().to_string_lossy().contains(&infix))
+                    .map(|entry| entry.path())
+                    .collect()
+            }
+            Err(_) => vec![],
+        };
+        commit_dirs.sort();
+        for dir in commit_dirs {
+            println!("- saved outputs: `{}`", dir.display());
+        }
+    }
+
+    fn report(&mut self,
+              commit_id: &str,
+              stage: &'static str,
+              message: String,
+              outputs: &[(&str, &Output)]) {
+        self.divergences.push(Divergence {
+            commit_id: commit_id.to_string(),
+            stage: stage,
+            message: message,
+            outputs: outputs.iter()
+                .map(|&(side, output)| (side.to_string(), util::output_text(output)))
+                .collect(),
+        });
+
+        // Rewrite the whole file each time, so that it is complete even if
+        // we abort below.
+        if !self.args.flag_divergence_report.is_empty() {
+            self.write_details(Path::new(&self.args.flag_divergence_report));
+        }
+
+        if self.is_fatal() {
+            self.write_report_files_on_stop();
+            if self.args.flag_until_divergence {
+                self.print_kept_state(commit_id);
+                // Otherwise `error!` would undo our changes to the checkout.
+                util::forget_modified_files();
+            }
+            error!(exit_code: self.args.flag_exit_code_on_divergence;
+                   "{}\nTo reproduce execute: {}",
+                   self.divergences.last().unwrap().message,
+                   self.args.to_cli_command())
+        }
+    }
+
+    // Writes the report files for the commits replayed so far, and for the
+    // one we are about to stop at.
+    fn write_report_files_on_stop(&self) {
+        let report = ReplayReport {
+            divergences: self.to_reports(),
+            .. self.so_far.report.clone()
+        };
+        let mut commit_statuses = self.so_far.commit_statuses.clone();
+        commit_statuses.push(CommitStatus {
+            id: self.so_far.current_commit.0.clone(),
+            summary: self.so_far.current_commit.1.clone(),
+            build: "STOPPED",
+            .. CommitStatus::default()
+        });
+        write_report_files(self.args,
+                           &report,
+                           &commit_statuses,
+                           &self.so_far.junit_suites,
+                           &self.output_diffs());
+    }
+
+    // For each divergence, a unified diff between the outputs of its two
+    // sides, or the output itself if there is only one.
+    fn output_diffs(&self) -> Vec<String> {
+        self.divergences
+            .iter()
+            .map(|divergence| {
+                let outputs = &divergence.outputs;
+                if outputs.len() == 2 {
+                    format!("--- {}\n+++ {}\n{}",
+                            outputs[0].0,
+                            outputs[1].0,
+                            util::unified_diff(&outputs[0].1, &outputs[1].1, DIFF_CONTEXT))
+                } else {
+                    outputs.iter().map(|&(_, ref text)| &text[..]).collect::<Vec<_>>().join("\n")
+                }
+            })
+            .collect()
+    }
+
+    // Writes everything we know about the divergences to `path`, as a JSON
+    // array with one object per divergence.
+    fn write_details(&self, path: &Path) {
+        let details: Vec<Json> = self.divergences
+            .iter()
+            .map(|divergence| {
+                let mut outputs = BTreeMap::new();
+                for &(ref side, ref text) in &divergence.outputs {
+                    outputs.insert(side.clone(), text.to_json());
+                }
+
+                let mut object = BTreeMap::new();
+                object.insert("commit_id".to_string(), divergence.commit_id.to_json());
+                object.insert("stage".to_string(), divergence.stage.to_json());
+                object.insert("message".to_string(), divergence.message.to_json());
+                object.insert("outputs".to_string(), Json::Object(outputs));
+                Json::Object(object)
+            })
+            .collect();
+
+        let contents = format!("{}\n", Json::Array(details).pretty());
+        let result = File::create(path).and_then(|mut file| file.write_all(contents.as_bytes()));
+        if let Err(err) = result {
+            error!("failed to write divergence report to `{}`: {}", path.display(), err)
+        }
+    }
+
+    fn to_reports(&self) -> Vec<DivergenceReport> {
+        self.divergences
+            .iter()
+            .map(|divergence| {
+                DivergenceReport {
+                    commit_id: divergence.commit_id.clone(),
+                    stage: divergence.stage.to_string(),
+                    message: divergence.message.clone(),
+                }
+            })
+            .collect()
+    }
+}
+
+// Reads the progress of an earlier replay from `path`. Returns how many of
+// `commits` it completed, or `None` if there is nothing to resume, in which
+// case the replay starts over.
+fn load_progress(path: &Path, commits: &[Commit], quiet: bool) -> Option<usize> {
+    let mut contents = String::new();
+    if File::open(path).and_then(|mut file| file.read_to_string(&mut contents)).is_err() {
+        if !quiet {
+            println!("warning: no `{}` to resume from; starting over", path.display());
+        }
+        return None;
+    }
+
+    let progress = Json::from_str(&contents).ok();
+    let recorded_commits = progress.as_ref()
+        .and_then(|progress| progress.find("commits"))
+        .and_then(Json::as_array)
+        .map(|ids| ids.iter().map(Json::as_string).collect::<Vec<_>>());
+    let completed = progress.as_ref()
+        .and_then(|progress| progress.find("completed"))
+        .and_then(Json::as_u64);
+
+    let commit_ids: Vec<_> = commits.iter().map(|commit| commit.id().to_string()).collect();
+    match (recorded_commits, completed) {
+        (Some(ref recorded_commits), Some(completed))
+            if recorded_commits.len() == commit_ids.len() &&
+               recorded_commits.iter().zip(&commit_ids).all(|(a, b)| *a == Some(&b[..])) &&
+               completed as usize <= commit_ids.len() => Some(completed as usize),
+        _ => {
+            if !quiet {
+                println!("warning: `{}` was written for a different list of commits (did the \
+                          revisions or filters change?); starting over",
+                         path.display());
+            }
+            None
+        }
+    }
+}
+
+// Records that the first `completed` of `commits` have gone through all
+// stages. The file is replaced atomically, so that a crash leaves either
+// the old or the new version.
+fn save_progress(path: &Path, commits: &[Commit], completed: usize) {
+    let commit_ids: Vec<_> = commits.iter().map(|commit| commit.id().to_string()).collect();
+    let mut progress = BTreeMap::new();
+    progress.insert("commits".to_string(), commit_ids.to_json());
+    progress.insert("completed".to_string(), completed.to_json());
+
+    let temp_path = path.with_extension("json.tmp");
+    util::write_file(&temp_path, Json::Object(progress).to_string().as_bytes());
+    if let Err(err) = fs::rename(&temp_path, path) {
+        error!("failed to write `{}`: {}", path.display(), err)
+    }
+}
+
+// Applies `--filter-path`, `--exclude-path` and `--filter-author` to the
+// commits we are about to replay.
+fn filter_commits<'repo>(repo: &'repo Repository,
+                         commits: Vec<Commit<'repo>>,
+                         args: &Args)
+                         -> Vec<Commit<'repo>> {
+    if args.flag_filter_path.is_empty() && args.flag_exclude_path.is_empty() &&
+       args.flag_filter_author.is_empty() {
+        return commits;
+    }
+
+    let total_count = commits.len();
+    let commits: Vec<_> = commits.into_iter()
+        .filter(|commit| {
+            (args.flag_filter_path.is_empty() ||
+             touches_path(repo, commit, &args.flag_filter_path)) &&
+            (args.flag_exclude_path.is_empty() ||
+             touches_path_outside(repo, commit, &args.flag_exclude_path)) &&
+            (args.flag_filter_author.is_empty() ||
+             has_author(commit, &args.flag_filter_author))
+        })
+        .collect();
+
+    if !args.flag_quiet {
+        println!("{} of {} commits remain after filtering", commits.len(), total_count);
+    }
+
+    commits
+}
+
+// Prints what a replay would do, for `--dry-run`. This must not build
+// anything or touch the repository or the work directory.
+fn print_plan(commits: &[Commit], cargo_toml_path: &Path, args: &Args) {
+    let current_dir = match env::current_dir() {
+        Ok(current_dir) => current_dir,
+        Err(err) => error!("failed to read the current directory: {}", err),
+    };
+    let work_dir = current_dir.join(&args.flag_work_dir);
+
+    println!("dry run: nothing will be built");
+    println!("");
+    println!("Cargo.toml: {}", cargo_toml_path.display());
+    println!("work directory (will be deleted): {}", work_dir.display());
+    for dir in &["target-normal", "target-incr", "commits"] {
+        println!("  {}", work_dir.join(dir).display());
+    }
+    let incr_base_dir = if args.flag_incr_dir.is_empty() {
+        work_dir.clone()
+    } else {
+        let incr_dir = current_dir.join(&args.flag_incr_dir);
+        println!("incremental cache directory: {}", incr_dir.display());
+        incr_dir
+    };
+    for dir in &["incr-workspace", "incr-evacuated"] {
+        println!("  {}", incr_base_dir.join(dir).display());
+    }
+    if args.flag_incr_determinism_check {
+        for dir in &["incr-determinism-before", "incr-determinism-first"] {
+            println!("  {}", incr_base_dir.join(dir).display());
+        }
+    }
+    if args.flag_strip_before_compare {
+        println!("  {}", work_dir.join("stripped").display());
+    }
+    if args.flag_compare_three_way {
+        println!("  {}", work_dir.join("incr-artifacts").display());
+    }
+    let mut cargo_options = args.cargo_options();
+    if args.flag_clean_registry {
+        let cargo_home = work_dir.join("cargo-home");
+        println!("  {}", cargo_home.display());
+        cargo_options.cargo_home = Some(cargo_home);
+    }
+    cargo_options.print_summary();
+
+    println!("");
+    println!("stages run for each commit:");
+    for stage in STAGES {
+        match stage_skip_reason(stage, args) {
+            Some(reason) => println!("  {} (skipped: {})", stage, reason),
+            None => println!("  {}", stage),
+        }
+    }
+
+    println!("");
+    println!("{} commits to replay:", commits.len());
+    for (index, commit) in commits.iter().enumerate() {
+        println!("  {:04} {} \"{}\"",
+                 index,
+                 util::short_id(commit),
+                 util::commit_summary(commit, MAX_SUMMARY_CHARS));
+    }
+}
+
+// Why the command-line options cause `stage` to be skipped for every
+// commit, if they do.
+fn stage_skip_reason(stage: &str, args: &Args) -> Option<&'static str> {
+    if stage == COMPARE_THREE_WAY && !args.flag_compare_three_way {
+        Some("needs `--compare-three-way`")
+    } else if stage == COMPARE_BINARIES && !args.flag_compare_binaries {
+        Some("needs `--compare-binaries`")
+    } else if stage == COMPARE_BINARIES && args.flag_doc {
+        Some("`--doc` builds no binaries")
+    } else if stage == COMPARE_BINARIES && args.flag_reference == ReferenceBuild::Check {
+        Some("`--reference check` builds no normal binaries")
+    } else if (stage == NORMAL_BUILD || stage == COMPARE_BUILDS || stage == COMPARE_BINARIES ||
+               stage == NORMAL_TEST || stage == COMPARE_TESTS) && args.flag_skip_normal {
+        Some("`--skip-normal`")
+    } else if (stage == NORMAL_TEST || stage == INCREMENTAL_TEST || stage == COMPARE_TESTS) &&
+              args.flag_skip_tests {
+        Some("`--skip-tests`")
+    } else if stage == INCREMENTAL_BUILD_DETERMINISM && !args.flag_incr_determinism_check {
+        Some("needs `--incr-determinism-check`")
+    } else if stage == INCREMENTAL_BUILD_NO_CHANGE && args.flag_skip_reuse_check {
+        Some("`--skip-reuse-check`")
+    } else if stage == INCREMENTAL_TEST_NO_CACHE && args.flag_skip_tests {
+        Some("`--skip-tests`")
+    } else if (stage == INCREMENTAL_BUILD_NO_CACHE || stage == INCREMENTAL_TEST_NO_CACHE) &&
+              args.flag_no_from_scratch {
+        Some("`--no-from-scratch`")
+    } else {
+        None
+    }
+}
+
+// Runs `cargo update` for `--update-before-build` and `--update-each-commit`.
+// Unless we update for each commit, we only run it for the first commit and
+// then reuse the resulting `Cargo.lock`, so that the dependencies don't change
+// underneath the commits we are testing.
+fn update_lockfile(cargo_dir: &Path,
+                   target_dir: &Path,
+                   updated_lockfile: &mut Option<Vec<u8>>,
+                   cargo_options: &CargoOptions,
+                   args: &Args) {
+    let lockfile_path = cargo_dir.join("Cargo.lock");
+
+    let mut original_contents = vec![];
+    if let Ok(mut file) = File::open(&lockfile_path) {
+        if let Err(err) = file.read_to_end(&mut original_contents) {
+            error!("failed to read `{}`: {}", lockfile_path.display(), err)
+        }
+        util::remember_original_contents(&lockfile_path, &original_contents);
+    }
+
+    if !args.flag_update_each_commit {
+        if let Some(ref contents) = *updated_lockfile {
+            let result = File::create(&lockfile_path)
+                .and_then(|mut file| file.write_all(contents));
+            if let Err(err) = result {
+                error!("failed to write `{}`: {}", lockfile_path.display(), err)
+            }
+            return;
+        }
+    }
+
+    util::cargo_update(cargo_dir, target_dir, args.flag_verbose, cargo_options);
+
+    let mut contents = vec![];
+    let result = File::open(&lockfile_path).and_then(|mut file| file.read_to_end(&mut contents));
+    if let Err(err) = result {
+        error!("failed to read `{}`: {}", lockfile_path.display(), err)
+    }
+    *updated_lockfile = Some(contents);
+}
+
+// Runs an extra build for `--samples` and returns how long it took. These
+// don't count towards the totals in the report, and their output isn't kept.
+fn sample_build_time(cargo_dir: &Path,
+                     commit_dir: &Path,
+                     target_dir: &Path,
+                     incremental: IncrementalOptions,
+                     args: &Args,
+                     cargo_options: &CargoOptions)
+                     -> f64 {
+    let mut stats = CompilationStats::default();
+    cargo_build(cargo_dir,
+                commit_dir,
+                target_dir,
+                incremental,
+                &mut stats,
+                false,
+                args.flag_verbose,
+                args.flag_verbose,
+                cargo_options);
+    stats.build_time
+}
+
+// Runs `build` up to `retries` more times for as long as it fails in a way
+// that looks like a network problem rather than a problem with the code.
+// Also returns whether we had to retry at all.
+fn retry_transient_failures<F>(retries: u32, mut build: F) -> (BuildResult, bool)
+    where F: FnMut() -> BuildResult
+{
+    let mut result = build();
+    let mut attempts = 0;
+    while attempts < retries && util::is_transient_failure(&result) {
+        attempts += 1;
+        debug!("retrying build after transient failure (retry {} of {})", attempts, retries);
+        result = build();
+    }
+    (result, attempts > 0)
+}
+
+// Writes the short id and summary of each commit we would replay, in order,
+// to `path` (or to stdout if `path` is `-`).
+fn dump_order(commits: &[Commit], path: &Path) {
+    let mut order = String::new();
+    for commit in commits {
+        order.push_str(&format!("{} {}\n",
+                                util::short_id(commit),
+                                util::commit_summary(commit, usize::max_value())));
+    }
+
+    if path == Path::new("-") {
+        print!("{}", order);
+        return;
+    }
+
+    let result = File::create(path).and_then(|mut file| file.write_all(order.as_bytes()));
+    if let Err(err) = result {
+        error!("failed to write commit order to `{}`: {}", path.display(), err)
+    }
+}
+
+// The commits for `--print-commit-list-json`, for tools that drive us.
+fn commit_list_json(commits: &[Commit]) -> Json {
+    let list: Vec<Json> = commits.iter()
+        .enumerate()
+        .map(|(index, commit)| {
+            let mut object = BTreeMap::new();
+            object.insert("index".to_string(), index.to_json());
+            object.insert("short_id".to_string(), util::short_id(commit).to_json());
+            object.insert("oid".to_string(), commit.id().to_string().to_json());
+            object.insert("subject".to_string(),
+                          util::commit_summary(commit, usize::max_value()).to_json());
+            Json::Object(object)
+        })
+        .collect();
+    Json::Array(list)
+}
+
+// Applies `--shard`: keeps only every TOTAL-th commit, starting at INDEX.
+fn shard_commits<'repo>(commits: Vec<Commit<'repo>>, args: &Args) -> Vec<Commit<'repo>> {
+    if args.flag_shard.is_empty() {
+        return commits;
+    }
+
+    let (shard_index, shard_count) = match parse_shard(&args.flag_shard) {
+        Ok(shard) => shard,
+        Err(err) => error!("invalid `--shard {}`: {}", args.flag_shard, err),
+    };
+
+    let commits: Vec<_> = commits.into_iter()
+        .enumerate()
+        .filter(|&(position, _)| position % shard_count == shard_index)
+        .map(|(_, commit)| commit)
+        .collect();
+
+    if !args.flag_quiet {
+        let ids: Vec<_> = commits.iter().map(|commit| util::short_id(commit)).collect();
+        println!("shard {} of {} is responsible for {} commits: {}",
+                 shard_index,
+                 shard_count,
+                 commits.len(),
+                 ids.join(" "));
+    }
+
+    commits
+}
+
+// Parses `INDEX/TOTAL`, where INDEX counts from 0.
+fn parse_shard(spec: &str) -> Result<(usize, usize), String> {
+    let mut parts = spec.splitn(2, '/');
+    let index = parts.next().and_then(|index| index.parse::<usize>().ok());
+    let count = parts.next().and_then(|count| count.parse::<usize>().ok());
+    match (index, count) {
+        (Some(index), Some(count)) if index < count => Ok((index, count)),
+        (Some(_), Some(_)) => Err("INDEX must be less than TOTAL".to_string()),
+        _ => Err("expected INDEX/TOTAL, e.g. `0/4`".to_string()),
+    }
+}
+
+// Checks whether `commit` changes any file matching the glob `path`,
+// compared to its first parent.
+fn touches_path(repo: &Repository, commit: &Commit, path: &str) -> bool {
+    let mut diff_options = DiffOptions::new();
+    diff_options.pathspec(path);
+
+    diff_against_parent(repo, commit, &mut diff_options).deltas().len() > 0
+}
+
+// Checks whether `commit` changes any file that does not match the glob
+// `path`, compared to its first parent.
+fn touches_path_outside(repo: &Repository, commit: &Commit, path: &str) -> bool {
+    let excluded = match Pathspec::new(Some(path)) {
+        Ok(pathspec) => pathspec,
+        Err(err) => error!("invalid path `{}`: {}", path, err),
+    };
+
+    let diff = diff_against_parent(repo, commit, &mut DiffOptions::new());
+    let is_outside = |file_path: Option<&Path>| {
+        file_path.map_or(false, |p| !excluded.matches_path(p, git2::PATHSPEC_DEFAULT))
+    };
+    diff.deltas()
+        .any(|delta| is_outside(delta.old_file().path()) || is_outside(delta.new_file().path()))
+}
+
+fn diff_against_parent<'repo>(repo: &'repo Repository,
+                              commit: &Commit,
+                              diff_options: &mut DiffOptions)
+                              -> Diff<'repo> {
+    let tree = match commit.tree() {
+        Ok(tree) => tree,
+        Err(err) => error!("failed to load tree of `{}`: {}", util::short_id(commit), err),
+    };
+
+    let parent_tree = match commit.parent(0) {
+        Ok(parent) => {
+            match parent.tree() {
+                Ok(tree) => Some(tree),
+                Err(err) => {
+                    error!("failed to load tree of `{}`: {}", util::short_id(&parent), err)
+                }
+            }
+        }
+        // A root commit (or the boundary of a shallow clone), so compare
+        // against the empty tree.
+        Err(_) => None,
+    };
+
+    match repo.diff_tree_to_tree(parent_tree.as_ref(), Some(&tree), Some(diff_options)) {
+        Ok(diff) => diff,
+        Err(err) => error!("failed to diff `{}`: {}", util::short_id(commit), err),
+    }
+}
+
+// The changes between the trees of `previous` and `commit`.
+fn diff_commits<'r>(repo: &'r Repository, previous: &Commit, commit: &Commit) -> Diff<'r> {
+    let trees = previous.tree().and_then(|previous_tree| {
+        commit.tree().map(|tree| (previous_tree, tree))
+    });
+    let diff = trees.and_then(|(previous_tree, tree)| {
+        repo.diff_tree_to_tree(Some(&previous_tree), Some(&tree), None)
+    });
+    match diff {
+        Ok(diff) => diff,
+        Err(err) => {
+            error!("failed to diff `{}` against `{}`: {}",
+                   util::short_id(commit),
+                   util::short_id(previous),
+                   err)
+        }
+    }
+}
+
+// Prints which files changed between `previous` and `commit`, with the
+// number of added and removed lines, so that the reuse numbers of `commit`
+// can be put in context.
+fn print_diff_stat(repo: &Repository, previous: &Commit, commit: &Commit) {
+    let diff = diff_commits(repo, previous, commit);
+
+    println!("changes since {}:", util::short_id(previous));
+    for (index, delta) in diff.deltas().enumerate() {
+        let path = delta.new_file().path().or(delta.old_file().path());
+        let path = path.map_or("?".to_string(), |path| path.display().to_string());
+        match Patch::from_diff(&diff, index) {
+            Ok(Some(patch)) => {
+                let (_, additions, deletions) = patch.line_stats().unwrap_or((0, 0, 0));
+                println!("  {} | +{} -{}", path, additions, deletions);
+            }
+            // e.g. a binary file
+            _ => println!("  {}", path),
+        }
+    }
+}
+
+// Whether all that changed since `previous` is that files were renamed or
+// moved, with exactly the same contents.
+fn is_rename_only(repo: &Repository, previous: &Commit, commit: &Commit) -> bool {
+    let mut diff = diff_commits(repo, previous, commit);
+    let mut find_options = DiffFindOptions::new();
+    find_options.renames(true).exact_match_only(true);
+    if let Err(err) = diff.find_similar(Some(&mut find_options)) {
+        error!("failed to find renames between `{}` and `{}`: {}",
+               util::short_id(previous),
+               util::short_id(commit),
+               err)
+    }
+
+    diff.deltas().count() > 0 && diff.deltas().all(|delta| delta.status() == Delta::Renamed)
+}
+
+fn has_author(commit: &Commit, author: &str) -> bool {
+    let signature = commit.author();
+    signature.name().map_or(false, |name| name.contains(author)) ||
+    signature.email().map_or(false, |email| email.contains(author))
+}
+
+fn cargo_test(cargo_dir: &Path,
+              commit_dir: &Path,
+              target_dir: &Path,
+              incremental: IncrementalOptions,
+              verbose: bool,
+              options: &CargoOptions,
+              runner: TestRunner)
+              -> TestResult {
+    let mut cargo_cmd = CargoCommand::new(cargo_dir, target_dir);
+    options.apply(&mut cargo_cmd);
+    match runner {
+        TestRunner::Libtest => {
+            cargo_cmd.arg("test");
+        }
+        TestRunner::Nextest => {
+            // Without `--no-fail-fast`, which tests get cancelled after a
+            // failure depends on timing, which would make the normal and
+            // incremental results differ. We have nextest report every test
+            // as it finishes, and nothing again at the end.
+            cargo_cmd.arg("nextest")
+                .arg("run")
+                .arg("--no-fail-fast")
+                .arg("--status-level")
+                .arg("all")
+                .arg("--final-status-level")
+                .arg("none");
+        }
+    }
+    options.apply_build_args(&mut cargo_cmd);
+
+    // We are setting rustc's incremental flags manually, so let's
+    // make cargo not interfere. And if we have IncrementalOptions::None then
+    // we explicitly don't want to default to incremental compilation.
+    cargo_cmd.env("CARGO_INCREMENTAL", "0");
+
+    match incremental {
+        IncrementalOptions::None => options.apply_rustflags(&mut cargo_cmd, &[]),
+        IncrementalOptions::AllDeps(incr_dir) |
+        IncrementalOptions::CurrentProject(incr_dir) => {
+            options.apply_rustflags(&mut cargo_cmd,
+                                    &[format!("-Z incremental={}", incr_dir.display()),
+                                      "-Z incremental-info".to_string()]);
+        }
+    }
+
+    if verbose {
+        util::print_command(&cargo_cmd);
+    }
+
+    let mut cmd = cargo_cmd.to_command();
+    debug!("{:?}", cmd);
+
+    let output = cmd.output();
+    let output = match output {
+        Ok(output) => {
+            util::save_output(commit_dir, &output, options.strip_ansi);
+            output
+        }
+        Err(err) => error!("failed to execute `cargo build`: {}", err),
+    };
+
+    parse_test_output(output, cargo_cmd, runner)
+}
+
+// Everything `cargo_test` does after running the tests: finds out which tests
+// ran, and with which result, from their `output`.
+fn parse_test_output(output: Output, command: CargoCommand, runner: TestRunner) -> TestResult {
+    let all_bytes: Vec<u8> = output.stdout
+        .iter()
+        .cloned()
+        .chain(output.stderr.iter().cloned())
+        .collect();
+    let all_output = util::into_string(all_bytes);
+
+    let test_results = match runner {
+        TestRunner::Libtest => parse_libtest_results(&all_output, &output),
+        TestRunner::Nextest => parse_nextest_results(&all_output),
+    };
+
+    TestResult {
+        success: output.status.success(),
+        results: test_results,
+        raw_output: output,
+        command: command,
+    }
+}
+
+// Compares the test results of two builds and reports a divergence at `stage`
+// if they differ. The names say which builds the results came from, e.g.
+// "normal" and "incremental". Returns the status of the stage.
+fn compare_test_results(reference: &TestResult,
+                        reference_name: &str,
+                        tested: &TestResult,
+                        tested_name: &str,
+                        stage: &'static str,
+                        commit_id: &str,
+                        divergences: &mut DivergenceLog,
+                        args: &Args)
+                        -> &'static str {
+    if same_test_results(reference, tested, args.flag_ignore_ignored_tests) {
+        return "OK";
+    }
+
+    // With `--compare-only-errors-in-tests`, the raw output is not to be
+    // trusted, so we don't show it and only diff the test results.
+    let only_errors = args.flag_compare_only_errors_in_tests;
+    if divergences.is_fatal() && (args.flag_diff || only_errors) {
+        println!("DIFF OF {} (-) AND {} (+) TEST RESULTS:\n",
+                 reference_name.to_uppercase(),
+                 tested_name.to_uppercase());
+        print_diff(&test_results_text(&reference.results),
+                   &test_results_text(&tested.results),
+                   args);
+    } else if divergences.is_fatal() {
+        println!("OUTPUT OF {} TESTS:\n", reference_name.to_uppercase());
+        util::print_output(&reference.raw_output);
+
+        println!("\nOUTPUT OF {} TESTS:\n", tested_name.to_uppercase());
+        util::print_output(&tested.raw_output);
+    }
+
+    let mut message = format!("{} tests differed from {} tests", tested_name, reference_name);
+    if only_errors {
+        message.push_str(" (comparing only test names and statuses, not test output)");
+    }
+    for (name, reference_result, tested_result) in differing_tests(reference, tested) {
+        message.push_str(&format!("\n- {}: {} in {} build, {} in {} build",
+                                  name,
+                                  reference_result.map_or("missing", |r| &r.status[..]),
+                                  reference_name,
+                                  tested_result.map_or("missing", |r| &r.status[..]),
+                                  tested_name));
+        if only_errors {
+            continue;
+        }
+        for result in reference_result.iter().chain(&tested_result) {
+            if let Some(ref panic_message) = result.panic_message {
+                message.push_str(&format!("\n  {}", panic_message.replace("\n", "\n  ")));
+            }
+        }
+    }
+
+    let outputs: &[(&str, &Output)] = if only_errors {
+        &[]
+    } else {
+        &[(reference_name, &reference.raw_output), (tested_name, &tested.raw_output)]
+    };
+    divergences.report(commit_id, stage, message, outputs);
+    "DIVERGED"
 }
 
-fn cargo_test(cargo_dir: &Path,
-              commit_dir: &Path,
-              target_dir: &Path,
-              incremental: IncrementalOptions)
-              -> TestResult {
-    let mut cmd = Command::new("cargo");
-    cmd.current_dir(&cargo_dir);
-    cmd.env("CARGO_TARGET_DIR", target_dir);
-    cmd.arg("test");
+// The tests whose status differs between two test runs, with their results
+// in each run (`None` if the test didn't run).
+fn differing_tests<'a>(reference: &'a TestResult,
+                       tested: &'a TestResult)
+                       -> Vec<(&'a str, Option<&'a TestCaseResult>, Option<&'a TestCaseResult>)> {
+    let names: BTreeSet<&str> = reference.results
+        .iter()
+        .chain(&tested.results)
+        .map(|result| &result.test_name[..])
+        .collect();
+    let find = |results: &'a [TestCaseResult], name: &str| {
+        results.iter().find(|result| result.test_name == name)
+    };
 
-    // We are setting rustc's incremental flags manually, so let's
-    // make cargo not interfere. And if we have IncrementalOptions::None then
-    // we explicitly don't want to default to incremental compilation.
-    cmd.env("CARGO_INCREMENTAL", "0");
+    names.into_iter()
+        .map(|name| (name, find(&reference.results, name), find(&tested.results, name)))
+        .filter(|&(_, reference_result, tested_result)| reference_result != tested_result)
+        .collect()
+}
 
-    match incremental {
-        IncrementalOptions::None => {}
-        IncrementalOptions::AllDeps(incr_dir) |
-        IncrementalOptions::CurrentProject(incr_dir) => {
-            let rustflags = env::var("RUSTFLAGS").unwrap_or(String::new());
-            cmd.env("RUSTFLAGS",
-                    format!("-Z incremental={} -Z incremental-info {}",
-                            incr_dir.display(),
-                            rustflags));
-        }
+// With `ignore_ignored`, tests that were ignored in either run are left out
+// of the comparison.
+fn same_test_results(reference: &TestResult, tested: &TestResult, ignore_ignored: bool) -> bool {
+    if !ignore_ignored {
+        return reference == tested;
     }
-    debug!("{:?}", cmd);
-
-    let output = cmd.output();
-    let output = match output {
-        Ok(output) => {
-            util::save_output(commit_dir, &output);
-            output
-        }
-        Err(err) => error!("failed to execute `cargo build`: {}", err),
-    };
 
-    // compute set of tests and their results
-    let all_bytes: Vec<u8> = output.stdout
+    let ignored: BTreeSet<&str> = reference.results
         .iter()
-        .cloned()
-        .chain(output.stderr.iter().cloned())
+        .chain(&tested.results)
+        .filter(|result| result.status == "ignored")
+        .map(|result| &result.test_name[..])
         .collect();
-    let all_output = util::into_string(all_bytes);
+    let not_ignored = |result: &&TestCaseResult| !ignored.contains(&result.test_name[..]);
+    reference.success == tested.success &&
+    reference.results.iter().filter(&not_ignored).eq(tested.results.iter().filter(&not_ignored))
+}
+
+fn print_diff(normal: &str, incremental: &str, args: &Args) {
+    let diff = util::unified_diff(normal, incremental, DIFF_CONTEXT);
+    if args.use_color() {
+        print!("{}", util::colorize_diff(&diff));
+    } else {
+        print!("{}", diff);
+    }
+}
+
+// The (sorted) test results, one per line, for `--diff`.
+fn test_results_text(results: &[TestCaseResult]) -> String {
+    results.iter()
+        .map(|result| format!("test {} ... {}\n", result.test_name, result.status))
+        .collect()
+}
 
+fn parse_libtest_results(all_output: &str, output: &Output) -> Vec<TestCaseResult> {
+    let panics = parse_libtest_panics(all_output);
     let test_regex = Regex::new(r"(?m)^test (.*) \.\.\. (\w+)").unwrap();
     let mut test_results: Vec<_> = test_regex.captures_iter(&all_output)
         .map(|captures| {
+            let test_name = captures.at(1).unwrap();
+            // A benchmark prints its timing instead, e.g. `bench: 120 ns/iter`.
+            let status = match captures.at(2).unwrap() {
+                "bench" => "ok",
+                status => status,
+            };
             TestCaseResult {
-                test_name: captures.at(1).unwrap().to_string(),
-                status: captures.at(2).unwrap().to_string(),
+                test_name: test_name.to_string(),
+                status: status.to_string(),
+                panic_message: panics.get(test_name).cloned(),
             }
         })
         .collect();
 
     test_results.sort();
 
-    let summary_regex = Regex::new(r"(?m)(\d+) passed; (\d+) failed; (\d+) ignored; \d+ measured")
-        .unwrap();
+    let summary_regex =
+        Regex::new(r"(?m)(\d+) passed; (\d+) failed; (\d+) ignored; (\d+) measured").unwrap();
 
     let nb_tests_summary = summary_regex.captures_iter(&all_output)
         .fold(0, |acc, captures| {
             acc +
               captures.at(1).unwrap().parse::<usize>().unwrap() + // passed
               captures.at(2).unwrap().parse::<usize>().unwrap() + // failed
-              captures.at(3).unwrap().parse::<usize>().unwrap()   // ignored
+              captures.at(3).unwrap().parse::<usize>().unwrap() + // ignored
+              captures.at(4).unwrap().parse::<usize>().unwrap()   // benchmarks, `... bench:`
         });
 
     if nb_tests_summary != test_results.len() {
-        util::print_output(&output);
+        util::print_output(output);
 
         error!("matched a different number of tests ({}) than in the summary ({})",
                test_results.len(),
                nb_tests_summary);
     }
 
-    TestResult {
-        success: output.status.success(),
-        results: test_results,
-        raw_output: output,
+    test_results
+}
+
+// Extracts what each failed test printed from the `failures:` section of
+// libtest's output, starting at its panic message, e.g.
+//
+//     ---- tests::foo stdout ----
+//     thread 'tests::foo' panicked at src/lib.rs:5:9:
+//     assertion failed: false
+//     note: run with `RUST_BACKTRACE=1` environment variable to display a backtrace
+//
+// Returns a map from test name to panic message (including any backtrace).
+fn parse_libtest_panics(all_output: &str) -> BTreeMap<String, String> {
+    let header_regex = Regex::new(r"^---- (.*) stdout ----$").unwrap();
+    let mut panics = BTreeMap::new();
+    let mut current_test: Option<String> = None;
+    let mut panic_lines: Vec<&str> = vec![];
+
+    for line in all_output.lines() {
+        let header = header_regex.captures(line).map(|captures| captures.at(1).unwrap());
+        let section_ended = header.is_some() || line == "failures:" ||
+                            line.starts_with("test result:");
+
+        if section_ended {
+            if let Some(test_name) = current_test.take() {
+                if !panic_lines.is_empty() {
+                    panics.insert(test_name, panic_lines.join("\n").trim_right().to_string());
+                }
+            }
+            panic_lines.clear();
+            current_test = header.map(|name| name.to_string());
+        } else if current_test.is_some() {
+            let is_panic = line.starts_with("thread '") && line.contains("panicked at");
+            if (is_panic || !panic_lines.is_empty()) && !line.starts_with("note: run with") {
+                panic_lines.push(line);
+            }
+        }
     }
+
+    panics
+}
+
+// Parses nextest's `--status-level all` output, e.g.
+//
+//         PASS [   0.004s] my-crate tests::foo
+//
+// The statuses are translated to what libtest would have printed, so that
+// the rest of the code doesn't have to care which runner was used.
+fn parse_nextest_results(all_output: &str) -> Vec<TestCaseResult> {
+    let test_regex = Regex::new(r"(?m)^\s*([A-Z]+) \[[^\]]*\] (.*)$").unwrap();
+    let mut test_results: Vec<_> = test_regex.captures_iter(all_output)
+        .filter_map(|captures| {
+            let status = match captures.at(1).unwrap() {
+                "PASS" => "ok",
+                "SKIP" => "ignored",
+                "FAIL" | "SIGSEGV" | "SIGABRT" | "ABORT" | "TIMEOUT" | "LEAK" => "FAILED",
+                // e.g. `START` or `SLOW`, which are not results.
+                _ => return None,
+            };
+            Some(TestCaseResult {
+                test_name: captures.at(2).unwrap().trim().to_string(),
+                status: status.to_string(),
+                panic_message: None,
+            })
+        })
+        .collect();
+
+    test_results.sort();
+    test_results
 }
 
 // Compare two incremental compilation cache directories:
@@ -491,16 +2596,40 @@ fn cargo_test(cargo_dir: &Path,
 // - For each pair of crate directories, make sure they are equivalent
 //
 // The function aborts if it finds a difference.
+//
+// `only_crate`, if given, restricts the comparison to the directories of the
+// crate of that name.
+//
+// `progress`, if given, is called with the index of each crate before it is
+// compared, and the number of crates.
 fn compare_incr_comp_dirs(reference_dir: &Path,
-                          tested_dir: &Path)
+                          tested_dir: &Path,
+                          compare_metadata: bool,
+                          only_crate: Option<&str>,
+                          mut progress: Option<&mut FnMut(usize, usize)>)
                           -> Result<(), String> {
 
     // The cache directory contains a sub-directory for each crate
 
-    let reference_crate_dirs = util::dir_entries(reference_dir);
+    let mut reference_crate_dirs = util::dir_entries(reference_dir);
     let tested_crate_dirs = util::dir_entries(tested_dir);
 
-    for reference_crate_dir in reference_crate_dirs {
+    if let Some(crate_name) = only_crate {
+        reference_crate_dirs.retain(|dir| {
+            is_crate_dir_of(&util::path_file_name(dir), crate_name)
+        });
+        if reference_crate_dirs.is_empty() {
+            return Err(format!("no cache directory found for crate `{}` (`--only-crate`)",
+                               crate_name));
+        }
+    }
+
+    let crate_count = reference_crate_dirs.len();
+    for (crate_index, reference_crate_dir) in reference_crate_dirs.into_iter().enumerate() {
+        if let Some(ref mut progress) = progress {
+            progress(crate_index, crate_count);
+        }
+
         let reference_crate_id = reference_crate_dir.file_name().unwrap();
 
         let crate_dir_to_test = tested_crate_dirs.iter().find(|dir| {
@@ -522,74 +2651,252 @@ fn compare_incr_comp_dirs(reference_dir: &Path,
         // directory. It must be the one with exactly the same SVH as the
         // reference directory.
         let reference_session_dir_name = util::path_file_name(&reference_session_dir);
-        let index = reference_session_dir_name.rfind("-").unwrap() + 1;
-        let svh = Some(&reference_session_dir_name[index..]);
-        let test_session_dir = try!(get_only_session_dir(&crate_dir_to_test, svh));
+        let svh = try!(parse_session_dir_name(&reference_session_dir_name)).svh;
+        let test_session_dir = try!(get_only_session_dir(&crate_dir_to_test, Some(svh)));
 
-        try!(compare_incr_comp_session_dirs(&reference_session_dir, &test_session_dir));
+        try!(compare_incr_comp_session_dirs(&reference_session_dir,
+                                            &test_session_dir,
+                                            compare_metadata));
     }
 
     Ok(())
 }
 
+// The cache directory of a crate is named after the crate and a hash that
+// tells apart crates of the same name, e.g. `serde-2fbd3e0e6aa1d2e3`.
+fn is_crate_dir_of(dir_name: &str, crate_name: &str) -> bool {
+    dir_name.rsplitn(2, '-').nth(1) == Some(crate_name)
+}
+
 // Compare two incr. comp. session directories:
 //
 // - Make sure that the two session directories contain exactly the same object
 //   and bitcode files and that they have the same content.
-// - Dep-graph and metadata files are not compared yet.
+// - Compare the work-products index, which maps compilation units to those
+//   files, ignoring its header (see `compare_incr_cache_files`).
+// - If `compare_metadata` is set, do the same for the metadata and query-cache
+//   files.
+// - Dep-graph files are not compared yet.
 //
 // The function aborts if it finds a difference.
 fn compare_incr_comp_session_dirs(reference_crate_dir: &Path,
-                                  crate_dir_to_test: &Path)
+                                  crate_dir_to_test: &Path,
+                                  compare_metadata: bool)
                                   -> Result<(), String> {
 
-    let ref_dir_entries = util::dir_entries(reference_crate_dir);
-    let test_dir_entries = util::dir_entries(crate_dir_to_test);
+    let ref_dir_file_names = file_names(&util::dir_entries(reference_crate_dir));
+    let test_dir_file_names = file_names(&util::dir_entries(crate_dir_to_test));
 
-    let ref_dir_file_names: BTreeSet<String> = ref_dir_entries
-        .iter()
-        .map(|p| p.file_name().unwrap())
-        .map(|s| s.to_string_lossy().into_owned())
-        .collect();
+    try!(check_same_file_names(&ref_dir_file_names, &test_dir_file_names));
 
-    let test_dir_file_names: BTreeSet<String> = test_dir_entries
-        .iter()
-        .map(|p| p.file_name().unwrap())
-        .map(|s| s.to_string_lossy().into_owned())
-        .collect();
+    for file_name in ref_dir_file_names.iter() {
+        // Compare compilation units (object files + bitcode) first, so that a
+        // difference there is reported as such even if the metadata differs
+        // too. Dep-graph and exported hashes don't have a stable encoding
+        // yet. (Doc builds have no compilation units, so for them we only
+        // check that the same files exist.)
+        if file_name.starts_with("cgu-") {
+            let ref_file = reference_crate_dir.join(file_name);
+            let test_file = crate_dir_to_test.join(file_name);
+
+            try!(compare_files(&ref_file, &test_file)
+                .map_err(|err| format!("codegen unit mismatch: {}", err)));
+        }
+    }
+
+    // The compilation units can be right while the index pointing to them
+    // is wrong, so check that separately.
+    for file_name in ref_dir_file_names.iter() {
+        if file_name.starts_with("work-products") {
+            let ref_file = reference_crate_dir.join(file_name);
+            let test_file = crate_dir_to_test.join(file_name);
+
+            try!(compare_incr_cache_files(&ref_file, &test_file)
+                .map_err(|err| format!("work-products mismatch: {}", err)));
+        }
+    }
+
+    if compare_metadata {
+        for file_name in ref_dir_file_names.iter() {
+            if file_name.starts_with("metadata") || file_name.starts_with("query-cache") {
+                let ref_file = reference_crate_dir.join(file_name);
+                let test_file = crate_dir_to_test.join(file_name);
+
+                try!(compare_incr_cache_files(&ref_file, &test_file)
+                    .map_err(|err| format!("metadata mismatch: {}", err)));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// Compare two of rustc's incremental cache files after zeroing out their
+// headers, which contain the compiler version and so may legitimately differ.
+fn compare_incr_cache_files(file1_path: &Path, file2_path: &Path) -> Result<(), String> {
+    let mut contents1 = try!(read_file(file1_path));
+    let mut contents2 = try!(read_file(file2_path));
+
+    mask_incr_cache_header(&mut contents1);
+    mask_incr_cache_header(&mut contents2);
+
+    if contents1 != contents2 {
+        return Err(format!("Files `{}` and `{}` have different content",
+                           file1_path.display(),
+                           file2_path.display()));
+    }
+
+    Ok(())
+}
+
+fn read_file(path: &Path) -> Result<Vec<u8>, String> {
+    let mut contents = vec![];
+    try!(File::open(path).and_then(|mut file| file.read_to_end(&mut contents)).map_err(|err| {
+        format!("Could not read file `{}` for comparison: {}", path.display(), err)
+    }));
+    Ok(contents)
+}
+
+// The header of rustc's incremental cache files is the magic `RSIC`, a
+// two-byte format version, and the length-prefixed version string of the
+// compiler that wrote the file. Files without the magic are left as they are.
+fn mask_incr_cache_header(contents: &mut [u8]) {
+    const MAGIC: &'static [u8] = b"RSIC";
+    const VERSION_LEN_OFFSET: usize = 6;
+
+    if !contents.starts_with(MAGIC) || contents.len() <= VERSION_LEN_OFFSET {
+        return;
+    }
+
+    let header_len = VERSION_LEN_OFFSET + 1 + contents[VERSION_LEN_OFFSET] as usize;
+    let header_len = ::std::cmp::min(header_len, contents.len());
+    for byte in &mut contents[MAGIC.len()..header_len] {
+        *byte = 0;
+    }
+}
+
+// Compare the final artifacts (binaries, rlibs, ...) that cargo leaves in the
+// top level of two target directories. Intermediate artifacts in `deps/` etc.
+// are not compared, and neither are the `.d` dependency files since they
+// contain paths. For cross builds (`target`), cargo puts these artifacts in a
+// subdirectory named after the target triple.
+//
+// If `strip_dir` is given, the artifacts are copied there and run through
+// `strip` before comparing, so that embedded paths and debuginfo don't cause
+// spurious differences.
+//
+// The function aborts if it finds a difference.
+fn compare_build_artifacts(reference_target_dir: &Path,
+                           tested_target_dir: &Path,
+                           target: Option<&str>,
+                           strip_dir: Option<&Path>)
+                           -> Result<(), String> {
+    let reference_dir = reference_target_dir.join(profile_dir(target));
+    let tested_dir = tested_target_dir.join(profile_dir(target));
+
+    let ref_artifacts = build_artifacts(&reference_dir);
+    let test_artifacts = build_artifacts(&tested_dir);
+
+    let ref_file_names = file_names(&ref_artifacts);
+    let test_file_names = file_names(&test_artifacts);
+
+    try!(check_same_file_names(&ref_file_names, &test_file_names));
+
+    if let Some(strip_dir) = strip_dir {
+        util::remove_dir(strip_dir);
+    }
+
+    for file_name in ref_file_names.iter() {
+        let ref_file = reference_dir.join(file_name);
+        let test_file = tested_dir.join(file_name);
+
+        if let Some(strip_dir) = strip_dir {
+            let stripped_ref_file = strip_dir.join("reference").join(file_name);
+            let stripped_test_file = strip_dir.join("tested").join(file_name);
+            try!(util::strip_copy(&ref_file, &stripped_ref_file));
+            try!(util::strip_copy(&test_file, &stripped_test_file));
+            try!(compare_files(&stripped_ref_file, &stripped_test_file));
+        } else {
+            try!(compare_files(&ref_file, &test_file));
+        }
+    }
+
+    Ok(())
+}
+
+// Where cargo puts the final artifacts within a target directory.
+fn profile_dir(target: Option<&str>) -> PathBuf {
+    match target {
+        Some(target) => Path::new(target).join("debug"),
+        None => PathBuf::from("debug"),
+    }
+}
+
+// The final artifacts in `dir`, leaving out the dep-info files.
+fn build_artifacts(dir: &Path) -> Vec<PathBuf> {
+    util::dir_entries(dir)
+        .into_iter()
+        .filter(|path| path.is_file() && path.extension().map_or(true, |ext| ext != "d"))
+        .collect()
+}
+
+// Copies the final artifacts in `target_dir` to `snapshot_dir`, laid out
+// like a target directory, so `compare_build_artifacts` can still compare
+// them after the next build has replaced them.
+fn snapshot_build_artifacts(target_dir: &Path, target: Option<&str>, snapshot_dir: &Path) {
+    util::remove_dir(snapshot_dir);
+    let snapshot_profile_dir = snapshot_dir.join(profile_dir(target));
+    util::make_dir(&snapshot_profile_dir);
+    for path in build_artifacts(&target_dir.join(profile_dir(target))) {
+        let copy = snapshot_profile_dir.join(util::path_file_name(&path));
+        if let Err(err) = fs::copy(&path, &copy) {
+            error!("failed to copy `{}` to `{}`: {}", path.display(), copy.display(), err)
+        }
+    }
+}
+
+fn file_names(paths: &[PathBuf]) -> BTreeSet<String> {
+    paths.iter()
+         .map(|p| util::path_file_name(p))
+         .collect()
+}
 
-    if ref_dir_file_names != test_dir_file_names {
+fn check_same_file_names(ref_file_names: &BTreeSet<String>,
+                         test_file_names: &BTreeSet<String>)
+                         -> Result<(), String> {
+    if ref_file_names != test_file_names {
         let mut message = String::new();
         message.push_str("The following files are missing in test dir:\n");
 
-        for name in ref_dir_file_names.difference(&test_dir_file_names) {
+        for name in ref_file_names.difference(test_file_names) {
             message.push_str(&format!(" - {}\n", name));
         }
 
         message.push_str("\nThe following files in test dir should not be there:\n");
 
-        for name in test_dir_file_names.difference(&ref_dir_file_names) {
+        for name in test_file_names.difference(ref_file_names) {
             message.push_str(&format!(" - {}\n", name));
         }
 
         return Err(message);
     }
 
-    for file_name in ref_dir_file_names.iter() {
-        // For now only compare compilation units (object files + bitcode).
-        // Metadata, dep-graph, and exported hashes don't have a stable encoding
-        // yet.
-        if file_name.starts_with("cgu-") {
-            let ref_file = reference_crate_dir.join(file_name);
-            let test_file = crate_dir_to_test.join(file_name);
-
-            try!(compare_files(&ref_file, &test_file));
-        }
-    }
-
     Ok(())
 }
 
+// Whether any crate directory in the incremental cache `incr_dir` has a
+// session directory.
+fn has_session_dirs(incr_dir: &Path) -> bool {
+    util::dir_entries(incr_dir)
+        .iter()
+        .filter(|crate_dir| crate_dir.is_dir())
+        .any(|crate_dir| {
+            util::dir_entries(crate_dir)
+                .iter()
+                .any(|entry| entry.is_dir() && util::path_file_name(entry).starts_with("s-"))
+        })
+}
+
 // From a crate-directory within the incremental compilation directory, get the
 // sole session directory in there. If there is more than one directory,
 // something is wrong and the function will abort.
@@ -598,12 +2905,11 @@ fn get_only_session_dir(crate_dir: &Path,
                         -> Result<PathBuf, String> {
     let dir_entries = util::dir_entries(crate_dir);
 
-    return if let Some(svh) = svh {
+    if let Some(svh) = svh {
         for entry in dir_entries {
             if entry.is_dir() {
                 let dir_name = util::path_file_name(&entry);
-                if dir_name.ends_with(svh) {
-                    try!(check_well_formed_session_dir_name(&dir_name));
+                if try!(parse_session_dir_name(&dir_name)).svh == svh {
                     return Ok(entry);
                 }
             }
@@ -634,16 +2940,34 @@ fn get_only_session_dir(crate_dir: &Path,
 
         let first_dir = first_dir.unwrap();
         let dir_name = util::path_file_name(&first_dir);
-        try!(check_well_formed_session_dir_name(&dir_name));
+        try!(parse_session_dir_name(&dir_name));
         Ok(first_dir)
-    };
+    }
+}
+
+// The parts of an incr. comp. session directory name, which looks like
+// `s-<timestamp>-<random>-<svh>`.
+#[derive(Debug, PartialEq, Eq)]
+struct SessionDirName<'a> {
+    timestamp: &'a str,
+    random: &'a str,
+    svh: &'a str,
+}
 
-    fn check_well_formed_session_dir_name(dir_name: &str) -> Result<(), String> {
-        if !dir_name.starts_with("s-") {
+fn parse_session_dir_name(dir_name: &str) -> Result<SessionDirName, String> {
+    let mut parts = dir_name.split('-');
+    match (parts.next(), parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some("s"), Some(timestamp), Some(random), Some(svh), None)
+            if !timestamp.is_empty() && !random.is_empty() && !svh.is_empty() => {
+            Ok(SessionDirName {
+                timestamp: timestamp,
+                random: random,
+                svh: svh,
+            })
+        }
+        _ => {
             Err(format!("incr. comp. session directory has unexpected name `{}`",
-                         dir_name))
-        } else {
-            Ok(())
+                        dir_name))
         }
     }
 }
@@ -701,7 +3025,14 @@ struct SubTaskRunner<'a> {
     progress_bar: &'a mut Bar,
     commit_index: usize,
     commit_id: String,
+    commit_summary: String,
     cli_log: bool,
+    // With `--summary-only`, `cli_log` prints one line per commit, from
+    // `stage_results`, rather than one per stage.
+    summary_only: bool,
+    stage_results: Vec<(&'static str, &'static str)>,
+    quiet: bool,
+    on_interrupt: &'a Fn(),
     total_commit_count: usize,
     global_start_time: time::Instant,
 }
@@ -710,10 +3041,24 @@ impl<'a> SubTaskRunner<'a> {
 
     fn run<F, T>(&mut self, task_label: &str, task: F) -> T
         where F: FnOnce() -> (T, &'static str)
+    {
+        self.run_with_progress(task_label, |_| task())
+    }
+
+    // Like `run`, for stages that take long enough that `task` should show
+    // how far along it is, using the `StageProgress` it gets.
+    fn run_with_progress<F, T>(&mut self, task_label: &str, task: F) -> T
+        where F: FnOnce(&mut StageProgress) -> (T, &'static str)
     {
         let stage_index = STAGES.iter().position(|&x| x == task_label).unwrap();
 
-        if self.cli_log {
+        self.abort_if_interrupted();
+
+        let num_stages = STAGES.len() as f32;
+        let progress = (self.commit_index as f32 * num_stages) + (stage_index as f32);
+        let total = (self.total_commit_count as f32) * num_stages;
+
+        let (task_title, eta) = if self.cli_log && !self.summary_only {
             let time_stamp = self.global_start_time.elapsed();
 
             let stdout = ::std::io::stdout();
@@ -723,25 +3068,149 @@ impl<'a> SubTaskRunner<'a> {
                    util::duration_to_string(time_stamp),
                    STAGES[stage_index]).unwrap();
             stdout.flush().unwrap();
+            (String::new(), String::new())
+        } else if !self.cli_log && !self.quiet {
+            let task_title = format!("{} ({} \"{}\")",
+                                     STAGES[stage_index],
+                                     self.commit_id,
+                                     self.commit_summary);
+
+            // Extrapolate the remaining time from the stages completed so far.
+            let eta = if progress > 0.0 {
+                let elapsed = self.global_start_time.elapsed();
+                let elapsed_secs = elapsed.as_secs() as f32 +
+                                   elapsed.subsec_nanos() as f32 / 1_000_000_000.0;
+                let remaining_secs = elapsed_secs / progress * (total - progress);
+                let remaining = time::Duration::from_secs(remaining_secs as u64);
+                format!(" - ETA {}", util::duration_to_eta_string(remaining))
+            } else {
+                String::new()
+            };
+
+            self.progress_bar.set_job_title(&progress_title(&task_title, &eta));
+            (task_title, eta)
         } else {
-            let task_title = &format!("{} ({})", STAGES[stage_index], self.commit_id);
-            self.progress_bar.set_job_title(task_title);
-        }
+            (String::new(), String::new())
+        };
 
-        let (result, message) = task();
+        let (result, message) = {
+            let mut stage_progress = StageProgress {
+                progress_bar: if !self.cli_log && !self.quiet {
+                    Some(&mut *self.progress_bar)
+                } else {
+                    None
+                },
+                title: task_title,
+                eta: eta,
+            };
+            task(&mut stage_progress)
+        };
 
-        if self.cli_log {
+        self.abort_if_interrupted();
+        self.stage_results.push((STAGES[stage_index], message));
+
+        if self.cli_log && !self.summary_only {
             println!("{}", message);
-        } else {
-            let num_stages = STAGES.len() as f32;
-            let progress = (self.commit_index as f32 * num_stages) + (stage_index as f32);
-            let total = (self.total_commit_count as f32) * num_stages;
+        } else if !self.cli_log && !self.quiet {
             let percentage = progress / total * 100.0;
             self.progress_bar.reach_percent(percentage as i32);
         }
 
         result
     }
+
+    // Prints e.g. `abc1234 [9/9 OK]` or `abc1234 DIVERGED at compare
+    // incr/normal builds` for `--summary-only`. Skipped stages don't count.
+    fn print_commit_summary(&self) {
+        if !self.cli_log || !self.summary_only {
+            return;
+        }
+
+        let diverged = self.stage_results.iter().find(|&&(_, message)| message == "DIVERGED");
+        if let Some(&(stage, _)) = diverged {
+            println!("{} DIVERGED at {}", self.commit_id, stage);
+            return;
+        }
+
+        let run: Vec<_> = self.stage_results
+            .iter()
+            .filter(|&&(_, message)| message != "skipped")
+            .collect();
+        let ok = run.iter()
+            .filter(|&&&(_, message)| message.starts_with("OK") || message.starts_with("reused"))
+            .count();
+        println!("{} [{}/{} OK]", self.commit_id, ok, run.len());
+    }
+
+    fn abort_if_interrupted(&self) {
+        if util::was_interrupted() {
+            (self.on_interrupt)();
+            error!("interrupted; the repository has been restored to its original HEAD");
+        }
+    }
+}
+
+// Fits `title` followed by `suffix` into `PROGRESS_TITLE_CHARS`, shortening
+// the title first, so the ETA and what the stage is doing stay visible.
+fn progress_title(title: &str, suffix: &str) -> String {
+    let room = PROGRESS_TITLE_CHARS.saturating_sub(suffix.chars().count());
+    util::truncate_chars(&format!("{}{}", util::truncate_chars(title, room), suffix),
+                         PROGRESS_TITLE_CHARS)
+}
+
+// Lets a long stage show how far along it is in the job title of the
+// progress bar. Without a progress bar (`--cli-log`, `--quiet`), it does
+// nothing.
+struct StageProgress<'b> {
+    progress_bar: Option<&'b mut Bar>,
+    title: String,
+    // E.g. ` - ETA 5m12s`, or nothing.
+    eta: String,
+}
+
+impl<'b> StageProgress<'b> {
+    // Shows `detail` after the stage, e.g. `comparing crate 3 of 10`.
+    fn set_detail(&mut self, detail: &str) {
+        if let Some(ref mut progress_bar) = self.progress_bar {
+            progress_bar.set_job_title(&progress_title(&self.title,
+                                                       &format!("{} - {}", self.eta, detail)));
+        }
+    }
+
+    // Runs `task`, meanwhile updating the job title with how long it has
+    // been running, so the progress bar doesn't look stuck during a long
+    // build.
+    fn show_elapsed_time<F, T>(&mut self, task: F) -> T
+        where F: FnOnce() -> T
+    {
+        let progress_bar = match self.progress_bar {
+            Some(ref mut progress_bar) => progress_bar,
+            None => return task(),
+        };
+
+        // The ticker thread borrows the progress bar until `task` is done.
+        let mut bar = mem::replace(&mut **progress_bar, Bar::new());
+        let title = self.title.clone();
+        let eta = self.eta.clone();
+        let (done_sender, done_receiver) = mpsc::channel();
+        let ticker = thread::spawn(move || {
+            let start_time = time::Instant::now();
+            loop {
+                let elapsed = util::duration_to_string(start_time.elapsed());
+                bar.set_job_title(&progress_title(&title,
+                                                  &format!("{} - {} elapsed", eta, elapsed)));
+                match done_receiver.recv_timeout(time::Duration::from_secs(1)) {
+                    Err(mpsc::RecvTimeoutError::Timeout) => {}
+                    _ => return bar,
+                }
+            }
+        });
+
+        let result = task();
+        done_sender.send(()).unwrap();
+        **progress_bar = ticker.join().unwrap();
+        result
+    }
 }
 
 // This function injects a [profile.dev] into the given Cargo.toml that
@@ -765,6 +3234,8 @@ fn inject_no_debug_into_cargo_toml(cargo_dir: &Path) -> io::Result<()> {
         return Err(io::Error::new(io::ErrorKind::Other, msg));
     }
 
+    util::remember_original_contents(&cargo_toml_path, contents.as_bytes());
+
     contents.push_str("\n");
     contents.push_str("[profile.dev]\n");
     contents.push_str("debug = false\n");
@@ -775,3 +3246,237 @@ fn inject_no_debug_into_cargo_toml(cargo_dir: &Path) -> io::Result<()> {
 
     Ok(())
 }
+
+#[test]
+fn test_progress_title() {
+    assert_eq!(progress_title("build (abc1234 \"Fix typo\")", " - ETA 5m"),
+               "build (abc1234 \"Fix typo\") - ETA 5m");
+
+    let title = progress_title("incremental build / no change (abc1234 \"Rework the \
+                                whole query system\")",
+                               " - ETA 1h05m - 12s elapsed");
+    assert_eq!(title.chars().count(), PROGRESS_TITLE_CHARS);
+    assert!(title.ends_with("... - ETA 1h05m - 12s elapsed"));
+
+    let long_suffix: String = ::std::iter::repeat('x').take(60).collect();
+    assert_eq!(progress_title("build", &long_suffix).chars().count(), PROGRESS_TITLE_CHARS);
+}
+
+#[test]
+fn test_parse_shard() {
+    assert_eq!(parse_shard("0/4"), Ok((0, 4)));
+    assert_eq!(parse_shard("3/4"), Ok((3, 4)));
+    assert!(parse_shard("4/4").is_err());
+    assert!(parse_shard("1").is_err());
+    assert!(parse_shard("a/b").is_err());
+}
+
+#[test]
+fn test_mask_incr_cache_header() {
+    let mut contents = b"RSIC\x02\x00\x061.70.0payload".to_vec();
+    mask_incr_cache_header(&mut contents);
+    assert_eq!(&contents[..], &b"RSIC\0\0\0\0\0\0\0\0\0payload"[..]);
+
+    let mut contents = b"not a cache file".to_vec();
+    mask_incr_cache_header(&mut contents);
+    assert_eq!(&contents[..], &b"not a cache file"[..]);
+}
+
+#[test]
+fn test_parse_session_dir_name() {
+    assert_eq!(parse_session_dir_name("s-f2gkxpmwk0-1x4k3ve-3f8pf5e2c4rxq"),
+               Ok(SessionDirName {
+                   timestamp: "f2gkxpmwk0",
+                   random: "1x4k3ve",
+                   svh: "3f8pf5e2c4rxq",
+               }));
+    assert_eq!(parse_session_dir_name("s-f2gkxpmwk0-1x4k3ve-working").map(|name| name.svh),
+               Ok("working"));
+    assert!(parse_session_dir_name("f2gkxpmwk0-1x4k3ve-3f8pf5e2c4rxq").is_err());
+    assert!(parse_session_dir_name("s-f2gkxpmwk0-3f8pf5e2c4rxq").is_err());
+    assert!(parse_session_dir_name("s-f2gkxpmwk0-1x4k3ve-").is_err());
+    assert!(parse_session_dir_name("s-f2gkxpmwk0-1x4k3ve-3f8p-f5e2").is_err());
+}
+
+#[test]
+fn test_parse_libtest_panics() {
+    let output = "\
+running 2 tests
+test tests::works ... ok
+test tests::panics ... FAILED
+
+failures:
+
+---- tests::panics stdout ----
+some output of the test
+thread 'tests::panics' panicked at src/lib.rs:12:9:
+assertion `left == right` failed
+  left: 1
+ right: 2
+note: run with `RUST_BACKTRACE=1` environment variable to display a backtrace
+
+
+failures:
+    tests::panics
+
+test result: FAILED. 1 passed; 1 failed; 0 ignored; 0 measured; 0 filtered out
+";
+
+    let panics = parse_libtest_panics(output);
+    assert_eq!(panics.len(), 1);
+    assert_eq!(panics["tests::panics"],
+               "thread 'tests::panics' panicked at src/lib.rs:12:9:\n\
+                assertion `left == right` failed\n  left: 1\n right: 2");
+}
+
+#[test]
+fn test_parse_test_output() {
+    use std::os::unix::process::ExitStatusExt;
+    use std::process::ExitStatus;
+
+    let parse = |status: i32, stdout: &str| {
+        let output = Output {
+            status: ExitStatus::from_raw(status),
+            stdout: stdout.as_bytes().to_vec(),
+            stderr: vec![],
+        };
+        let command = CargoCommand::new(Path::new("."), Path::new("target"));
+        let result = parse_test_output(output, command, TestRunner::Libtest);
+        let results: Vec<(String, String)> = result.results
+            .into_iter()
+            .map(|r| (r.test_name, r.status))
+            .collect();
+        (result.success, results)
+    };
+    let results = |expected: &[(&str, &str)]| {
+        expected.iter()
+            .map(|&(name, status)| (name.to_string(), status.to_string()))
+            .collect::<Vec<_>>()
+    };
+
+    let passing = "\
+running 2 tests
+test tests::b ... ok
+test tests::a ... ignored, needs the network
+
+test result: ok. 1 passed; 0 failed; 1 ignored; 0 measured; 0 filtered out; finished in 0.00s
+
+   Doc-tests foo
+
+running 1 test
+test src/lib.rs - foo (line 3) ... ok
+
+test result: ok. 1 passed; 0 failed; 0 ignored; 0 measured; 0 filtered out; finished in 0.20s
+";
+    assert_eq!(parse(0, passing),
+               (true,
+                results(&[("src/lib.rs - foo (line 3)", "ok"),
+                          ("tests::a", "ignored"),
+                          ("tests::b", "ok")])));
+
+    let failing = "\
+running 1 test
+test tests::fails ... FAILED
+
+failures:
+
+---- tests::fails stdout ----
+thread 'tests::fails' panicked at src/lib.rs:3:5:
+oops
+
+failures:
+    tests::fails
+
+test result: FAILED. 0 passed; 1 failed; 0 ignored; 0 measured; 0 filtered out
+";
+    assert_eq!(parse(101 << 8, failing), (false, results(&[("tests::fails", "FAILED")])));
+
+    let benches = "\
+running 1 test
+test benches::sum ... bench:         120 ns/iter (+/- 3)
+
+test result: ok. 0 passed; 0 failed; 0 ignored; 1 measured; 0 filtered out
+";
+    assert_eq!(parse(0, benches), (true, results(&[("benches::sum", "ok")])));
+
+    // A test printing raw bytes must not keep us from parsing the rest, nor
+    // end up mangled in the output we save.
+    let mut raw_bytes = b"running 1 test\n\xff\xfe garbage\ntest tests::raw ... ok\n\n".to_vec();
+    raw_bytes.extend_from_slice(b"test result: ok. 1 passed; 0 failed; 0 ignored; 0 measured\n");
+    let output = Output {
+        status: ExitStatus::from_raw(0),
+        stdout: raw_bytes.clone(),
+        stderr: vec![],
+    };
+    let command = CargoCommand::new(Path::new("."), Path::new("target"));
+    let result = parse_test_output(output, command, TestRunner::Libtest);
+    assert_eq!(result.results.iter().map(|r| &r.test_name[..]).collect::<Vec<_>>(),
+               vec!["tests::raw"]);
+    assert_eq!(result.raw_output.stdout, raw_bytes);
+}
+
+#[test]
+fn test_is_rename_only() {
+    let temp = util::TempRepo::new("renames");
+    let repo = &temp.repo;
+    let signature = git2::Signature::now("Test", "test@example.com").unwrap();
+
+    let commit_with = |files: &[(&str, &[u8])]| {
+        let mut builder = repo.treebuilder(None).unwrap();
+        for &(name, contents) in files {
+            builder.insert(name, repo.blob(contents).unwrap(), 0o100644).unwrap();
+        }
+        let tree = repo.find_tree(builder.write().unwrap()).unwrap();
+        let oid = repo.commit(None, &signature, &signature, "test", &tree, &[]).unwrap();
+        repo.find_commit(oid).unwrap()
+    };
+
+    let original = commit_with(&[("lib.rs", b"mod foo;"), ("foo.rs", b"fn foo() {}")]);
+    let renamed = commit_with(&[("lib.rs", b"mod foo;"), ("bar.rs", b"fn foo() {}")]);
+    let changed = commit_with(&[("lib.rs", b"mod bar;"), ("bar.rs", b"fn foo() {}")]);
+    assert!(is_rename_only(repo, &original, &renamed));
+    assert!(!is_rename_only(repo, &original, &changed));
+    assert!(!is_rename_only(repo, &original, &original));
+}
+
+#[test]
+fn test_restore_original_head_after_interrupt() {
+    let temp = util::TempRepo::new("interrupt");
+    let repo = &temp.repo;
+    let signature = git2::Signature::now("Test", "test@example.com").unwrap();
+    let commit_with = |update_ref: Option<&str>, cargo_toml: &[u8]| {
+        let mut builder = repo.treebuilder(None).unwrap();
+        builder.insert("Cargo.toml", repo.blob(cargo_toml).unwrap(), 0o100644).unwrap();
+        let tree = repo.find_tree(builder.write().unwrap()).unwrap();
+        let oid = repo.commit(update_ref, &signature, &signature, "test", &tree, &[]).unwrap();
+        repo.find_commit(oid).unwrap()
+    };
+    let original = commit_with(Some("HEAD"), b"[package]\nname = \"new\"\n");
+    let replayed = commit_with(None, b"[package]\nname = \"old\"\n");
+
+    // Interrupted while building `replayed`, with its Cargo.toml modified.
+    util::reset_repo(repo, &replayed);
+    let cargo_toml = temp.dir.join("Cargo.toml");
+    let mut contents = vec![];
+    File::open(&cargo_toml).unwrap().read_to_end(&mut contents).unwrap();
+    util::remember_original_contents(&cargo_toml, &contents);
+    File::create(&cargo_toml).unwrap().write_all(b"[profile.dev]\ndebug = false\n").unwrap();
+
+    let branch = "refs/heads/master".to_string();
+    restore_original_head(repo, &original, Some(&branch));
+    // What `error!` does next.
+    util::restore_modified_files();
+
+    let mut contents = String::new();
+    File::open(&cargo_toml).unwrap().read_to_string(&mut contents).unwrap();
+    assert_eq!(contents, "[package]\nname = \"new\"\n");
+    assert_eq!(repo.head().unwrap().name(), Some("refs/heads/master"));
+}
+
+#[test]
+fn test_is_crate_dir_of() {
+    assert!(is_crate_dir_of("serde-2fbd3e0e6aa1d2e3", "serde"));
+    assert!(is_crate_dir_of("my_crate-2fbd3e0e6aa1d2e3", "my_crate"));
+    assert!(!is_crate_dir_of("serde_json-2fbd3e0e6aa1d2e3", "serde"));
+    assert!(!is_crate_dir_of("serde", "serde"));
+}
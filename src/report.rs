@@ -0,0 +1,379 @@
+use rustc_serialize::json::{Json, ToJson};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::prelude::*;
+use std::path::Path;
+
+use super::Args;
+use super::util;
+
+/// The outcome of a replay, as written by `--report-json` (or printed by
+/// `--format json`) and read back by `compare-reports`.
+#[derive(Clone, Debug, Default)]
+pub struct ReplayReport {
+    pub revisions: String,
+    // The output of `rustc --version --verbose` and `cargo --version`.
+    // Reports written before these were added leave them empty.
+    pub rustc_version: String,
+    pub cargo_version: String,
+    pub commits: Vec<CommitReport>,
+    pub normal_build_time: f64,
+    pub incremental_build_time: f64,
+    pub modules_reused: u64,
+    pub modules_total: u64,
+    // Reports written before these were added, or by a rustc that doesn't
+    // report on its query cache, leave them 0.
+    pub queries_reused: u64,
+    pub queries_total: u64,
+    pub tests_total: u64,
+    pub tests_passed: u64,
+    pub divergences: Vec<DivergenceReport>,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct CommitReport {
+    pub id: String,
+    // The full commit id, author, and commit date. Reports written before
+    // these were added leave them empty.
+    pub oid: String,
+    pub author: String,
+    pub date: String,
+    pub summary: String,
+    // In seconds. The first incremental build is followed by no-change
+    // rebuilds; there is more than one of each only with `--samples`.
+    pub normal_build_times: Vec<f64>,
+    pub incremental_build_times: Vec<f64>,
+}
+
+// How one commit fared, for `--per-commit-table` and `--html-report`. The
+// modules are those of its first incremental build.
+#[derive(Clone, Debug, Default)]
+pub struct CommitStatus {
+    pub id: String,
+    pub summary: String,
+    // "OK", "FAIL" or "SKIPPED", or "STOPPED" for the commit where the
+    // replay stopped at a divergence
+    pub build: &'static str,
+    pub tests_passed: usize,
+    pub tests_total: usize,
+    pub modules_reused: u64,
+    pub modules_total: u64,
+    // Whether the commit only renamed (or moved) files; see `is_rename_only`.
+    pub rename_only: bool,
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct DivergenceReport {
+    pub commit_id: String,
+    pub stage: String,
+    pub message: String,
+}
+
+impl ReplayReport {
+    pub fn reuse_percent(&self) -> f64 {
+        if self.modules_total == 0 {
+            100.0
+        } else {
+            self.modules_reused as f64 / self.modules_total as f64 * 100.0
+        }
+    }
+
+    pub fn write(&self, path: &Path) {
+        let contents = format!("{}\n", self.to_json().pretty());
+        let result = File::create(path).and_then(|mut file| file.write_all(contents.as_bytes()));
+        if let Err(err) = result {
+            error!("failed to write report to `{}`: {}", path.display(), err)
+        }
+    }
+
+    /// One CSV row per commit, as printed by `replay --format csv`. The build
+    /// times are in seconds; the normal one is the mean over the samples and
+    /// left empty if there was no normal build. The incremental one is the
+    /// first build of each commit, not one of the no-change rebuilds.
+    pub fn to_csv(&self) -> String {
+        let mut csv = String::from("id,oid,author,date,summary,normal_build_time,\
+                                    incremental_build_time,divergences\n");
+        for commit in &self.commits {
+            let normal_build_time = if commit.normal_build_times.is_empty() {
+                "".to_string()
+            } else {
+                let (mean, _) = util::mean_and_std_dev(&commit.normal_build_times);
+                format!("{:.3}", mean)
+            };
+            let incremental_build_time = commit.incremental_build_times
+                .first()
+                .map_or("".to_string(), |time| format!("{:.3}", time));
+            let divergences = self.divergences
+                .iter()
+                .filter(|divergence| divergence.commit_id == commit.id)
+                .count();
+            let fields = [csv_field(&commit.id),
+                          csv_field(&commit.oid),
+                          csv_field(&commit.author),
+                          csv_field(&commit.date),
+                          csv_field(&commit.summary),
+                          normal_build_time,
+                          incremental_build_time,
+                          divergences.to_string()];
+            csv.push_str(&fields.join(","));
+            csv.push('\n');
+        }
+        csv
+    }
+
+    pub fn load(path: &Path) -> Result<ReplayReport, String> {
+        let mut contents = String::new();
+        try!(File::open(path)
+            .and_then(|mut file| file.read_to_string(&mut contents))
+            .map_err(|err| format!("failed to read `{}`: {}", path.display(), err)));
+
+        let json = try!(Json::from_str(&contents)
+            .map_err(|err| format!("`{}` is not valid JSON: {}", path.display(), err)));
+
+        ReplayReport::from_json(&json)
+            .map_err(|err| format!("`{}` is not a replay report: {}", path.display(), err))
+    }
+
+    fn from_json(json: &Json) -> Result<ReplayReport, String> {
+        let commits = try!(field(json, "commits", Json::as_array));
+        let divergences = try!(field(json, "divergences", Json::as_array));
+
+        Ok(ReplayReport {
+            revisions: try!(field(json, "revisions", Json::as_string)).to_string(),
+            rustc_version: optional_string(json, "rustc_version"),
+            cargo_version: optional_string(json, "cargo_version"),
+            commits: try!(commits.iter().map(CommitReport::from_json).collect()),
+            normal_build_time: try!(field(json, "normal_build_time", Json::as_f64)),
+            incremental_build_time: try!(field(json, "incremental_build_time", Json::as_f64)),
+            modules_reused: try!(field(json, "modules_reused", Json::as_u64)),
+            modules_total: try!(field(json, "modules_total", Json::as_u64)),
+            queries_reused: json.find("queries_reused").and_then(Json::as_u64).unwrap_or(0),
+            queries_total: json.find("queries_total").and_then(Json::as_u64).unwrap_or(0),
+            tests_total: try!(field(json, "tests_total", Json::as_u64)),
+            tests_passed: try!(field(json, "tests_passed", Json::as_u64)),
+            divergences: try!(divergences.iter().map(DivergenceReport::from_json).collect()),
+        })
+    }
+}
+
+impl CommitReport {
+    fn from_json(json: &Json) -> Result<CommitReport, String> {
+        Ok(CommitReport {
+            id: try!(field(json, "id", Json::as_string)).to_string(),
+            oid: optional_string(json, "oid"),
+            author: optional_string(json, "author"),
+            date: optional_string(json, "date"),
+            summary: try!(field(json, "summary", Json::as_string)).to_string(),
+            normal_build_times: try!(field(json, "normal_build_times", f64_array)),
+            incremental_build_times: try!(field(json, "incremental_build_times", f64_array)),
+        })
+    }
+}
+
+impl DivergenceReport {
+    fn from_json(json: &Json) -> Result<DivergenceReport, String> {
+        Ok(DivergenceReport {
+            commit_id: try!(field(json, "commit_id", Json::as_string)).to_string(),
+            stage: try!(field(json, "stage", Json::as_string)).to_string(),
+            message: try!(field(json, "message", Json::as_string)).to_string(),
+        })
+    }
+}
+
+// Looks up `key` in the JSON object `json` and converts it with `convert`.
+fn field<'a, T, F>(json: &'a Json, key: &str, convert: F) -> Result<T, String>
+    where F: FnOnce(&'a Json) -> Option<T>
+{
+    match json.find(key) {
+        Some(value) => convert(value).ok_or(format!("field `{}` has the wrong type", key)),
+        None => Err(format!("field `{}` is missing", key)),
+    }
+}
+
+fn optional_string(json: &Json, key: &str) -> String {
+    json.find(key).and_then(Json::as_string).unwrap_or("").to_string()
+}
+
+// Quotes `text` if it contains anything with a meaning in CSV.
+fn csv_field(text: &str) -> String {
+    if text.contains(|c| c == ',' || c == '"' || c == '\n' || c == '\r') {
+        format!("\"{}\"", text.replace("\"", "\"\""))
+    } else {
+        text.to_string()
+    }
+}
+
+fn f64_array(json: &Json) -> Option<Vec<f64>> {
+    json.as_array().and_then(|array| array.iter().map(Json::as_f64).collect())
+}
+
+impl ToJson for ReplayReport {
+    fn to_json(&self) -> Json {
+        let mut object = BTreeMap::new();
+        object.insert("revisions".to_string(), self.revisions.to_json());
+        object.insert("rustc_version".to_string(), self.rustc_version.to_json());
+        object.insert("cargo_version".to_string(), self.cargo_version.to_json());
+        object.insert("commits".to_string(), self.commits.to_json());
+        object.insert("normal_build_time".to_string(), self.normal_build_time.to_json());
+        object.insert("incremental_build_time".to_string(),
+                      self.incremental_build_time.to_json());
+        object.insert("modules_reused".to_string(), self.modules_reused.to_json());
+        object.insert("modules_total".to_string(), self.modules_total.to_json());
+        object.insert("queries_reused".to_string(), self.queries_reused.to_json());
+        object.insert("queries_total".to_string(), self.queries_total.to_json());
+        object.insert("tests_total".to_string(), self.tests_total.to_json());
+        object.insert("tests_passed".to_string(), self.tests_passed.to_json());
+        object.insert("divergences".to_string(), self.divergences.to_json());
+        Json::Object(object)
+    }
+}
+
+impl ToJson for CommitReport {
+    fn to_json(&self) -> Json {
+        let mut object = BTreeMap::new();
+        object.insert("id".to_string(), self.id.to_json());
+        object.insert("oid".to_string(), self.oid.to_json());
+        object.insert("author".to_string(), self.author.to_json());
+        object.insert("date".to_string(), self.date.to_json());
+        object.insert("summary".to_string(), self.summary.to_json());
+        object.insert("normal_build_times".to_string(), self.normal_build_times.to_json());
+        object.insert("incremental_build_times".to_string(),
+                      self.incremental_build_times.to_json());
+        Json::Object(object)
+    }
+}
+
+impl ToJson for DivergenceReport {
+    fn to_json(&self) -> Json {
+        let mut object = BTreeMap::new();
+        object.insert("commit_id".to_string(), self.commit_id.to_json());
+        object.insert("stage".to_string(), self.stage.to_json());
+        object.insert("message".to_string(), self.message.to_json());
+        Json::Object(object)
+    }
+}
+
+pub fn compare_reports(args: &Args) {
+    assert!(args.cmd_compare_reports);
+
+    let baseline = match ReplayReport::load(Path::new(&args.arg_baseline)) {
+        Ok(report) => report,
+        Err(err) => error!("{}", err),
+    };
+    let current = match ReplayReport::load(Path::new(&args.arg_current)) {
+        Ok(report) => report,
+        Err(err) => error!("{}", err),
+    };
+
+    println!("normal build time: {:.2}s -> {:.2}s ({})",
+             baseline.normal_build_time,
+             current.normal_build_time,
+             percent_change(baseline.normal_build_time, current.normal_build_time));
+    println!("incremental build time: {:.2}s -> {:.2}s ({})",
+             baseline.incremental_build_time,
+             current.incremental_build_time,
+             percent_change(baseline.incremental_build_time, current.incremental_build_time));
+    println!("modules re-used: {:.1}% -> {:.1}% ({:+.1} points)",
+             baseline.reuse_percent(),
+             current.reuse_percent(),
+             current.reuse_percent() - baseline.reuse_percent());
+    println!("tests passed: {} of {} -> {} of {}",
+             baseline.tests_passed,
+             baseline.tests_total,
+             current.tests_passed,
+             current.tests_total);
+    println!("divergences: {} -> {}",
+             baseline.divergences.len(),
+             current.divergences.len());
+
+    let limit = baseline.incremental_build_time * (1.0 + args.flag_threshold / 100.0);
+    if baseline.incremental_build_time > 0.0 && current.incremental_build_time > limit {
+        error!("incremental build time regressed by more than {}%", args.flag_threshold)
+    }
+}
+
+fn percent_change(old: f64, new: f64) -> String {
+    if old == 0.0 {
+        "n/a".to_string()
+    } else {
+        format!("{:+.1}%", (new - old) / old * 100.0)
+    }
+}
+
+#[test]
+fn test_round_trip() {
+    let report = ReplayReport {
+        revisions: "master~2..master".to_string(),
+        rustc_version: "rustc 1.17.0-nightly (b1e31766d 2017-03-03)\n\
+                        commit-hash: b1e31766de45f18c4ea4feca7c1f1d3b0f9f7bbc"
+            .to_string(),
+        cargo_version: "cargo-0.18.0-nightly (a73a665 2017-02-14)".to_string(),
+        commits: vec![CommitReport {
+                          id: "abc1234".to_string(),
+                          oid: "abc1234f00dfeedbeef0000000000000000000000".to_string(),
+                          author: "Jane Doe <jane@example.com>".to_string(),
+                          date: "2017-03-14T09:16:53+01:00".to_string(),
+                          summary: "Fix \"things\"".to_string(),
+                          normal_build_times: vec![10.0, 12.5],
+                          incremental_build_times: vec![3.25, 0.5],
+                      }],
+        normal_build_time: 12.5,
+        incremental_build_time: 3.25,
+        modules_reused: 7,
+        modules_total: 10,
+        queries_reused: 90,
+        queries_total: 120,
+        tests_total: 4,
+        tests_passed: 3,
+        divergences: vec![DivergenceReport {
+                              commit_id: "abc1234".to_string(),
+                              stage: "compare incr/normal builds".to_string(),
+                              message: "incremental build differed".to_string(),
+                          }],
+    };
+
+    let json = Json::from_str(&report.to_json().to_string()).unwrap();
+    let loaded = ReplayReport::from_json(&json).unwrap();
+    assert_eq!(loaded.revisions, report.revisions);
+    assert_eq!(loaded.rustc_version, report.rustc_version);
+    assert_eq!(loaded.cargo_version, report.cargo_version);
+    assert_eq!(loaded.commits[0].summary, "Fix \"things\"");
+    assert_eq!(loaded.commits[0].author, "Jane Doe <jane@example.com>");
+    assert_eq!(loaded.commits[0].date, "2017-03-14T09:16:53+01:00");
+    assert_eq!(loaded.commits[0].incremental_build_times, vec![3.25, 0.5]);
+    assert_eq!(loaded.incremental_build_time, 3.25);
+    assert_eq!(loaded.modules_total, 10);
+    assert_eq!(loaded.queries_total, 120);
+    assert_eq!(loaded.divergences[0].stage, "compare incr/normal builds");
+    assert_eq!(loaded.reuse_percent(), 70.0);
+}
+
+#[test]
+fn test_to_csv() {
+    let commit = CommitReport {
+        id: "abc1234".to_string(),
+        summary: "Fix \"things\", again".to_string(),
+        normal_build_times: vec![10.0, 11.0],
+        incremental_build_times: vec![3.25, 0.5],
+        .. CommitReport::default()
+    };
+    let report = ReplayReport {
+        commits: vec![commit.clone(),
+                      CommitReport {
+                          id: "def5678".to_string(),
+                          summary: "Skip the normal build".to_string(),
+                          normal_build_times: vec![],
+                          .. commit.clone()
+                      }],
+        divergences: vec![DivergenceReport {
+                              commit_id: "def5678".to_string(),
+                              .. DivergenceReport::default()
+                          }],
+        .. ReplayReport::default()
+    };
+
+    assert_eq!(report.to_csv(),
+               "id,oid,author,date,summary,normal_build_time,incremental_build_time,divergences\n\
+                abc1234,,,,\"Fix \"\"things\"\", again\",10.500,3.250,0\n\
+                def5678,,,,Skip the normal build,,3.250,1\n");
+}
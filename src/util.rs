@@ -1,25 +1,46 @@
+use ctrlc;
 use git2::{Commit, Error as Git2Error, ErrorCode, Object, Repository, Status,
            STATUS_IGNORED, ResetType};
 use git2::build::CheckoutBuilder;
 use std::io;
 use std::io::prelude::*;
 use std::path::{Path, PathBuf};
-use std::process::{Command, Output, Stdio};
+use std::process::{self, Command, ExitStatus, Output, Stdio};
 use regex::Regex;
+use rustc_serialize::json::Json;
 use std::env;
 use std::str::FromStr;
 use std::fs::{self, File};
 use std::thread::{self, JoinHandle};
-use std::sync::Arc;
+use std::sync::Mutex;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::time::Duration;
+use std::fmt;
+use std::cmp;
 use toml;
 
+// Set by the Ctrl-C handler. Long-running loops check this at safe points so
+// that they can clean up before exiting.
+pub static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+// Whether `cargo_build` has already warned that it could not find rustc's
+// module re-use line; once per replay is enough.
+static WARNED_ABOUT_MODULE_REUSE: AtomicBool = AtomicBool::new(false);
+
+// Files in the user's checkout that we have modified, along with their
+// original contents. `error!` puts these back before exiting so that we don't
+// leave a mutated source tree behind.
+static MODIFIED_FILES: Mutex<Vec<(PathBuf, Vec<u8>)>> = Mutex::new(Vec::new());
+
 #[derive(Default)]
 pub struct CompilationStats {
     pub build_time: f64, // in seconds
     pub modules_reused: u64,
     pub modules_total: u64,
+    // Query results loaded from the cache, and all the ones looked up there.
+    // Only some versions of rustc report these.
+    pub queries_reused: u64,
+    pub queries_total: u64,
 }
 
 #[derive(Copy, Clone, Debug)]
@@ -34,20 +55,156 @@ pub struct BuildResult {
     pub success: bool,
     pub messages: Vec<Message>,
     pub raw_output: Output,
+    pub command: CargoCommand,
+    // Lines in which `-Z incremental-verify-ich` reported a mismatch.
+    pub unstable_fingerprints: Vec<String>,
+    // Set if the build was killed by a signal (see `exit_reason`), which
+    // points at the environment rather than at the compiler.
+    pub exit_reason: Option<String>,
 }
 
 impl PartialEq for BuildResult {
     fn eq(&self, other: &BuildResult) -> bool {
         self.success == other.success &&
-        self.messages == other.messages
+        self.messages.len() == other.messages.len() &&
+        self.messages.iter().zip(&other.messages).all(|(a, b)| a.same_diagnostic(b))
+    }
+}
+
+// Things cargo says when a build fails because of the network (e.g. while
+// downloading dependencies) rather than because of the code.
+const TRANSIENT_FAILURE_PATTERNS: &'static [&'static str] = &["spurious network error",
+                                                                "failed to download",
+                                                                "failed to update registry",
+                                                                "failed to fetch",
+                                                                "Couldn't resolve host",
+                                                                "Connection reset",
+                                                                "timed out"];
+
+/// Whether `result` is a failure that is worth retrying.
+pub fn is_transient_failure(result: &BuildResult) -> bool {
+    if result.success {
+        return false;
     }
+
+    let stderr = String::from_utf8_lossy(&result.raw_output.stderr);
+    TRANSIENT_FAILURE_PATTERNS.iter().any(|pattern| stderr.contains(pattern))
 }
 
 #[derive(PartialEq, Eq, Debug, Clone)]
 pub struct Message {
+    // "error", "warning", "note", ...
     pub kind: String,
+    // e.g. `E0308`, or the name of a lint. Only available from JSON output.
+    pub code: Option<String>,
     pub message: String,
+    // `file:line:column` of the primary span, if any.
     pub location: String,
+    // Notes and help attached to the diagnostic. Only available from JSON
+    // output.
+    pub children: Vec<Message>,
+}
+
+impl Message {
+    /// Whether two diagnostics should be considered the same when comparing
+    /// builds. The wording of a diagnostic is less stable than its error
+    /// code, so when both have a code we compare just that and the location.
+    pub fn same_diagnostic(&self, other: &Message) -> bool {
+        match (&self.code, &other.code) {
+            (&Some(ref code), &Some(ref other_code)) => {
+                code == other_code && self.location == other.location
+            }
+            _ => self == other,
+        }
+    }
+
+    fn from_json(diagnostic: &Json) -> Option<Message> {
+        let kind = match diagnostic.find("level").and_then(Json::as_string) {
+            Some(kind) => kind.to_string(),
+            None => return None,
+        };
+
+        let message = match diagnostic.find("message").and_then(Json::as_string) {
+            Some(message) => message.to_string(),
+            None => return None,
+        };
+
+        let code = diagnostic.find("code")
+            .and_then(|code| code.find("code"))
+            .and_then(Json::as_string)
+            .map(|code| code.to_string());
+
+        let location = diagnostic.find("spans")
+            .and_then(Json::as_array)
+            .and_then(|spans| {
+                spans.iter()
+                    .find(|span| span.find("is_primary").and_then(Json::as_boolean) == Some(true))
+            })
+            .map(|span| {
+                format!("{}:{}:{}",
+                        span.find("file_name").and_then(Json::as_string).unwrap_or("?"),
+                        span.find("line_start").and_then(Json::as_u64).unwrap_or(0),
+                        span.find("column_start").and_then(Json::as_u64).unwrap_or(0))
+            })
+            .unwrap_or(String::new());
+
+        let children = diagnostic.find("children")
+            .and_then(Json::as_array)
+            .map(|children| children.iter().filter_map(Message::from_json).collect())
+            .unwrap_or(vec![]);
+
+        Some(Message {
+            kind: kind,
+            code: code,
+            message: message,
+            location: location,
+            children: children,
+        })
+    }
+}
+
+// Extracts the compiler diagnostics from the output of cargo's
+// `--message-format=json`. Returns `None` if the output is not JSON at all
+// (e.g. because this version of cargo does not support it).
+fn parse_json_messages(stdout: &[u8]) -> Option<Vec<Message>> {
+    let mut saw_json = false;
+    let mut messages = vec![];
+
+    for line in String::from_utf8_lossy(stdout).lines() {
+        let json = match Json::from_str(line) {
+            Ok(json) => json,
+            Err(_) => continue,
+        };
+        saw_json = true;
+
+        if json.find("reason").and_then(Json::as_string) == Some("compiler-message") {
+            if let Some(message) = json.find("message").and_then(Message::from_json) {
+                messages.push(message);
+            }
+        }
+    }
+
+    if saw_json {
+        Some(messages)
+    } else {
+        None
+    }
+}
+
+// Turns a line of cargo's `--message-format=json` output back into what
+// cargo would have shown a human. Diagnostics are rendered as usual, other
+// JSON messages (e.g. about artifacts) are dropped, and anything that isn't
+// JSON is passed through unchanged.
+fn render_output_line(line: &str) -> Option<String> {
+    let json = match Json::from_str(line) {
+        Ok(json) => json,
+        Err(_) => return Some(line.to_string()),
+    };
+
+    json.find("message")
+        .and_then(|message| message.find("rendered"))
+        .and_then(Json::as_string)
+        .map(|rendered| rendered.trim_right().to_string())
 }
 
 #[derive(Eq, Debug, Clone)]
@@ -55,6 +212,7 @@ pub struct TestResult {
     pub success: bool,
     pub results: Vec<TestCaseResult>,
     pub raw_output: Output,
+    pub command: CargoCommand,
 }
 
 impl PartialEq for TestResult {
@@ -64,10 +222,35 @@ impl PartialEq for TestResult {
     }
 }
 
-#[derive(PartialEq, Eq, PartialOrd, Ord, Debug, Clone)]
+#[derive(Debug, Clone)]
 pub struct TestCaseResult {
     pub test_name: String,
     pub status: String,
+    // What the test printed when it panicked (the message, its location and
+    // any backtrace), if it did. Only available with libtest.
+    pub panic_message: Option<String>,
+}
+
+// The panic message is left out of comparisons: it may contain addresses
+// and the like, and a different status is what we are looking for anyway.
+impl PartialEq for TestCaseResult {
+    fn eq(&self, other: &TestCaseResult) -> bool {
+        self.test_name == other.test_name && self.status == other.status
+    }
+}
+
+impl Eq for TestCaseResult {}
+
+impl PartialOrd for TestCaseResult {
+    fn partial_cmp(&self, other: &TestCaseResult) -> Option<cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for TestCaseResult {
+    fn cmp(&self, other: &TestCaseResult) -> cmp::Ordering {
+        (&self.test_name, &self.status).cmp(&(&other.test_name, &other.status))
+    }
 }
 
 fn create_file(path: &Path) -> File {
@@ -77,7 +260,7 @@ fn create_file(path: &Path) -> File {
     }
 }
 
-fn write_file(path: &Path, content: &[u8]) {
+pub fn write_file(path: &Path, content: &[u8]) {
     let mut file = create_file(path);
     match file.write_all(content) {
         Ok(()) => (),
@@ -85,10 +268,19 @@ fn write_file(path: &Path, content: &[u8]) {
     }
 }
 
+// Creates the directory `path` if it doesn't exist yet, and returns its
+// canonical path. An existing directory must be empty, so that we don't mix
+// our results with whatever is in there.
 pub fn absolute_dir_path(path: &Path) -> PathBuf {
-    assert!(!path.exists(),
-            "absolute_dir_path: path {} already exists",
-            path.display());
+    if let Err(err) = check_empty_or_missing_dir(path) {
+        error!("{}", err)
+    }
+    reused_dir_path(path)
+}
+
+// Like `absolute_dir_path`, but keeps the contents of an existing directory,
+// e.g. for `--resume`.
+pub fn reused_dir_path(path: &Path) -> PathBuf {
     make_dir(&path);
     match fs::canonicalize(&path) {
         Ok(i) => i,
@@ -96,6 +288,25 @@ pub fn absolute_dir_path(path: &Path) -> PathBuf {
     }
 }
 
+fn check_empty_or_missing_dir(path: &Path) -> Result<(), String> {
+    if !path.exists() {
+        return Ok(());
+    }
+    if !path.is_dir() {
+        return Err(format!("`{}` is not a directory", path.display()));
+    }
+    match fs::read_dir(path) {
+        Ok(mut entries) => {
+            if entries.next().is_some() {
+                Err(format!("`{}` already exists and is not empty", path.display()))
+            } else {
+                Ok(())
+            }
+        }
+        Err(err) => Err(format!("failed to read `{}`: {}", path.display(), err)),
+    }
+}
+
 pub fn remove_dir(path: &Path) {
     if path.exists() {
         if !path.is_dir() {
@@ -109,11 +320,104 @@ pub fn remove_dir(path: &Path) {
     }
 }
 
-pub fn save_output(output_dir: &Path, output: &Output) {
-    write_file(&output_dir.join("status"),
-               format!("{}", output.status).as_bytes());
-    write_file(&output_dir.join("stdout"), &output.stdout);
-    write_file(&output_dir.join("stderr"), &output.stderr);
+pub fn remember_original_contents(path: &Path, contents: &[u8]) {
+    MODIFIED_FILES.lock().unwrap().push((path.to_path_buf(), contents.to_vec()));
+}
+
+pub fn modified_files() -> Vec<PathBuf> {
+    MODIFIED_FILES.lock().unwrap().iter().map(|&(ref path, _)| path.clone()).collect()
+}
+
+// Called once the modified files have been restored by other means (e.g. by
+// resetting the repository).
+pub fn forget_modified_files() {
+    MODIFIED_FILES.lock().unwrap().clear();
+}
+
+// This is called from `error!`, so it must not call `error!` itself.
+pub fn restore_modified_files() {
+    let files = match MODIFIED_FILES.lock() {
+        Ok(mut files) => files.drain(..).collect::<Vec<_>>(),
+        Err(_) => return,
+    };
+
+    for (path, contents) in files {
+        let result = File::create(&path).and_then(|mut file| file.write_all(&contents));
+        if let Err(err) = result {
+            let stderr = io::stderr();
+            let mut stderr = stderr.lock();
+            writeln!(stderr, "failed to restore `{}`: {}", path.display(), err).unwrap();
+        }
+    }
+}
+
+pub fn save_output(output_dir: &Path, output: &Output, strip_ansi: bool) {
+    let mut status = format!("{}\n", output.status);
+    if let Some(reason) = exit_reason(output) {
+        status.push_str(&format!("{}\n", reason));
+    }
+    write_file(&output_dir.join("status"), status.as_bytes());
+    if strip_ansi {
+        write_file(&output_dir.join("stdout"), &strip_ansi_codes(&output.stdout));
+        write_file(&output_dir.join("stderr"), &strip_ansi_codes(&output.stderr));
+    } else {
+        write_file(&output_dir.join("stdout"), &output.stdout);
+        write_file(&output_dir.join("stderr"), &output.stderr);
+    }
+}
+
+// Removes ANSI escape sequences, such as the color codes in `\x1b[1;31m`,
+// from `bytes`.
+fn strip_ansi_codes(bytes: &[u8]) -> Vec<u8> {
+    let mut stripped = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] != 0x1b {
+            stripped.push(bytes[i]);
+            i += 1;
+            continue;
+        }
+
+        i += 1;
+        match bytes.get(i) {
+            // CSI: `ESC [`, parameters and intermediates, then a final byte
+            // in `@`..`~`.
+            Some(&b'[') => {
+                i += 1;
+                while i < bytes.len() && !(0x40 <= bytes[i] && bytes[i] <= 0x7e) {
+                    i += 1;
+                }
+                i += 1;
+            }
+            // OSC, e.g. a hyperlink: `ESC ]`, ended by BEL or `ESC \`.
+            Some(&b']') => {
+                i += 1;
+                while i < bytes.len() && bytes[i] != 0x07 &&
+                      !(bytes[i] == 0x1b && bytes.get(i + 1) == Some(&b'\\')) {
+                    i += 1;
+                }
+                i += if bytes.get(i) == Some(&0x1b) { 2 } else { 1 };
+            }
+            // Any other escape is two bytes long.
+            Some(_) => i += 1,
+            None => {}
+        }
+    }
+    stripped
+}
+
+// Writes a `repro.sh` into `output_dir` that checks out `commit` and re-runs
+// `cmd`, so that each commit directory is a self-contained reproduction.
+pub fn save_repro_script(output_dir: &Path, commit: &Commit, cmd: &CargoCommand) {
+    let script = format!("#!/bin/sh\n\
+                          set -e\n\
+                          cd {}\n\
+                          git checkout {}\n\
+                          {}\n",
+                         shell_quote(&cmd.dir.to_string_lossy()),
+                         commit.id(),
+                         cmd);
+    write_file(&output_dir.join("repro.sh"), script.as_bytes());
 }
 
 pub fn print_output(output: &Output) {
@@ -121,11 +425,18 @@ pub fn print_output(output: &Output) {
     println!("EXIT STATUS:");
     println!("=============");
     println!("{}", output.status);
+    if let Some(reason) = exit_reason(output) {
+        println!("{}", reason);
+    }
     println!("");
 
     println!("STANDARD OUT");
     println!("============");
-    println!("{}", into_string(output.stdout.clone()));
+    for line in into_string(output.stdout.clone()).lines() {
+        if let Some(line) = render_output_line(line) {
+            println!("{}", line);
+        }
+    }
     println!("");
 
     println!("STANDARD ERR");
@@ -140,21 +451,26 @@ pub fn make_dir(path: &Path) {
     }
 }
 
+// Tests may print anything, and some locales make tools print non-UTF-8
+// bytes, so we replace what we cannot decode. This is only for scanning and
+// printing; the raw output we save and compare keeps the original bytes.
 pub fn into_string(bytes: Vec<u8>) -> String {
     match String::from_utf8(bytes) {
         Ok(v) => v,
-        Err(_) => error!("unable to parse output as utf-8"),
+        Err(err) => String::from_utf8_lossy(err.as_bytes()).into_owned(),
     }
 }
 
-pub fn open_repo(cargo_path: &Path) -> Result<Repository, Git2Error> {
+pub fn open_repo(cargo_path: &Path, quiet: bool) -> Result<Repository, Git2Error> {
     let mut git_path = cargo_path;
 
     loop {
         if git_path.is_dir() {
             match Repository::open(git_path) {
                 Ok(r) => {
-                    println!("repo at {}", git_path.display());
+                    if !quiet {
+                        println!("repo at {}", git_path.display());
+                    }
                     return Ok(r);
                 }
                 Err(err) => {
@@ -175,6 +491,44 @@ pub fn open_repo(cargo_path: &Path) -> Result<Repository, Git2Error> {
     }
 }
 
+/// Errors if `work_dir` (which need not exist yet) is inside the working
+/// directory of `repo`. Everything we put there would show up as untracked
+/// files and interfere with checking out and building the commits. `option`
+/// is the command-line option that sets `work_dir`.
+pub fn check_work_dir_outside_repo(repo: &Repository, work_dir: &Path, option: &str) {
+    let repo_dir = match repo.workdir().map(fs::canonicalize) {
+        Some(Ok(repo_dir)) => repo_dir,
+        Some(Err(err)) => error!("failed to canonicalize repository directory: {}", err),
+        // A bare repository has no working directory to interfere with.
+        None => return,
+    };
+
+    let work_dir = match env::current_dir() {
+        Ok(current_dir) => canonicalize_existing_prefix(&current_dir.join(work_dir)),
+        Err(err) => error!("failed to get current directory: {}", err),
+    };
+
+    if work_dir.starts_with(&repo_dir) {
+        error!("directory `{}` is inside the repository at `{}`; \
+                please use `{}` to put it somewhere else",
+               work_dir.display(),
+               repo_dir.display(),
+               option);
+    }
+}
+
+// Canonicalizes the longest prefix of `path` that exists and appends the rest.
+fn canonicalize_existing_prefix(path: &Path) -> PathBuf {
+    if let Ok(canonical) = fs::canonicalize(path) {
+        return canonical;
+    }
+
+    match (path.parent(), path.file_name()) {
+        (Some(parent), Some(file_name)) => canonicalize_existing_prefix(parent).join(file_name),
+        _ => path.to_path_buf(),
+    }
+}
+
 pub fn check_clean(repo: &Repository) {
     let statuses = match repo.statuses(None) {
         Ok(s) => s,
@@ -198,6 +552,133 @@ pub fn check_clean(repo: &Repository) {
     }
 }
 
+/// Pins this process, and with it every process we spawn, to a single CPU
+/// (the first one we are allowed to run on). Returns false if that failed.
+#[cfg(target_os = "linux")]
+pub fn pin_to_single_cpu() -> bool {
+    use libc;
+    use std::mem;
+
+    unsafe {
+        let mut allowed: libc::cpu_set_t = mem::zeroed();
+        if libc::sched_getaffinity(0, mem::size_of::<libc::cpu_set_t>(), &mut allowed) != 0 {
+            return false;
+        }
+
+        let first_allowed = (0..libc::CPU_SETSIZE as usize)
+            .find(|&cpu| libc::CPU_ISSET(cpu, &allowed));
+        let cpu = match first_allowed {
+            Some(cpu) => cpu,
+            None => return false,
+        };
+
+        let mut pinned: libc::cpu_set_t = mem::zeroed();
+        libc::CPU_SET(cpu, &mut pinned);
+        libc::sched_setaffinity(0, mem::size_of::<libc::cpu_set_t>(), &pinned) == 0
+    }
+}
+
+/// Pinning is only implemented on Linux.
+#[cfg(not(target_os = "linux"))]
+pub fn pin_to_single_cpu() -> bool {
+    false
+}
+
+/// Mounts a tmpfs of `size` (anything `mount -o size=` accepts) on `path`.
+#[cfg(target_os = "linux")]
+pub fn mount_tmpfs(path: &Path, size: &str) -> Result<(), String> {
+    let mut cmd = Command::new("mount");
+    cmd.args(&["-t", "tmpfs", "-o"]).arg(format!("size={}", size)).arg("tmpfs").arg(path);
+    run_mount_command(cmd)
+}
+
+/// tmpfs mounts are only implemented on Linux.
+#[cfg(not(target_os = "linux"))]
+pub fn mount_tmpfs(_path: &Path, _size: &str) -> Result<(), String> {
+    Err("mounting a tmpfs is only supported on Linux".to_string())
+}
+
+/// Whether something (e.g. the tmpfs of an earlier replay) is mounted on
+/// `path`.
+#[cfg(target_os = "linux")]
+pub fn is_mount_point(path: &Path) -> bool {
+    let path = match path.canonicalize() {
+        Ok(path) => path,
+        Err(_) => return false,
+    };
+    let mut mounts = String::new();
+    match File::open("/proc/self/mounts").and_then(|mut file| file.read_to_string(&mut mounts)) {
+        Ok(_) => mount_points(&mounts).contains(&path),
+        Err(_) => false,
+    }
+}
+
+/// We only mount anything on Linux.
+#[cfg(not(target_os = "linux"))]
+pub fn is_mount_point(_path: &Path) -> bool {
+    false
+}
+
+// The mount points in the contents of `/proc/self/mounts`, which escapes
+// spaces and the like in octal, e.g. `\040`.
+#[cfg_attr(not(target_os = "linux"), allow(dead_code))]
+fn mount_points(mounts: &str) -> Vec<PathBuf> {
+    mounts.lines()
+        .filter_map(|line| line.split(' ').nth(1))
+        .map(|mount_point| {
+            let mut unescaped = String::new();
+            let mut rest = mount_point;
+            while let Some(position) = rest.find('\\') {
+                unescaped.push_str(&rest[..position]);
+                let code = rest.get(position + 1..position + 4)
+                    .and_then(|digits| u8::from_str_radix(digits, 8).ok());
+                match code {
+                    Some(code) => {
+                        unescaped.push(code as char);
+                        rest = &rest[position + 4..];
+                    }
+                    None => {
+                        unescaped.push('\\');
+                        rest = &rest[position + 1..];
+                    }
+                }
+            }
+            unescaped.push_str(rest);
+            PathBuf::from(unescaped)
+        })
+        .collect()
+}
+
+pub fn unmount(path: &Path) -> Result<(), String> {
+    let mut cmd = Command::new("umount");
+    cmd.arg(path);
+    run_mount_command(cmd)
+}
+
+fn run_mount_command(mut cmd: Command) -> Result<(), String> {
+    debug!("{:?}", cmd);
+
+    let output = try!(cmd.output().map_err(|err| format!("could not execute {:?}: {}", cmd, err)));
+
+    if !output.status.success() {
+        return Err(format!("{:?} failed: {}",
+                           cmd,
+                           String::from_utf8_lossy(&output.stderr).trim()));
+    }
+
+    Ok(())
+}
+
+pub fn install_interrupt_handler() {
+    if let Err(err) = ctrlc::set_handler(|| INTERRUPTED.store(true, Ordering::SeqCst)) {
+        error!("failed to install Ctrl-C handler: {}", err);
+    }
+}
+
+pub fn was_interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
+
 pub fn reset_repo(repo: &Repository, commit: &Commit) {
     let mut cb = CheckoutBuilder::new();
     if let Err(err) = repo.reset(commit.as_object(),
@@ -207,25 +688,21 @@ pub fn reset_repo(repo: &Repository, commit: &Commit) {
     }
 }
 
-pub fn checkout_commit(repo: &Repository, commit: &Commit) {
+pub fn checkout_commit(repo: &Repository, commit: &Commit) -> Result<(), String> {
     let mut cb = CheckoutBuilder::new();
-    match repo.checkout_tree(commit.as_object(), Some(&mut cb)) {
-        Ok(()) => {}
-        Err(err) => {
-            error!("encountered error checking out `{}`: {}",
-                   short_id(commit),
-                   err)
-        }
+    if let Err(err) = repo.checkout_tree(commit.as_object(), Some(&mut cb)) {
+        return Err(format!("encountered error checking out `{}`: {}",
+                           short_id(commit),
+                           err));
     }
 
-    match repo.set_head_detached(commit.id()) {
-        Ok(()) => {}
-        Err(err) => {
-            error!("encountered error adjusting head to `{}`: {}",
-                   short_id(commit),
-                   err)
-        }
+    if let Err(err) = repo.set_head_detached(commit.id()) {
+        return Err(format!("encountered error adjusting head to `{}`: {}",
+                           short_id(commit),
+                           err));
     }
+
+    Ok(())
 }
 
 pub trait AsObject<'repo> {
@@ -254,6 +731,84 @@ pub fn short_id<'repo, T>(obj: &T) -> String
     }
 }
 
+// Returns the first line of the commit message, truncated to `max_chars`
+// characters. Messages that are not valid UTF-8 are converted lossily.
+pub fn commit_summary(commit: &Commit, max_chars: usize) -> String {
+    let summary = match commit.summary() {
+        Some(summary) => summary.to_string(),
+        None => {
+            let message = String::from_utf8_lossy(commit.message_bytes());
+            message.lines().next().unwrap_or("").to_string()
+        }
+    };
+
+    let summary = summary.trim();
+    if summary.is_empty() {
+        return "(no commit message)".to_string();
+    }
+
+    truncate_chars(summary, max_chars)
+}
+
+/// Shortens `text` to at most `max_chars` characters, ending it with `...`
+/// if anything was cut off.
+pub fn truncate_chars(text: &str, max_chars: usize) -> String {
+    if text.chars().count() <= max_chars {
+        text.to_string()
+    } else if max_chars < 3 {
+        text.chars().take(max_chars).collect()
+    } else {
+        let truncated: String = text.chars().take(max_chars - 3).collect();
+        format!("{}...", truncated)
+    }
+}
+
+// The author of `commit` as `Name <email>`.
+pub fn commit_author(commit: &Commit) -> String {
+    let author = commit.author();
+    format!("{} <{}>",
+            String::from_utf8_lossy(author.name_bytes()),
+            String::from_utf8_lossy(author.email_bytes()))
+}
+
+// When `commit` was committed, in the committer's time zone, e.g.
+// `2017-03-14T09:26:53+01:00`.
+pub fn commit_date(commit: &Commit) -> String {
+    let time = commit.time();
+    format_git_time(time.seconds(), time.offset_minutes())
+}
+
+fn format_git_time(seconds: i64, offset_minutes: i32) -> String {
+    let local = seconds + offset_minutes as i64 * 60;
+    let days = if local >= 0 { local / 86400 } else { (local - 86399) / 86400 };
+    let secs_of_day = local - days * 86400;
+
+    // From days since 1970-01-01 to a civil date, see
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days + 719468;
+    let era = (if z >= 0 { z } else { z - 146096 }) / 146097;
+    let day_of_era = z - era * 146097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 -
+                       day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    let offset_sign = if offset_minutes < 0 { '-' } else { '+' };
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}{}{:02}:{:02}",
+            year,
+            month,
+            day,
+            secs_of_day / 3600,
+            secs_of_day / 60 % 60,
+            secs_of_day % 60,
+            offset_sign,
+            offset_minutes.abs() / 60,
+            offset_minutes.abs() % 60)
+}
+
 pub fn commit_or_error<'obj, 'repo>(obj: Object<'repo>) -> Commit<'repo> {
     match obj.into_commit() {
         Ok(commit) => commit,
@@ -261,50 +816,481 @@ pub fn commit_or_error<'obj, 'repo>(obj: Object<'repo>) -> Commit<'repo> {
     }
 }
 
+// Environment variables that `--reproducible` removes from cargo's
+// environment, since they can change the produced artifacts without changing
+// the sources.
+pub const REPRODUCIBLE_SCRUBBED_ENV: &'static [&'static str] = &[
+    "RUSTC_WORKSPACE_WRAPPER",
+    "CARGO_BUILD_RUSTC_WORKSPACE_WRAPPER",
+    "CARGO_MAKEFLAGS",
+    "MAKEFLAGS",
+    "MFLAGS",
+];
+
+// Environment variables that `--reproducible` pins to a fixed value.
+pub const REPRODUCIBLE_FIXED_ENV: &'static [(&'static str, &'static str)] = &[
+    ("SOURCE_DATE_EPOCH", "0"),
+    ("TZ", "UTC"),
+    ("LC_ALL", "C"),
+];
+
+// With `--dump-depgraph`, incremental builds write `dep_graph.dot` and
+// `dep_graph.txt` into their commit dir.
+pub const DEP_GRAPH_FILE_STEM: &'static str = "dep_graph";
+
+// Environment variables through which cargo can be told to run rustc via a
+// wrapper like sccache.
+const WRAPPER_ENV: &'static [&'static str] = &["RUSTC_WRAPPER", "CARGO_BUILD_RUSTC_WRAPPER"];
+
+/// Which cargo command `cargo_build` runs.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum BuildDriver {
+    Build,
+    Doc,
+    Clippy,
+    // Only for the normal build of `--reference check`.
+    Check,
+}
+
+impl Default for BuildDriver {
+    fn default() -> BuildDriver {
+        BuildDriver::Build
+    }
+}
+
+impl BuildDriver {
+    // The cargo subcommand to run. With `current_project`, this must be one
+    // that passes the arguments after `--` to the compiler for the current
+    // project only.
+    fn subcommand(&self, current_project: bool) -> &'static str {
+        match (*self, current_project) {
+            (BuildDriver::Build, false) => "build",
+            (BuildDriver::Build, true) => "rustc",
+            (BuildDriver::Doc, false) => "doc",
+            (BuildDriver::Doc, true) => "rustdoc",
+            // `cargo clippy` hands the arguments after `--` to clippy-driver
+            // for the current project's crates only.
+            (BuildDriver::Clippy, _) => "clippy",
+            // Never used for an incremental build, so `current_project` is
+            // never set.
+            (BuildDriver::Check, _) => "check",
+        }
+    }
+}
+
+/// Settings that apply to every `cargo build`/`cargo test` we run.
+#[derive(Clone, Debug, Default)]
+pub struct CargoOptions {
+    /// Pin timestamps and scrub volatile environment variables so that
+    /// artifacts can be compared byte-for-byte.
+    pub reproducible: bool,
+    /// Unset `RUSTC_WRAPPER` (e.g. sccache) for the spawned cargo.
+    pub no_wrapper: bool,
+    /// Have incremental builds dump their dep-graph into the commit dir.
+    pub dump_depgraph: bool,
+    /// What to run in place of `cargo build`.
+    pub driver: BuildDriver,
+    /// The target triple to build for, if not the host.
+    pub target: Option<String>,
+    /// The `--bin`, `--test` and `--example` targets to restrict all builds
+    /// and test runs to, e.g. `("--bin", "server")`.
+    pub selected_targets: Vec<(&'static str, String)>,
+    /// Build all targets, not just the default ones. Test runs are left
+    /// alone, since `cargo test --all-targets` would skip the doc tests.
+    pub all_targets: bool,
+    /// Build with `--jobs 1`, for more stable timings.
+    pub single_job: bool,
+    /// Extra flags for every rustc invocation, on top of `RUSTFLAGS`.
+    pub rustflags: Option<String>,
+    /// Environment variables to set for every cargo process, from `--env`.
+    pub env: Vec<(String, String)>,
+    /// A private `CARGO_HOME`, so that the registry and git checkouts of the
+    /// dependencies start out empty.
+    pub cargo_home: Option<PathBuf>,
+    /// Remove ANSI escape sequences from the output we save to files. The
+    /// output we compare and print is left alone.
+    pub strip_ansi: bool,
+    /// Only count the module re-use of this crate, from `--only-crate`.
+    pub only_crate: Option<String>,
+    /// Have incremental builds write rustc's self-profile here.
+    pub self_profile_dir: Option<PathBuf>,
+}
+
+impl CargoOptions {
+    pub fn apply(&self, cmd: &mut CargoCommand) {
+        if self.no_wrapper {
+            for key in WRAPPER_ENV {
+                cmd.env_remove(key);
+            }
+        }
+
+        if self.reproducible {
+            for key in REPRODUCIBLE_SCRUBBED_ENV {
+                cmd.env_remove(key);
+            }
+            for &(key, value) in REPRODUCIBLE_FIXED_ENV {
+                cmd.env(key, value);
+            }
+        }
+
+        for &(ref key, ref value) in &self.env {
+            cmd.env(key, value);
+        }
+
+        if let Some(ref cargo_home) = self.cargo_home {
+            cmd.env("CARGO_HOME", &cargo_home.display().to_string());
+        }
+    }
+
+    // Adds `--target`, if we have one. Unlike the rest of the options this
+    // has to come after the subcommand.
+    pub fn apply_target(&self, cmd: &mut CargoCommand) {
+        if let Some(ref target) = self.target {
+            cmd.arg("--target").arg(target);
+        }
+    }
+
+    // Adds the options that go after the subcommand of a `cargo build` or
+    // `cargo test`.
+    pub fn apply_build_args(&self, cmd: &mut CargoCommand) {
+        self.apply_target(cmd);
+        for &(selector, ref name) in &self.selected_targets {
+            cmd.arg(selector).arg(name);
+        }
+        if self.single_job {
+            cmd.arg("--jobs").arg("1");
+        }
+    }
+
+    // The arguments that only builds get, not test runs.
+    fn apply_build_only_args(&self, cmd: &mut CargoCommand) {
+        if self.all_targets {
+            cmd.arg("--all-targets");
+        }
+    }
+
+    // Sets `RUSTFLAGS` to `flags`, followed by the `RUSTFLAGS` from our own
+    // environment and then `--rustflags`. If there are neither `flags` nor
+    // `--rustflags`, the spawned cargo just inherits our `RUSTFLAGS`.
+    pub fn apply_rustflags(&self, cmd: &mut CargoCommand, flags: &[String]) {
+        if flags.is_empty() && self.rustflags.is_none() {
+            return;
+        }
+
+        let mut rustflags = flags.to_vec();
+        rustflags.extend(env::var("RUSTFLAGS").ok());
+        rustflags.extend(self.rustflags.clone());
+        rustflags.retain(|flags| !flags.is_empty());
+        cmd.env("RUSTFLAGS", rustflags.join(" "));
+    }
+
+    // The wrapper the spawned cargo will use, if any.
+    pub fn active_wrapper(&self) -> Option<String> {
+        if self.no_wrapper {
+            return None;
+        }
+
+        WRAPPER_ENV.iter()
+                   .filter_map(|key| env::var(key).ok())
+                   .find(|wrapper| !wrapper.is_empty())
+    }
+
+    pub fn warn_about_wrapper(&self) {
+        if let Some(wrapper) = self.active_wrapper() {
+            println!("warning: rustc wrapper `{}` is active; it may interfere with \
+                      incremental compilation (use `--no-wrapper` to disable it)",
+                     wrapper);
+        }
+    }
+
+    pub fn print_summary(&self) {
+        match self.driver {
+            BuildDriver::Build => {}
+            BuildDriver::Doc => println!("running `cargo doc` instead of `cargo build`"),
+            BuildDriver::Clippy => println!("running `cargo clippy` instead of `cargo build`"),
+            BuildDriver::Check => println!("running `cargo check` instead of `cargo build`"),
+        }
+
+        match self.active_wrapper() {
+            Some(wrapper) => println!("using rustc wrapper `{}`", wrapper),
+            None if self.no_wrapper => println!("rustc wrapper disabled by `--no-wrapper`"),
+            None => {}
+        }
+
+        for &(selector, ref name) in &self.selected_targets {
+            println!("only building and testing `{} {}`", selector, name);
+        }
+
+        if self.all_targets {
+            println!("building all targets (`--all-targets`)");
+        }
+
+        if let Some(ref rustflags) = self.rustflags {
+            println!("passing `{}` to every rustc invocation", rustflags);
+        }
+
+        for &(ref key, ref value) in &self.env {
+            println!("setting {}={} for cargo", key, value);
+        }
+
+        if let Some(ref self_profile_dir) = self.self_profile_dir {
+            println!("writing rustc's self-profile of incremental builds to `{}`",
+                     self_profile_dir.display());
+        }
+
+        if let Some(ref only_crate) = self.only_crate {
+            println!("only counting the module re-use of crate `{}`", only_crate);
+        }
+
+        if let Some(ref cargo_home) = self.cargo_home {
+            println!("using `{}` as CARGO_HOME; all dependencies will be downloaded again",
+                     cargo_home.display());
+        }
+
+        if self.reproducible {
+            println!("reproducible mode: removing {} from the environment",
+                     REPRODUCIBLE_SCRUBBED_ENV.join(", "));
+            for &(key, value) in REPRODUCIBLE_FIXED_ENV {
+                println!("reproducible mode: setting {}={}", key, value);
+            }
+        }
+    }
+}
+
+/// A `cargo` invocation. We keep track of the environment variables and
+/// arguments ourselves, rather than just handing them to a `Command`, so
+/// that we can show the user exactly what was run.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct CargoCommand {
+    pub dir: PathBuf,
+    pub env: Vec<(String, String)>,
+    pub env_remove: Vec<String>,
+    pub args: Vec<String>,
+}
+
+impl CargoCommand {
+    pub fn new(cargo_dir: &Path, target_dir: &Path) -> CargoCommand {
+        let mut cmd = CargoCommand {
+            dir: cargo_dir.to_path_buf(),
+            env: vec![],
+            env_remove: vec![],
+            args: vec![],
+        };
+        cmd.env("CARGO_TARGET_DIR", target_dir.display());
+        cmd
+    }
+
+    pub fn arg<S: ToString>(&mut self, arg: S) -> &mut CargoCommand {
+        self.args.push(arg.to_string());
+        self
+    }
+
+    pub fn env<S: ToString>(&mut self, key: &str, value: S) -> &mut CargoCommand {
+        self.env.push((key.to_string(), value.to_string()));
+        self
+    }
+
+    pub fn env_remove(&mut self, key: &str) -> &mut CargoCommand {
+        self.env_remove.push(key.to_string());
+        self
+    }
+
+    pub fn to_command(&self) -> Command {
+        let mut cmd = Command::new("cargo");
+        cmd.current_dir(&self.dir);
+        for key in &self.env_remove {
+            cmd.env_remove(key);
+        }
+        for &(ref key, ref value) in &self.env {
+            cmd.env(key, value);
+        }
+        cmd.args(&self.args);
+        cmd
+    }
+}
+
+impl fmt::Display for CargoCommand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if !self.env_remove.is_empty() {
+            try!(write!(f, "env"));
+            for key in &self.env_remove {
+                try!(write!(f, " -u {}", key));
+            }
+            try!(write!(f, " "));
+        }
+        for &(ref key, ref value) in &self.env {
+            try!(write!(f, "{}={} ", key, shell_quote(value)));
+        }
+        try!(write!(f, "cargo"));
+        for arg in &self.args {
+            try!(write!(f, " {}", shell_quote(arg)));
+        }
+        Ok(())
+    }
+}
+
+pub fn print_command(cmd: &CargoCommand) {
+    println!("running `{}` in `{}`", cmd, cmd.dir.display());
+}
+
+pub fn shell_quote(s: &str) -> String {
+    let is_plain = |c: char| c.is_alphanumeric() || "-_./=:,+@%".contains(c);
+    if !s.is_empty() && s.chars().all(is_plain) {
+        s.to_string()
+    } else {
+        format!("'{}'", s.replace("'", "'\\''"))
+    }
+}
+
+// Adds up the modules re-used and the total modules from rustc's
+// `-Z incremental-info` output, one line per crate. Over time rustc has
+// printed `incremental: re-using 3 out of 4 modules`, `[incremental] Re-using
+// 3 out of 4 modules` and `... out of 4 CGUs`. Returns `None` if there is no
+// such line at all.
+//
+// With `only_crate`, only the lines of that crate count. The output doesn't
+// say which crate a line is about, so we take the one cargo started last.
+// With parallel jobs that may be the wrong one; `--single-core` avoids that.
+fn parse_module_reuse(output: &str, only_crate: Option<&str>) -> Option<(u64, u64)> {
+    let reusing_regex =
+        Regex::new(r"(?i)^(?:incremental:|\[incremental\]) re-using (\d+) out of (\d+) (?:modules|CGUs|codegen units)\.?\s*$")
+            .unwrap();
+    sum_crate_lines(output, &reusing_regex, only_crate)
+}
+
+// Like `parse_module_reuse`, for the query cache lines of rustc's
+// `-Z incremental-info` output, e.g. `[incremental] query cache: 12 hits, 3
+// misses`. Returns the hits and the total lookups.
+fn parse_query_reuse(output: &str, only_crate: Option<&str>) -> Option<(u64, u64)> {
+    let query_cache_regex =
+        Regex::new(r"(?i)^(?:incremental:|\[incremental\]) query cache: (\d+) hits?, (\d+) miss(?:es)?\.?\s*$")
+            .unwrap();
+    sum_crate_lines(output, &query_cache_regex, only_crate)
+        .map(|(hits, misses)| (hits, hits + misses))
+}
+
+// Adds up the two numbers that `regex` captures in each line of `output`
+// it matches, for `parse_module_reuse` and `parse_query_reuse`.
+fn sum_crate_lines(output: &str, regex: &Regex, only_crate: Option<&str>) -> Option<(u64, u64)> {
+    let running_regex = Regex::new(r"^\s*Running `.*?--crate-name (\S+)").unwrap();
+    let mut current_crate = None;
+    let mut result = None;
+    for line in output.lines() {
+        if let Some(captures) = running_regex.captures(line) {
+            current_crate = captures.at(1);
+        } else if let Some(captures) = regex.captures(line) {
+            if only_crate.map_or(false, |name| current_crate != Some(name)) {
+                continue;
+            }
+            let (first, second) = result.unwrap_or((0, 0));
+            result = Some((first + u64::from_str(captures.at(1).unwrap()).unwrap(),
+                           second + u64::from_str(captures.at(2).unwrap()).unwrap()));
+        }
+    }
+    result
+}
+
 pub fn cargo_build(cargo_dir: &Path,
                    commit_dir: &Path,
                    target_dir: &Path,
                    incremental: IncrementalOptions,
                    stats: &mut CompilationStats,
                    should_save_output: bool,
-                   stream_output: bool)
+                   stream_output: bool,
+                   verbose: bool,
+                   options: &CargoOptions)
                    -> BuildResult {
-    let mut cmd = Command::new("cargo");
-    cmd.current_dir(&cargo_dir);
-    cmd.env("CARGO_TARGET_DIR", target_dir);
+    let mut cargo_cmd = CargoCommand::new(cargo_dir, target_dir);
+    options.apply(&mut cargo_cmd);
 
     // We are setting rustc's incremental flags manually, so let's
     // make cargo not interfere. And if we have IncrementalOptions::None then
     // we explicitly don't want to default to incremental compilation.
-    cmd.env("CARGO_INCREMENTAL", "0");
+    cargo_cmd.env("CARGO_INCREMENTAL", "0");
+
+    // The `-Z` flags we pass to rustc when building incrementally.
+    let incr_flags = |incr_dir: &Path| {
+        let mut flags = vec![format!("incremental={}", incr_dir.display()),
+                             "incremental-info".to_string(),
+                             "incremental-queries".to_string(),
+                             "incremental-verify-ich".to_string()];
+        if options.dump_depgraph {
+            flags.push("dump-dep-graph".to_string());
+        }
+        if let Some(ref self_profile_dir) = options.self_profile_dir {
+            flags.push(format!("self-profile={}", self_profile_dir.display()));
+        }
+        flags
+    };
+
+    let incremental_enabled = match incremental {
+        IncrementalOptions::None => false,
+        _ => true,
+    };
 
     match incremental {
         IncrementalOptions::None => {
-            cmd.arg("build").arg("-v");
+            cargo_cmd.arg(options.driver.subcommand(false))
+                .arg("-v")
+                .arg("--message-format=json");
+            options.apply_build_args(&mut cargo_cmd);
+            options.apply_build_only_args(&mut cargo_cmd);
+            options.apply_rustflags(&mut cargo_cmd, &[]);
         }
         IncrementalOptions::AllDeps(incr_dir) => {
-            let rustflags = env::var("RUSTFLAGS").unwrap_or(String::new());
-            cmd.arg("build")
+            let rustflags: Vec<String> = incr_flags(incr_dir)
+                .into_iter()
+                .map(|flag| format!("-Z {}", flag))
+                .collect();
+            if options.driver == BuildDriver::Doc {
+                // rustdoc does not look at RUSTFLAGS.
+                let mut rustdocflags = rustflags.clone();
+                rustdocflags.push(env::var("RUSTDOCFLAGS").unwrap_or(String::new()));
+                cargo_cmd.env("RUSTDOCFLAGS", rustdocflags.join(" "));
+            }
+            cargo_cmd.arg(options.driver.subcommand(false))
                 .arg("-v")
-                .env("RUSTFLAGS",
-                     format!("-Z incremental={} \
-                              -Z incremental-info {} \
-                              -Z incremental-queries \
-                              -Z incremental-verify-ich",
-                             incr_dir.display(),
-                             rustflags));
+                .arg("--message-format=json");
+            options.apply_build_args(&mut cargo_cmd);
+            options.apply_build_only_args(&mut cargo_cmd);
+            options.apply_rustflags(&mut cargo_cmd, &rustflags);
         }
         IncrementalOptions::CurrentProject(incr_dir) => {
-            cmd.arg("rustc")
+            cargo_cmd.arg(options.driver.subcommand(true))
                 .arg("-v")
-                .arg("--")
-                .arg("-Z").arg(format!("incremental={}", incr_dir.display()))
-                .arg("-Z").arg("incremental-info")
-                .arg("-Z").arg("incremental-queries")
-                .arg("-Z").arg("incremental-verify-ich");
+                .arg("--message-format=json");
+            options.apply_build_args(&mut cargo_cmd);
+            options.apply_build_only_args(&mut cargo_cmd);
+            options.apply_rustflags(&mut cargo_cmd, &[]);
+            cargo_cmd.arg("--");
+            for flag in incr_flags(incr_dir) {
+                cargo_cmd.arg("-Z").arg(flag);
+            }
         }
     }
 
+    let is_incremental = match incremental {
+        IncrementalOptions::None => false,
+        IncrementalOptions::AllDeps(_) |
+        IncrementalOptions::CurrentProject(_) => true,
+    };
+
+    if options.dump_depgraph && is_incremental {
+        // rustc writes the graph to `$RUST_DEP_GRAPH.{txt,dot}`. Note that
+        // every crate in the build writes to the same place, so we end up
+        // with the graph of the last crate that was compiled.
+        let commit_dir = match fs::canonicalize(commit_dir) {
+            Ok(dir) => dir,
+            Err(err) => error!("failed to canonicalize `{}`: {}", commit_dir.display(), err),
+        };
+        cargo_cmd.env("RUST_DEP_GRAPH", commit_dir.join(DEP_GRAPH_FILE_STEM).display());
+    }
+
+    if verbose {
+        print_command(&cargo_cmd);
+    }
+
+    let mut cmd = cargo_cmd.to_command();
     let output = if stream_output {
         cmd.stdout(Stdio::piped());
         cmd.stderr(Stdio::piped());
@@ -315,18 +1301,27 @@ pub fn cargo_build(cargo_dir: &Path,
             error!("failed to spawn `cargo build` process: {}", err)
         });
 
-        let done = Arc::new(AtomicBool::new(false));
-
-        let stdout_reader = spawn_stream_reader(done.clone(),
-                                                process.stdout.take().unwrap(),
-                                                |bytes| {
-                                                    let stdout = io::stdout();
-                                                    let mut stdout = stdout.lock();
-                                                    stdout.write_all(bytes).unwrap();
-                                                });
+        // Stdout is JSON, so we have to collect whole lines before we can
+        // show them.
+        let pending_line = Mutex::new(Vec::new());
+        let stdout_reader = spawn_stream_reader(process.stdout.take().unwrap(),
+                                                move |bytes| {
+            let mut pending_line = pending_line.lock().unwrap();
+            // At the end of the stream, also show an unterminated last line.
+            if bytes.is_empty() && !pending_line.is_empty() {
+                pending_line.push(b'\n');
+            }
+            pending_line.extend(bytes);
+            while let Some(end) = pending_line.iter().position(|&b| b == b'\n') {
+                let line: Vec<u8> = pending_line.drain(..end + 1).collect();
+                let line = String::from_utf8_lossy(&line[..end]);
+                if let Some(line) = render_output_line(line.trim_right_matches('\r')) {
+                    println!("{}", line);
+                }
+            }
+        });
 
-        let stderr_reader = spawn_stream_reader(done.clone(),
-                                                process.stderr.take().unwrap(),
+        let stderr_reader = spawn_stream_reader(process.stderr.take().unwrap(),
                                                 |bytes| {
                                                     let stderr = io::stderr();
                                                     let mut stderr = stderr.lock();
@@ -338,8 +1333,6 @@ pub fn cargo_build(cargo_dir: &Path,
                    err)
         });
 
-        done.store(true, Ordering::SeqCst);
-
         let stdout = stdout_reader.join().unwrap_or_else(|_| {
             error!("error while reading child process stdout")
         });
@@ -361,7 +1354,7 @@ pub fn cargo_build(cargo_dir: &Path,
     let output = match output {
         Ok(output) => {
             if should_save_output {
-                save_output(commit_dir, &output);
+                save_output(commit_dir, &output, options.strip_ansi);
             }
 
             output
@@ -377,14 +1370,31 @@ pub fn cargo_build(cargo_dir: &Path,
         .collect();
     let all_output = into_string(all_bytes);
 
-    let reusing_regex = Regex::new(r"(?m)^incremental: re-using (\d+) out of (\d+) modules$")
-        .unwrap();
-    for captures in reusing_regex.captures_iter(&all_output) {
-        let reused = u64::from_str(captures.at(1).unwrap()).unwrap();
-        let total = u64::from_str(captures.at(2).unwrap()).unwrap();
+    let only_crate = options.only_crate.as_ref().map(|name| &name[..]);
+    if let Some((reused, total)) = parse_module_reuse(&all_output, only_crate) {
         stats.modules_reused += reused;
         stats.modules_total += total;
     }
+    if let Some((reused, total)) = parse_query_reuse(&all_output, only_crate) {
+        stats.queries_reused += reused;
+        stats.queries_total += total;
+    }
+
+    // Only `cargo build` does code generation, which is where rustc reports
+    // the re-use. And a build with nothing to do runs no rustc at all. (With
+    // `only_crate`, the lines of the other crates still count here.)
+    if parse_module_reuse(&all_output, None).is_none() {
+        let ran_incremental_rustc = Regex::new(r"(?m)^\s*Running `.*incremental=")
+            .unwrap()
+            .is_match(&all_output);
+        if incremental_enabled && options.driver == BuildDriver::Build &&
+           output.status.success() && ran_incremental_rustc &&
+           !WARNED_ABOUT_MODULE_REUSE.swap(true, Ordering::SeqCst) {
+            println!("warning: rustc was run with `-Z incremental-info`, but we found no \
+                      line saying how many modules it re-used; all re-use statistics \
+                      will be wrong. Has rustc changed the wording?");
+        }
+    }
 
     let build_time_regex = Regex::new(r"(?m)^\s*Finished .* target\(s\) in ([0-9.]+)(( secs)|s)$")
         .unwrap();
@@ -407,25 +1417,44 @@ pub fn cargo_build(cargo_dir: &Path,
         }
     };
 
-    let message_regex = Regex::new("(?m)(warning|error): (.*)\n  --> ([^:]:\\d+:\\d+)$").unwrap();
-    let messages = message_regex.captures_iter(&all_output)
-        .map(|captures| {
-            Message {
-                kind: captures.at(1).unwrap().to_string(),
-                message: captures.at(2).unwrap().to_string(),
-                location: captures.at(3).unwrap().to_string(),
-            }
-        })
+    let messages = match parse_json_messages(&output.stdout) {
+        Some(messages) => messages,
+        None => {
+            // Fall back to scraping the human-readable output.
+            let message_regex = Regex::new("(?m)(warning|error): (.*)\n  --> ([^:]:\\d+:\\d+)$")
+                .unwrap();
+            message_regex.captures_iter(&all_output)
+                .map(|captures| {
+                    Message {
+                        kind: captures.at(1).unwrap().to_string(),
+                        code: None,
+                        message: captures.at(2).unwrap().to_string(),
+                        location: captures.at(3).unwrap().to_string(),
+                        children: vec![],
+                    }
+                })
+                .collect()
+        }
+    };
+
+    let unstable_fingerprint_regex = Regex::new(r"(?mi)^.*found unstable fingerprints.*$").unwrap();
+    let unstable_fingerprints = unstable_fingerprint_regex.captures_iter(&all_output)
+        .map(|captures| captures.at(0).unwrap().trim().to_string())
         .collect();
 
     return BuildResult {
         success: output.status.success(),
         messages: messages,
+        exit_reason: exit_reason(&output),
         raw_output: output,
+        command: cargo_cmd,
+        unstable_fingerprints: unstable_fingerprints,
     };
 
-    fn spawn_stream_reader<S, F>(done_flag: Arc<AtomicBool>,
-                                 mut stream: S,
+    // Reads `stream` until the child process closes it, passing everything
+    // to `forward` as it arrives, and finally an empty slice to signal the
+    // end of the stream.
+    fn spawn_stream_reader<S, F>(mut stream: S,
                                  forward: F)
                                  -> JoinHandle<Vec<u8>>
         where S: Read+Send+'static,
@@ -433,23 +1462,21 @@ pub fn cargo_build(cargo_dir: &Path,
     {
         thread::spawn(move || {
             let mut data = Vec::new();
-            let mut buffer = [0u8; 100];
+            let mut buffer = [0u8; 8 * 1024];
 
-            while !done_flag.load(Ordering::SeqCst) {
-                let byte_count = stream.read(&mut buffer).unwrap_or_else(|_| {
-                    error!("error reading from child process pipe")
-                });
+            loop {
+                let byte_count = match stream.read(&mut buffer) {
+                    Ok(0) => break,
+                    Ok(byte_count) => byte_count,
+                    Err(ref err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                    Err(_) => error!("error reading from child process pipe"),
+                };
 
                 forward(&buffer[0 .. byte_count]);
                 data.extend(&buffer[0 .. byte_count]);
             }
 
-            let size_before = data.len();
-            stream.read_to_end(&mut data).unwrap_or_else(|_| {
-                error!("error reading from child process pipe")
-            });
-
-            forward(&data[size_before..]);
+            forward(&[]);
 
             data
         })
@@ -458,10 +1485,10 @@ pub fn cargo_build(cargo_dir: &Path,
 
 pub fn cargo_clean(cargo_dir: &Path,
                    target_dir: &Path,
-                   just_current: bool) {
-    let mut cmd = Command::new("cargo");
-    cmd.current_dir(&cargo_dir);
-    cmd.env("CARGO_TARGET_DIR", target_dir);
+                   just_current: bool,
+                   verbose: bool,
+                   options: &CargoOptions) {
+    let mut cmd = CargoCommand::new(cargo_dir, target_dir);
     if !just_current {
         cmd.arg("clean").arg("-v");
     } else {
@@ -475,7 +1502,14 @@ pub fn cargo_clean(cargo_dir: &Path,
             .arg("-p")
             .arg(&cargo_package_name);
     }
+    options.apply(&mut cmd);
+    options.apply_target(&mut cmd);
 
+    if verbose {
+        print_command(&cmd);
+    }
+
+    let mut cmd = cmd.to_command();
     debug!("{:?}", cmd);
     match cmd.output() {
         Ok(output) => {
@@ -490,6 +1524,217 @@ pub fn cargo_clean(cargo_dir: &Path,
     }
 }
 
+/// Checks that there is a `cargo` on the `PATH` for us to run, so that we
+/// fail right away rather than when the first build starts.
+pub fn check_cargo_on_path() {
+    if find_on_path("cargo").is_none() {
+        error!("cargo not found on PATH; install Rust (e.g. with rustup, see \
+                https://rustup.rs) or add the directory containing `cargo` to PATH")
+    }
+}
+
+fn find_on_path(program: &str) -> Option<PathBuf> {
+    let file_name = format!("{}{}", program, env::consts::EXE_SUFFIX);
+    env::var_os("PATH").and_then(|paths| {
+        env::split_paths(&paths).map(|dir| dir.join(&file_name)).find(|path| path.is_file())
+    })
+}
+
+// The settings of cargo's `build.target-dir` that apply to builds in
+// `cargo_dir`: `CARGO_BUILD_TARGET_DIR` and the config files. We give each
+// build its own `CARGO_TARGET_DIR`, which is supposed to win over these.
+pub fn target_dir_settings(cargo_dir: &Path) -> Vec<String> {
+    let mut settings = vec![];
+    if let Ok(dir) = env::var("CARGO_BUILD_TARGET_DIR") {
+        settings.push(format!("`CARGO_BUILD_TARGET_DIR={}`", dir));
+    }
+
+    let mut config_dirs = vec![];
+    let mut dir = Some(cargo_dir);
+    while let Some(d) = dir {
+        config_dirs.push(d.join(".cargo"));
+        dir = d.parent();
+    }
+    let cargo_home = env::var_os("CARGO_HOME")
+        .map(PathBuf::from)
+        .or(env::var_os("HOME").map(|home| Path::new(&home).join(".cargo")));
+    config_dirs.extend(cargo_home);
+
+    for config_dir in config_dirs {
+        for config_path in &[config_dir.join("config"), config_dir.join("config.toml")] {
+            let mut contents = String::new();
+            if File::open(config_path).and_then(|mut f| f.read_to_string(&mut contents)).is_err() {
+                continue;
+            }
+            let target_dir = toml::Parser::new(&contents)
+                .parse()
+                .as_ref()
+                .and_then(|table| table.get("build"))
+                .and_then(|build| build.as_table())
+                .and_then(|build| build.get("target-dir"))
+                .and_then(|target_dir| target_dir.as_str())
+                .map(|target_dir| target_dir.to_string());
+            if let Some(target_dir) = target_dir {
+                settings.push(format!("`build.target-dir = \"{}\"` in `{}`",
+                                      target_dir,
+                                      config_path.display()));
+            }
+        }
+    }
+
+    settings
+}
+
+// Makes sure that the builds really went into `target_dirs`. If something
+// made cargo use a different target directory, the normal and incremental
+// builds would share it and reuse each other's artifacts.
+pub fn check_target_dirs_used(target_dirs: &[&Path], cargo_dir: &Path) {
+    for target_dir in target_dirs {
+        if !dir_entries(target_dir).is_empty() {
+            continue;
+        }
+
+        let settings = target_dir_settings(cargo_dir);
+        let culprit = if settings.is_empty() {
+            "something overrode `CARGO_TARGET_DIR`".to_string()
+        } else {
+            format!("probably because of {}", settings.join(", "))
+        };
+        error!("cargo built outside of `{}`, {}; the builds are not kept apart, so \
+                remove that setting and try again",
+               target_dir.display(),
+               culprit)
+    }
+}
+
+/// Checks that the rustc cargo is going to use accepts the `-Z` flags we
+/// need for incremental compilation, by compiling an empty crate with them.
+/// Otherwise every single build would fail later on with the same error.
+pub fn check_incremental_support(cargo_dir: &Path, options: &CargoOptions) {
+    let rustc = rustc_path(options);
+    // Other replays (such as the workers of `--max-parallel-commits`) may be
+    // probing at the same time, so each process gets a directory of its own.
+    let probe_dir = env::temp_dir().join(format!("cargo-incremental-probe-{}", process::id()));
+    remove_dir(&probe_dir);
+    make_dir(&probe_dir);
+
+    let mut cmd = toolchain_command(&rustc, cargo_dir, options);
+    cmd.arg("-")
+        .arg("--crate-type").arg("lib")
+        .arg("--crate-name").arg("probe")
+        .arg("--emit").arg("metadata")
+        .arg("--out-dir").arg(&probe_dir)
+        .arg("-Z").arg(format!("incremental={}", probe_dir.join("incr").display()))
+        .arg("-Z").arg("incremental-info")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    debug!("{:?}", cmd);
+
+    // Closing stdin right away gives rustc an empty crate.
+    let output = cmd.spawn().and_then(|mut child| {
+        drop(child.stdin.take());
+        child.wait_with_output()
+    });
+    remove_dir(&probe_dir);
+
+    match output {
+        Ok(ref output) if output.status.success() => {}
+        Ok(output) => {
+            let stderr = io::stderr();
+            let mut stderr = stderr.lock();
+            stderr.write_all(&output.stderr).unwrap();
+            error!("`{}` does not accept the `-Z` flags needed for incremental compilation; \
+                    try a nightly toolchain (e.g. `rustup override set nightly`)",
+                   rustc)
+        }
+        Err(err) => error!("failed to run `{}`: {}", rustc, err),
+    }
+}
+
+/// The output of `rustc --version --verbose` and `cargo --version`, run the
+/// way the builds will run them, so that we know which compiler the results
+/// are about.
+pub fn toolchain_versions(cargo_dir: &Path, options: &CargoOptions) -> (String, String) {
+    let mut rustc_cmd = toolchain_command(&rustc_path(options), cargo_dir, options);
+    rustc_cmd.arg("--version").arg("--verbose");
+    let rustc_version = version_output(&mut rustc_cmd);
+
+    let mut cargo_cmd = toolchain_command("cargo", cargo_dir, options);
+    cargo_cmd.arg("--version");
+    let cargo_version = version_output(&mut cargo_cmd);
+
+    (rustc_version, cargo_version)
+}
+
+// The rustc that cargo will use: the one `--env RUSTC=...` names, if any,
+// or else the one cargo would pick itself.
+fn rustc_path(options: &CargoOptions) -> String {
+    options.env
+        .iter()
+        .find(|&&(ref key, _)| key == "RUSTC")
+        .map(|&(_, ref value)| value.clone())
+        .or(env::var("RUSTC").ok())
+        .unwrap_or("rustc".to_string())
+}
+
+// A command to run `program` (rustc or cargo) the way the builds will run
+// it: in `cargo_dir`, so that any toolchain override there applies, and with
+// the `--env` variables, some of which (e.g. `RUSTUP_TOOLCHAIN`) may pick
+// the toolchain.
+fn toolchain_command(program: &str, cargo_dir: &Path, options: &CargoOptions) -> Command {
+    let mut cmd = Command::new(program);
+    cmd.current_dir(cargo_dir);
+    for &(ref key, ref value) in &options.env {
+        cmd.env(key, value);
+    }
+    cmd
+}
+
+fn version_output(cmd: &mut Command) -> String {
+    debug!("{:?}", cmd);
+    match cmd.output() {
+        Ok(ref output) if output.status.success() => {
+            into_string(output.stdout.clone()).trim().to_string()
+        }
+        Ok(output) => {
+            let stderr = io::stderr();
+            let mut stderr = stderr.lock();
+            stderr.write_all(&output.stderr).unwrap();
+            error!("failed to find out the toolchain version: {:?} failed", cmd)
+        }
+        Err(err) => error!("failed to find out the toolchain version: {:?}: {}", cmd, err),
+    }
+}
+
+// Runs `cargo update`, which changes `Cargo.lock` in the checkout.
+pub fn cargo_update(cargo_dir: &Path,
+                    target_dir: &Path,
+                    verbose: bool,
+                    options: &CargoOptions) {
+    let mut cmd = CargoCommand::new(cargo_dir, target_dir);
+    cmd.arg("update");
+    options.apply(&mut cmd);
+
+    if verbose {
+        print_command(&cmd);
+    }
+
+    let mut cmd = cmd.to_command();
+    debug!("{:?}", cmd);
+    match cmd.output() {
+        Ok(output) => {
+            if !output.status.success() {
+                print_output(&output);
+                error!("cargo update failed");
+            }
+        }
+        Err(err) => {
+            error!("could not execute `cargo update`: {}", err);
+        }
+    }
+}
+
 pub fn dir_entries(dir: &Path) -> Vec<PathBuf> {
     debug!("dir_entries({})", dir.display());
     let dir_iter = fs::read_dir(dir).unwrap_or_else(|err| {
@@ -508,6 +1753,19 @@ pub fn dir_entries(dir: &Path) -> Vec<PathBuf> {
     .collect()
 }
 
+// The total size in bytes of the files in `dir` and its subdirectories.
+pub fn dir_size(dir: &Path) -> u64 {
+    dir_entries(dir).iter()
+        .map(|entry| {
+            if entry.is_dir() {
+                dir_size(entry)
+            } else {
+                entry.metadata().map(|metadata| metadata.len()).unwrap_or(0)
+            }
+        })
+        .sum()
+}
+
 pub fn path_file_name(entry: &Path) -> String {
     entry.file_name().unwrap().to_string_lossy().into_owned()
 }
@@ -522,6 +1780,71 @@ pub fn duration_to_string(duration: Duration) -> String {
     format!("{:02}:{:02}:{:02}", hours, mins, secs)
 }
 
+// A coarser version of `duration_to_string`, used for time estimates where
+// seconds would just be noise.
+pub fn duration_to_eta_string(duration: Duration) -> String {
+    let total_mins = (duration.as_secs() + 30) / 60;
+    let mins = total_mins % 60;
+    let hours = total_mins / 60;
+
+    if hours > 0 {
+        format!("{}h{:02}m", hours, mins)
+    } else if mins > 0 {
+        format!("{}m", mins)
+    } else {
+        "<1m".to_string()
+    }
+}
+
+pub fn mean_and_std_dev(samples: &[f64]) -> (f64, f64) {
+    if samples.is_empty() {
+        return (0.0, 0.0);
+    }
+
+    let count = samples.len() as f64;
+    let mean = samples.iter().fold(0.0, |sum, sample| sum + sample) / count;
+    let variance = samples.iter()
+        .fold(0.0, |sum, sample| sum + (sample - mean) * (sample - mean)) / count;
+    (mean, variance.sqrt())
+}
+
+// Escapes `text` for the JUnit and HTML reports. Control characters other
+// than tabs and line breaks are not allowed in XML 1.0, even escaped, so
+// color codes (e.g. in panic messages) are dropped and the rest replaced.
+pub fn escape_markup(text: &str) -> String {
+    let text = String::from_utf8_lossy(&strip_ansi_codes(text.as_bytes())).into_owned();
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '\t' | '\n' | '\r' => escaped.push(c),
+            c if c < ' ' => escaped.push('\u{fffd}'),
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+pub fn bytes_to_string(bytes: u64) -> String {
+    const UNITS: &'static [&'static str] = &["KiB", "MiB", "GiB"];
+
+    if bytes < 1024 {
+        return format!("{} B", bytes);
+    }
+
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit + 1 < UNITS.len() {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", size, UNITS[unit])
+}
+
 pub fn get_cargo_package_name(cargo_dir: &Path) -> Result<String, String> {
     let cargo_toml_path = cargo_dir.join("Cargo.toml");
     let mut cargo_toml_file = try!(File::open(&cargo_toml_path)
@@ -553,6 +1876,52 @@ pub fn get_cargo_package_name(cargo_dir: &Path) -> Result<String, String> {
     }
 }
 
+// Copies `source` to `target` and runs `strip` on the copy.
+pub fn strip_copy(source: &Path, target: &Path) -> Result<(), String> {
+    if let Some(parent) = target.parent() {
+        make_dir(parent);
+    }
+
+    try!(fs::copy(source, target).map_err(|err| {
+        format!("Could not copy `{}` to `{}`: {}", source.display(), target.display(), err)
+    }));
+
+    let mut cmd = Command::new("strip");
+    cmd.arg(target);
+    debug!("{:?}", cmd);
+
+    let output = try!(cmd.output().map_err(|err| {
+        format!("Could not execute `strip`: {}", err)
+    }));
+
+    if !output.status.success() {
+        return Err(format!("`strip {}` failed: {}",
+                           target.display(),
+                           String::from_utf8_lossy(&output.stderr)));
+    }
+
+    Ok(())
+}
+
+// Recreates the directory tree `source` at `target`, hardlinking the files
+// where possible and copying them where not (e.g. across file systems).
+pub fn link_or_copy_dir(source: &Path, target: &Path) {
+    make_dir(target);
+    for entry in dir_entries(source) {
+        let entry_target = target.join(path_file_name(&entry));
+        if entry.is_dir() {
+            link_or_copy_dir(&entry, &entry_target);
+        } else if fs::hard_link(&entry, &entry_target).is_err() {
+            if let Err(err) = fs::copy(&entry, &entry_target) {
+                error!("could not copy `{}` to `{}`: {}",
+                       entry.display(),
+                       entry_target.display(),
+                       err)
+            }
+        }
+    }
+}
+
 pub fn rename_directory(old_path: &Path, new_path: &Path) {
     fs::rename(old_path, new_path).unwrap_or_else(|err| {
         error!("Could not rename directory from `{}` to `{}`: {}",
@@ -561,3 +1930,382 @@ pub fn rename_directory(old_path: &Path, new_path: &Path) {
                 err);
     });
 }
+
+/// One line of the result of `diff_lines`.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum DiffLine<'a> {
+    Same(&'a str),
+    Removed(&'a str),
+    Added(&'a str),
+}
+
+/// Computes a line-by-line diff turning `old` into `new`, based on their
+/// longest common subsequence.
+pub fn diff_lines<'a>(old: &[&'a str], new: &[&'a str]) -> Vec<DiffLine<'a>> {
+    // The common prefix and suffix don't need to go through the (quadratic)
+    // LCS computation, and usually they are most of the output.
+    let prefix = old.iter().zip(new).take_while(|&(a, b)| a == b).count();
+    let suffix = old[prefix..].iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|&(a, b)| a == b)
+        .count();
+    let old_middle = &old[prefix..old.len() - suffix];
+    let new_middle = &new[prefix..new.len() - suffix];
+
+    // `lcs[i][j]` is the length of the LCS of `old_middle[i..]` and
+    // `new_middle[j..]`.
+    let (n, m) = (old_middle.len(), new_middle.len());
+    let mut lcs = vec![vec![0; m + 1]; n + 1];
+    for i in (0..n).rev() {
+        for j in (0..m).rev() {
+            lcs[i][j] = if old_middle[i] == new_middle[j] {
+                lcs[i + 1][j + 1] + 1
+            } else {
+                cmp::max(lcs[i + 1][j], lcs[i][j + 1])
+            };
+        }
+    }
+
+    let mut diff: Vec<_> = old[..prefix].iter().map(|&line| DiffLine::Same(line)).collect();
+    let (mut i, mut j) = (0, 0);
+    while i < n || j < m {
+        if i < n && j < m && old_middle[i] == new_middle[j] {
+            diff.push(DiffLine::Same(old_middle[i]));
+            i += 1;
+            j += 1;
+        } else if i < n && (j == m || lcs[i + 1][j] >= lcs[i][j + 1]) {
+            diff.push(DiffLine::Removed(old_middle[i]));
+            i += 1;
+        } else {
+            diff.push(DiffLine::Added(new_middle[j]));
+            j += 1;
+        }
+    }
+    diff.extend(old[old.len() - suffix..].iter().map(|&line| DiffLine::Same(line)));
+    diff
+}
+
+/// Formats the differences between `old` and `new` like `diff -u` does,
+/// with `context` unchanged lines around each change. Returns an empty
+/// string if there are no differences.
+pub fn unified_diff(old: &str, new: &str, context: usize) -> String {
+    let old_lines: Vec<&str> = old.lines().collect();
+    let new_lines: Vec<&str> = new.lines().collect();
+    let diff = diff_lines(&old_lines, &new_lines);
+
+    let changes: Vec<usize> = diff.iter()
+        .enumerate()
+        .filter(|&(_, line)| match *line {
+            DiffLine::Same(_) => false,
+            DiffLine::Removed(_) | DiffLine::Added(_) => true,
+        })
+        .map(|(index, _)| index)
+        .collect();
+
+    let mut output = String::new();
+    let mut next_change = 0;
+    while next_change < changes.len() {
+        // Extend the hunk for as long as the next change is close enough that
+        // the context lines would overlap.
+        let start = changes[next_change].saturating_sub(context);
+        let mut end = cmp::min(changes[next_change] + context + 1, diff.len());
+        next_change += 1;
+        while next_change < changes.len() && changes[next_change] <= end + context {
+            end = cmp::min(changes[next_change] + context + 1, diff.len());
+            next_change += 1;
+        }
+
+        let count = |lines: &[DiffLine], in_old: bool| {
+            lines.iter()
+                .filter(|line| match **line {
+                    DiffLine::Same(_) => true,
+                    DiffLine::Removed(_) => in_old,
+                    DiffLine::Added(_) => !in_old,
+                })
+                .count()
+        };
+        let (old_before, new_before) = (count(&diff[..start], true), count(&diff[..start], false));
+        let (old_count, new_count) = (count(&diff[start..end], true),
+                                      count(&diff[start..end], false));
+
+        // Like `diff`, refer to the line before an empty range.
+        output.push_str(&format!("@@ -{},{} +{},{} @@\n",
+                                 if old_count == 0 { old_before } else { old_before + 1 },
+                                 old_count,
+                                 if new_count == 0 { new_before } else { new_before + 1 },
+                                 new_count));
+        for line in &diff[start..end] {
+            match *line {
+                DiffLine::Same(text) => output.push_str(&format!(" {}\n", text)),
+                DiffLine::Removed(text) => output.push_str(&format!("-{}\n", text)),
+                DiffLine::Added(text) => output.push_str(&format!("+{}\n", text)),
+            }
+        }
+    }
+
+    output
+}
+
+/// Colors the lines of a `unified_diff` for a terminal: removed lines red,
+/// added lines green and hunk headers cyan.
+pub fn colorize_diff(diff: &str) -> String {
+    let mut colored = String::new();
+    for line in diff.lines() {
+        let color = if line.starts_with("@@") {
+            Some("36")
+        } else if line.starts_with('-') {
+            Some("31")
+        } else if line.starts_with('+') {
+            Some("32")
+        } else {
+            None
+        };
+
+        match color {
+            Some(color) => colored.push_str(&format!("\x1b[{}m{}\x1b[0m\n", color, line)),
+            None => {
+                colored.push_str(line);
+                colored.push('\n');
+            }
+        }
+    }
+    colored
+}
+
+// What a user would have seen of `output`: stdout (with JSON diagnostics
+// rendered) followed by stderr.
+pub fn output_text(output: &Output) -> String {
+    let mut text = String::new();
+    for line in String::from_utf8_lossy(&output.stdout).lines() {
+        if let Some(line) = render_output_line(line) {
+            text.push_str(&line);
+            text.push('\n');
+        }
+    }
+    text.push_str(&String::from_utf8_lossy(&output.stderr));
+    text
+}
+
+// Explains a process that was killed by a signal rather than exiting with a
+// status code. cargo itself usually survives when rustc is killed, so we also
+// look for cargo's report of that in stderr. A SIGKILL most likely comes from
+// the OOM killer, which is worth telling apart from a compiler bug.
+pub fn exit_reason(output: &Output) -> Option<String> {
+    if let Some(signal) = exit_signal(&output.status) {
+        return Some(describe_signal(signal));
+    }
+
+    let stderr = into_string(output.stderr.clone());
+    let signal_regex =
+        Regex::new(r"process didn't exit successfully: `([^` ]+)[^`]*` \(signal: (\d+)")
+        .unwrap();
+    signal_regex.captures(&stderr).map(|captures| {
+        let program = Path::new(captures.at(1).unwrap())
+            .file_name()
+            .map_or(String::new(), |name| name.to_string_lossy().into_owned());
+        let signal = i32::from_str(captures.at(2).unwrap()).unwrap();
+        format!("`{}` {}", program, describe_signal(signal))
+    })
+}
+
+fn describe_signal(signal: i32) -> String {
+    // SIGKILL
+    if signal == 9 {
+        "killed by signal 9 (likely OOM)".to_string()
+    } else {
+        format!("killed by signal {}", signal)
+    }
+}
+
+#[cfg(unix)]
+fn exit_signal(status: &ExitStatus) -> Option<i32> {
+    use std::os::unix::process::ExitStatusExt;
+    status.signal()
+}
+
+#[cfg(not(unix))]
+fn exit_signal(_status: &ExitStatus) -> Option<i32> {
+    None
+}
+
+/// A git repository in a directory of its own under the system's temporary
+/// directory, for tests. The directory is removed when this is dropped, even
+/// if the test panics. `name` must be unique among the tests.
+#[cfg(test)]
+pub struct TempRepo {
+    pub dir: PathBuf,
+    pub repo: Repository,
+}
+
+#[cfg(test)]
+impl TempRepo {
+    pub fn new(name: &str) -> TempRepo {
+        let dir = env::temp_dir()
+            .join(format!("cargo-incremental-test-{}-{}", name, process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let repo = Repository::init(&dir).unwrap();
+        TempRepo {
+            dir: dir,
+            repo: repo,
+        }
+    }
+}
+
+#[cfg(test)]
+impl Drop for TempRepo {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{check_empty_or_missing_dir, colorize_diff, diff_lines, escape_markup,
+                format_git_time, mount_points, parse_module_reuse, parse_query_reuse, strip_ansi_codes,
+                unified_diff};
+    use super::DiffLine::{Added, Removed, Same};
+
+    #[test]
+    fn test_diff_lines() {
+        assert_eq!(diff_lines(&["a", "b", "c"], &["a", "b", "c"]),
+                   vec![Same("a"), Same("b"), Same("c")]);
+        assert_eq!(diff_lines(&["a", "b", "c"], &["a", "x", "c"]),
+                   vec![Same("a"), Removed("b"), Added("x"), Same("c")]);
+        assert_eq!(diff_lines(&["a", "b", "c", "d"], &["b", "c", "e"]),
+                   vec![Removed("a"), Same("b"), Same("c"), Removed("d"), Added("e")]);
+        assert_eq!(diff_lines(&[], &["a"]), vec![Added("a")]);
+    }
+
+    #[test]
+    fn test_unified_diff() {
+        assert_eq!(unified_diff("a\nb\nc\n", "a\nb\nc\n", 3), "");
+
+        let old = "1\n2\n3\n4\n5\n6\n7\n8\n9\n10\n";
+        let new = "1\n2\n3\n4\nfive\n6\n7\n8\n9\n10\n11\n";
+        assert_eq!(unified_diff(old, new, 1),
+                   "@@ -4,3 +4,3 @@\n 4\n-5\n+five\n 6\n@@ -10,1 +10,2 @@\n 10\n+11\n");
+        assert_eq!(unified_diff("a\n", "", 3), "@@ -1,1 +0,0 @@\n-a\n");
+    }
+
+    #[test]
+    fn test_format_git_time() {
+        assert_eq!(format_git_time(0, 0), "1970-01-01T00:00:00+00:00");
+        assert_eq!(format_git_time(1489479413, 60), "2017-03-14T09:16:53+01:00");
+        assert_eq!(format_git_time(951782400, -330), "2000-02-28T18:30:00-05:30");
+        assert_eq!(format_git_time(-1, 0), "1969-12-31T23:59:59+00:00");
+    }
+
+    #[test]
+    fn test_mount_points() {
+        use std::path::PathBuf;
+
+        let mounts = "proc /proc proc rw,nosuid 0 0\n\
+                      tmpfs /tmp/my\\040work tmpfs rw,size=4g 0 0\n";
+        assert_eq!(mount_points(mounts),
+                   vec![PathBuf::from("/proc"), PathBuf::from("/tmp/my work")]);
+    }
+
+    #[test]
+    fn test_check_empty_or_missing_dir() {
+        use std::env;
+        use std::fs;
+
+        let dir = env::temp_dir()
+            .join(format!("cargo-incremental-test-dir-{}", ::std::process::id()));
+        assert!(check_empty_or_missing_dir(&dir).is_ok());
+
+        fs::create_dir(&dir).unwrap();
+        assert!(check_empty_or_missing_dir(&dir).is_ok());
+
+        fs::File::create(dir.join("file")).unwrap();
+        assert!(check_empty_or_missing_dir(&dir).is_err());
+        assert!(check_empty_or_missing_dir(&dir.join("file")).is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_exit_reason() {
+        use std::os::unix::process::ExitStatusExt;
+        use std::process::{ExitStatus, Output};
+        use super::exit_reason;
+
+        let output = |status: i32, stderr: &str| {
+            Output {
+                status: ExitStatus::from_raw(status),
+                stdout: vec![],
+                stderr: stderr.as_bytes().to_vec(),
+            }
+        };
+
+        assert_eq!(exit_reason(&output(9, "")),
+                   Some("killed by signal 9 (likely OOM)".to_string()));
+        assert_eq!(exit_reason(&output(101 << 8,
+                                       "error: could not compile `foo`.\n\nCaused by:\n  \
+                                        process didn't exit successfully: `/usr/bin/rustc \
+                                        --crate-name foo src/lib.rs` (signal: 9, SIGKILL: \
+                                        kill)\n")),
+                   Some("`rustc` killed by signal 9 (likely OOM)".to_string()));
+        assert_eq!(exit_reason(&output(101 << 8, "error: aborting due to previous error\n")),
+                   None);
+    }
+
+    #[test]
+    fn test_parse_module_reuse() {
+        let old = "   Compiling foo v0.1.0\nincremental: re-using 3 out of 4 modules\n\
+                   incremental: re-using 0 out of 1 modules\n";
+        assert_eq!(parse_module_reuse(old, None), Some((3, 5)));
+        let new = "[incremental] session directory: 42 files hard-linked\n\
+                   [incremental] Re-using 7 out of 16 modules\n";
+        assert_eq!(parse_module_reuse(new, None), Some((7, 16)));
+        assert_eq!(parse_module_reuse("[incremental] re-using 2 out of 2 CGUs.\n", None),
+                   Some((2, 2)));
+        assert_eq!(parse_module_reuse("    Finished dev [unoptimized] target(s) in 0.1s\n",
+                                      None),
+                   None);
+
+        let two_crates = "     Running `rustc --crate-name dep src/dep.rs -C incremental=x`\n\
+                          [incremental] re-using 1 out of 2 modules\n\
+                          \u{20}    Running `/usr/bin/rustc --crate-name foo src/lib.rs`\n\
+                          [incremental] re-using 3 out of 4 modules\n";
+        assert_eq!(parse_module_reuse(two_crates, None), Some((4, 6)));
+        assert_eq!(parse_module_reuse(two_crates, Some("foo")), Some((3, 4)));
+        assert_eq!(parse_module_reuse(two_crates, Some("bar")), None);
+    }
+
+    #[test]
+    fn test_parse_query_reuse() {
+        let output = "[incremental] re-using 3 out of 4 modules\n\
+                      [incremental] query cache: 120 hits, 30 misses\n\
+                      [incremental] query cache: 1 hit, 0 misses\n";
+        assert_eq!(parse_query_reuse(output, None), Some((121, 151)));
+        assert_eq!(parse_query_reuse("[incremental] re-using 3 out of 4 modules\n", None),
+                   None);
+    }
+
+    #[test]
+    fn test_escape_markup() {
+        assert_eq!(escape_markup("tests::plain"), "tests::plain");
+        assert_eq!(escape_markup("a<b> & \"c\" 'd'"),
+                   "a&lt;b&gt; &amp; &quot;c&quot; &apos;d&apos;");
+        assert_eq!(escape_markup("\x1b[31mpanicked\x1b[0m at\t'x'\x07\n"),
+                   "panicked at\t&apos;x&apos;\u{fffd}\n");
+    }
+
+    #[test]
+    fn test_strip_ansi_codes() {
+        assert_eq!(strip_ansi_codes(b"\x1b[1m\x1b[31merror\x1b[0m: oops\n"), b"error: oops\n");
+        assert_eq!(strip_ansi_codes(b"\x1b]8;;http://x\x07link\x1b]8;;\x1b\\ done"),
+                   b"link done");
+        assert_eq!(strip_ansi_codes(b"plain"), b"plain");
+        assert_eq!(strip_ansi_codes(b"cut off \x1b["), b"cut off ");
+    }
+
+    #[test]
+    fn test_colorize_diff() {
+        assert_eq!(colorize_diff("@@ -1,2 +1,2 @@\n a\n-b\n+c\n"),
+                   "\x1b[36m@@ -1,2 +1,2 @@\x1b[0m\n a\n\x1b[31m-b\x1b[0m\n\x1b[32m+c\x1b[0m\n");
+    }
+}